@@ -0,0 +1,21 @@
+-- sqlite cannot drop a column that is part of a foreign key, rebuild the table instead
+create table booking_old (
+  id integer primary key autoincrement,
+  reason text not null,
+  duration integer not null,
+  status text not null,
+  date text not null,
+  fk_room_id integer not null,
+  fk_user_id integer not null,
+  created_at datetime default current_timestamp,
+  foreign key (fk_room_id) references room(id),
+  foreign key (fk_user_id) references user(id)
+);
+
+insert into booking_old (id, reason, duration, status, date, fk_room_id, fk_user_id, created_at)
+select id, reason, duration, status, date, fk_room_id, fk_user_id, created_at from booking;
+
+drop table booking;
+alter table booking_old rename to booking;
+
+drop table if exists booking_series;
@@ -0,0 +1,13 @@
+create table booking_series (
+  id integer primary key autoincrement,
+  frequency text not null,
+  until text,
+  occurrences integer,
+  fk_room_id integer not null,
+  fk_user_id integer not null,
+  created_at datetime default current_timestamp,
+  foreign key (fk_room_id) references room(id),
+  foreign key (fk_user_id) references user(id)
+);
+
+alter table booking add column fk_series_id integer references booking_series(id);
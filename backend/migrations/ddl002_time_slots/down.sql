@@ -0,0 +1,7 @@
+alter table booking add column duration integer not null default 2;
+
+update booking set duration = 0 where start_time < '12:00' and end_time <= '12:00';
+update booking set duration = 1 where start_time >= '12:00';
+
+alter table booking drop column start_time;
+alter table booking drop column end_time;
@@ -0,0 +1,9 @@
+alter table booking add column start_time text not null default '08:00';
+alter table booking add column end_time text not null default '17:00';
+
+-- 0 = Morning, 1 = Afternoon, 2 = Day
+update booking set start_time = '08:00', end_time = '12:00' where duration = 0;
+update booking set start_time = '12:00', end_time = '17:00' where duration = 1;
+update booking set start_time = '08:00', end_time = '17:00' where duration = 2;
+
+alter table booking drop column duration;
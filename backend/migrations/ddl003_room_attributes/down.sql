@@ -0,0 +1,7 @@
+alter table booking drop column attendees;
+
+drop table if exists room_amenity;
+
+alter table room drop column location;
+alter table room drop column floor;
+alter table room drop column capacity;
@@ -0,0 +1,12 @@
+alter table room add column capacity integer;
+alter table room add column floor integer;
+alter table room add column location text;
+
+create table room_amenity (
+  fk_room_id integer not null,
+  amenity text not null,
+  primary key (fk_room_id, amenity),
+  foreign key (fk_room_id) references room(id)
+);
+
+alter table booking add column attendees integer;
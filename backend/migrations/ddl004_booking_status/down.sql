@@ -0,0 +1 @@
+alter table booking drop column status_comment;
@@ -0,0 +1,4 @@
+alter table booking add column status_comment text;
+
+update booking set status = 'Pending'
+where status not in ('Pending', 'Approved', 'Rejected', 'Cancelled', 'Completed', 'NoShow');
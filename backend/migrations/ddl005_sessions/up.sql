@@ -0,0 +1,17 @@
+create table session (
+  id integer primary key autoincrement,
+  fk_user_id integer not null,
+  created_at datetime default current_timestamp,
+  revoked_at datetime,
+  foreign key (fk_user_id) references user(id)
+);
+
+create table refresh_token (
+  id integer primary key autoincrement,
+  token_hash text not null unique,
+  fk_session_id integer not null,
+  expires_at datetime not null,
+  used_at datetime,
+  created_at datetime default current_timestamp,
+  foreign key (fk_session_id) references session(id)
+);
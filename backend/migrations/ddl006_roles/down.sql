@@ -0,0 +1,3 @@
+drop table if exists user_role;
+drop table if exists role_permission;
+drop table if exists role;
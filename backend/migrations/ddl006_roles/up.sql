@@ -0,0 +1,34 @@
+create table role (
+  id integer primary key autoincrement,
+  name text not null unique
+);
+
+create table role_permission (
+  fk_role_id integer not null,
+  permission text not null,
+  primary key (fk_role_id, permission),
+  foreign key (fk_role_id) references role(id)
+);
+
+create table user_role (
+  fk_user_id integer not null,
+  fk_role_id integer not null,
+  primary key (fk_user_id, fk_role_id),
+  foreign key (fk_user_id) references user(id),
+  foreign key (fk_role_id) references role(id)
+);
+
+insert into role (name) values ('member'), ('receptionist'), ('facility_manager'), ('super_admin');
+
+insert into role_permission (fk_role_id, permission)
+select id, 'manage_bookings' from role where name in ('receptionist', 'super_admin')
+union all
+select id, 'manage_rooms' from role where name in ('facility_manager', 'super_admin')
+union all
+select id, 'manage_users' from role where name = 'super_admin';
+
+insert into user_role (fk_user_id, fk_role_id)
+select user.id, role.id from user, role where role.name = 'member';
+
+insert into user_role (fk_user_id, fk_role_id)
+select user.id, role.id from user, role where role.name = 'super_admin' and user.is_admin;
@@ -0,0 +1,42 @@
+create table booking_series_new (
+  id integer primary key autoincrement,
+  frequency text not null,
+  until text,
+  occurrences integer,
+  fk_room_id integer not null,
+  fk_user_id integer not null,
+  created_at datetime default current_timestamp,
+  foreign key (fk_room_id) references room(id),
+  foreign key (fk_user_id) references user(id)
+);
+
+insert into booking_series_new (id, frequency, until, occurrences, fk_room_id, fk_user_id, created_at)
+select id, frequency, until, occurrences, fk_room_id, fk_user_id, created_at from booking_series;
+
+drop table booking_series;
+alter table booking_series_new rename to booking_series;
+
+create table booking_new (
+  id integer primary key autoincrement,
+  reason text not null,
+  status text not null,
+  date text not null,
+  fk_room_id integer not null,
+  fk_user_id integer not null,
+  created_at datetime default current_timestamp,
+  fk_series_id integer references booking_series(id),
+  start_time text not null default '08:00',
+  end_time text not null default '17:00',
+  attendees integer,
+  status_comment text,
+  foreign key (fk_room_id) references room(id),
+  foreign key (fk_user_id) references user(id)
+);
+
+insert into booking_new (id, reason, status, date, fk_room_id, fk_user_id, created_at,
+  fk_series_id, start_time, end_time, attendees, status_comment)
+select id, reason, status, date, fk_room_id, fk_user_id, created_at,
+  fk_series_id, start_time, end_time, attendees, status_comment from booking;
+
+drop table booking;
+alter table booking_new rename to booking;
@@ -0,0 +1,10 @@
+create table user_token (
+  id integer primary key autoincrement,
+  token_hash text not null unique,
+  purpose text not null,
+  fk_user_id integer not null,
+  expires_at datetime not null,
+  used_at datetime,
+  created_at datetime default current_timestamp,
+  foreign key (fk_user_id) references user(id)
+);
@@ -0,0 +1,4 @@
+alter table user add column email_verified_at datetime;
+
+-- Existing accounts were created before verification and stay usable
+update user set email_verified_at = coalesce(created_at, current_timestamp);
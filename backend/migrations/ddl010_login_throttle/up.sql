@@ -0,0 +1,6 @@
+create table login_throttle (
+  key text not null primary key,
+  failures integer not null default 0,
+  last_failure_at datetime not null,
+  locked_until datetime
+);
@@ -0,0 +1,16 @@
+create table totp (
+  fk_user_id integer not null primary key,
+  secret text not null,
+  enabled_at datetime,
+  last_used_step bigint,
+  created_at datetime default current_timestamp,
+  foreign key (fk_user_id) references user(id)
+);
+
+create table recovery_code (
+  id integer primary key autoincrement,
+  fk_user_id integer not null,
+  code_hash text not null,
+  used_at datetime,
+  foreign key (fk_user_id) references user(id)
+);
@@ -0,0 +1,15 @@
+create table oidc_login (
+  state_hash text not null primary key,
+  nonce text not null,
+  pkce_verifier text not null,
+  expires_at datetime not null
+);
+
+create table user_identity (
+  issuer text not null,
+  subject text not null,
+  fk_user_id integer not null,
+  created_at datetime default current_timestamp,
+  primary key (issuer, subject),
+  foreign key (fk_user_id) references user(id)
+);
@@ -0,0 +1,13 @@
+create table api_key (
+  id integer primary key autoincrement,
+  fk_user_id integer not null,
+  name text not null,
+  key_hash text not null unique,
+  key_prefix text not null,
+  scopes text not null,
+  expires_at datetime,
+  last_used_at datetime,
+  created_at datetime default current_timestamp,
+  revoked_at datetime,
+  foreign key (fk_user_id) references user(id)
+);
@@ -0,0 +1,6 @@
+create table user_preference (
+  fk_user_id integer not null primary key,
+  language text not null default 'en',
+  theme text not null default 'system',
+  foreign key (fk_user_id) references user(id)
+);
@@ -1,19 +1,44 @@
-use std::env;
+use std::{env, marker::PhantomData, net::IpAddr, sync::OnceLock};
 
 use argon2::{
-    password_hash::{rand_core::OsRng, SaltString},
+    password_hash::{
+        rand_core::{OsRng, RngCore},
+        SaltString,
+    },
     Argon2, PasswordHash, PasswordHasher, PasswordVerifier,
 };
 use chrono::Utc;
-use jsonwebtoken::{DecodingKey, EncodingKey, Validation};
+use diesel::SqliteConnection;
+use jsonwebtoken::errors::ErrorKind;
+use rocket::http::Method;
 use rocket::request::{FromRequest, Outcome};
 use rocket::Request;
-use rocket_http::Status;
-use rocket_okapi::{gen::OpenApiGenerator, okapi::schemars, request::RequestHeaderInput};
-use rocket_okapi::{okapi::schemars::JsonSchema, request::OpenApiFromRequest};
+use rocket_okapi::{
+    gen::OpenApiGenerator,
+    okapi::openapi3::{Object, SecurityRequirement, SecurityScheme, SecuritySchemeData},
+    request::{OpenApiFromRequest, RequestHeaderInput},
+};
 use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+
+use crate::{
+    db::DbConn,
+    error::{guard_error, ApiError, ErrorCode},
+    jwt,
+    models::{
+        api_key::{ApiKey, ApiKeyScope, API_KEY_PREFIX},
+        role::{Permission, Role},
+        session::Session,
+        setting::Settings,
+        two_factor::Totp,
+        user::User,
+    },
+};
 
-use crate::models::user::User;
+use self::{
+    permissions::PermissionMarker,
+    roles::{RoleMarker, SuperAdmin},
+};
 
 #[allow(clippy::missing_panics_doc)]
 pub fn hash_password(password: &str) -> String {
@@ -25,6 +50,9 @@ pub fn hash_password(password: &str) -> String {
         .to_string()
 }
 
+/// Hash of a random password, checked for unknown emails
+static DUMMY_HASH: OnceLock<String> = OnceLock::new();
+
 #[allow(clippy::missing_panics_doc)]
 #[must_use]
 pub fn verify_password(password: &str, password_hash: &str) -> bool {
@@ -38,81 +66,738 @@ pub fn verify_password(password: &str, password_hash: &str) -> bool {
         .is_ok()
 }
 
-#[derive(JsonSchema, Serialize, Deserialize, Debug)]
-pub struct Token<'r>(pub &'r str);
+/// Like [`verify_password`], but checks a dummy hash if there is no user, so unknown
+/// emails take as long as wrong passwords
+#[must_use]
+pub fn verify_user_password(password: &str, user: Option<&User>) -> bool {
+    match user {
+        Some(user) => verify_password(password, &user.password),
+        None => {
+            let _ = verify_password(password, dummy_hash());
+            false
+        }
+    }
+}
+
+fn dummy_hash() -> &'static str {
+    DUMMY_HASH.get_or_init(|| hash_password(&generate_token()))
+}
+
+/// Computes the dummy hash at startup, otherwise the first unknown email takes longer
+pub fn init_dummy_hash() {
+    dummy_hash();
+}
+
+/// Address of the client, `None` if Rocket can not determine it
+///
+/// The address of the connection, which is the reverse proxy if there is one. Only with
+/// `TRUST_IP_HEADER=true` it is taken from the header named by `ROCKET_IP_HEADER`
+/// (`X-Real-IP` by default), clients can set any address there if no proxy overwrites it
+pub struct ClientIp(pub Option<IpAddr>);
 
 #[rocket::async_trait]
-impl<'r> FromRequest<'r> for Token<'r> {
+impl<'r> FromRequest<'r> for ClientIp {
     type Error = ();
 
     async fn from_request(request: &'r Request<'_>) -> Outcome<Self, Self::Error> {
-        fn is_valid(token: &str) -> bool {
-            decode_token(token).is_some()
+        let ip = if trust_ip_header() {
+            request.client_ip()
+        } else {
+            request.remote().map(|remote| remote.ip())
+        };
+        Outcome::Success(ClientIp(ip))
+    }
+}
+
+impl<'r> OpenApiFromRequest<'r> for ClientIp {
+    fn from_request_input(
+        _gen: &mut OpenApiGenerator,
+        _name: String,
+        _required: bool,
+    ) -> rocket_okapi::Result<RequestHeaderInput> {
+        Ok(RequestHeaderInput::None)
+    }
+}
+
+/// How the user of a request authenticated
+#[derive(Clone, Debug)]
+pub enum Credential {
+    /// Access token of a login session
+    Session(Claims),
+    /// API key, limited to its scopes
+    ApiKey { id: i32, scopes: Vec<ApiKeyScope> },
+}
+
+/// Authenticated user of the bearer token or API key in the `Authorization` header
+///
+/// Resolved once per request, 401 if the token is missing, invalid or expired, its
+/// session or API key has been revoked or the user no longer exists. API keys without
+/// the `write` scope can only make `GET` requests, 403 otherwise
+#[derive(Clone, Debug)]
+pub struct AuthUser {
+    pub user: User,
+    pub credential: Credential,
+}
+
+impl AuthUser {
+    /// Whether the credential may use the permission, access tokens may use all
+    /// permissions of the user
+    #[must_use]
+    pub fn allows(&self, permission: Permission) -> bool {
+        match &self.credential {
+            Credential::Session(_) => true,
+            Credential::ApiKey { scopes, .. } => {
+                scopes.iter().any(|s| s.permission() == Some(permission))
+            }
         }
+    }
+
+    /// Same checks as [`RequirePermission`], for endpoints that do more with the permission
+    ///
+    /// # Errors
+    ///
+    /// 403 if the user lacks the permission, an API key lacks its scope or the admin
+    /// policy requires two-factor authentication the user has not enabled
+    pub fn require_permission(
+        &self,
+        conn: &mut SqliteConnection,
+        permission: Permission,
+    ) -> Result<(), ApiError> {
+        if !self.user.has_permission(conn, permission) {
+            let message = format!("Requires the {} permission", permission.as_str());
+            return Err(ApiError::new(ErrorCode::Forbidden, message));
+        }
+        if !self.allows(permission) {
+            let message = format!("API key lacks the {} scope", permission.as_str());
+            return Err(ApiError::new(ErrorCode::Forbidden, message));
+        }
+        require_admin_two_factor(conn, &self.user)
+    }
+
+    /// Whether the user can use the permission with this credential right now, see
+    /// [`AuthUser::require_permission`]
+    #[must_use]
+    pub fn can(&self, conn: &mut SqliteConnection, permission: Permission) -> bool {
+        self.require_permission(conn, permission).is_ok()
+    }
 
-        match request.headers().get_one("Authorization") {
-            Some(key) if is_valid(key) => rocket::outcome::Outcome::Success(Token(key)),
-            Some(_) | None => Outcome::Error((Status::Unauthorized, ())),
+    /// Same checks as [`RequireRole`], for endpoints that only need the role for some changes
+    ///
+    /// # Errors
+    ///
+    /// 403 for API keys, users without the role and admins without the two-factor
+    /// authentication the policy requires
+    pub fn require_role(&self, conn: &mut SqliteConnection, role: &str) -> Result<(), ApiError> {
+        if !matches!(self.credential, Credential::Session(_)) {
+            return Err(session_required());
+        }
+        let has_role = self
+            .user
+            .id
+            .is_some_and(|id| Role::names_by_user_id(conn, id).iter().any(|r| r == role));
+        if !has_role {
+            let message = format!("Requires the {role} role");
+            return Err(ApiError::new(ErrorCode::Forbidden, message));
         }
+        require_admin_two_factor(conn, &self.user)
     }
 }
 
-impl<'r> OpenApiFromRequest<'r> for Token<'r> {
+#[rocket::async_trait]
+impl<'r> FromRequest<'r> for AuthUser {
+    type Error = ();
+
+    async fn from_request(request: &'r Request<'_>) -> Outcome<Self, Self::Error> {
+        let resolved = request
+            .local_cache_async(async { resolve_user(request).await })
+            .await
+            .clone();
+
+        match resolved {
+            Ok(auth_user) => Outcome::Success(auth_user),
+            Err(error) => guard_error(request, error),
+        }
+    }
+}
+
+impl<'r> OpenApiFromRequest<'r> for AuthUser {
     fn from_request_input(
         _gen: &mut OpenApiGenerator,
         _name: String,
         _required: bool,
     ) -> rocket_okapi::Result<RequestHeaderInput> {
-        Ok(RequestHeaderInput::None)
+        Ok(bearer_security())
+    }
+}
+
+async fn resolve_user(request: &Request<'_>) -> Result<AuthUser, ApiError> {
+    let Some(token) = request.headers().get_one("Authorization") else {
+        return Err(ApiError::new(
+            ErrorCode::Unauthorized,
+            "Missing Authorization header",
+        ));
+    };
+    let token = token.strip_prefix("Bearer ").unwrap_or(token);
+    let claims = if token.starts_with(API_KEY_PREFIX) {
+        None
+    } else {
+        Some(decode_token(token)?)
+    };
+
+    let mut db = match request.guard::<DbConn>().await {
+        Outcome::Success(db) => db,
+        Outcome::Error((status, ())) | Outcome::Forward(status) => return Err(status.into()),
+    };
+
+    let Some(claims) = claims else {
+        return user_from_api_key(&mut db, token, request.method());
+    };
+    match user_from_claims(&mut db, &claims) {
+        Some(user) => Ok(AuthUser {
+            user,
+            credential: Credential::Session(claims),
+        }),
+        None => Err(ApiError::new(
+            ErrorCode::InvalidToken,
+            "Session has been revoked or the user no longer exists",
+        )),
+    }
+}
+
+fn user_from_api_key(
+    conn: &mut SqliteConnection,
+    key: &str,
+    method: Method,
+) -> Result<AuthUser, ApiError> {
+    let Some((api_key, user)) = ApiKey::authenticate(conn, key)
+        .and_then(|k| Some((k.clone(), User::by_id(conn, k.fk_user_id)?)))
+    else {
+        return Err(ApiError::new(
+            ErrorCode::InvalidToken,
+            "API key is invalid, expired or has been revoked",
+        ));
+    };
+
+    let scopes = api_key.scopes();
+    let read_only = matches!(method, Method::Get | Method::Head);
+    let allowed =
+        scopes.contains(&ApiKeyScope::Write) || read_only && scopes.contains(&ApiKeyScope::Read);
+    if !allowed {
+        let needed = if read_only { "read" } else { "write" };
+        return Err(ApiError::new(
+            ErrorCode::Forbidden,
+            format!("API key lacks the {needed} scope"),
+        ));
+    }
+
+    Ok(AuthUser {
+        user,
+        credential: Credential::ApiKey {
+            id: api_key.id.unwrap_or_default(),
+            scopes,
+        },
+    })
+}
+
+/// User authenticated with the access token of a login session, 403 for API keys
+///
+/// Required to manage the account itself, e.g. its password, two-factor authentication
+/// and API keys
+pub struct SessionUser {
+    pub user: User,
+    pub claims: Claims,
+}
+
+#[rocket::async_trait]
+impl<'r> FromRequest<'r> for SessionUser {
+    type Error = ();
+
+    async fn from_request(request: &'r Request<'_>) -> Outcome<Self, Self::Error> {
+        match request.guard::<AuthUser>().await {
+            Outcome::Success(AuthUser {
+                user,
+                credential: Credential::Session(claims),
+            }) => Outcome::Success(SessionUser { user, claims }),
+            Outcome::Success(_) => guard_error(request, session_required()),
+            Outcome::Error(e) => Outcome::Error(e),
+            Outcome::Forward(status) => Outcome::Forward(status),
+        }
     }
 }
 
-#[derive(Debug, Serialize, Deserialize)]
+impl<'r> OpenApiFromRequest<'r> for SessionUser {
+    fn from_request_input(
+        _gen: &mut OpenApiGenerator,
+        _name: String,
+        _required: bool,
+    ) -> rocket_okapi::Result<RequestHeaderInput> {
+        Ok(bearer_security())
+    }
+}
+
+fn session_required() -> ApiError {
+    ApiError::new(
+        ErrorCode::Forbidden,
+        "Log in to use this endpoint, API keys can not use it",
+    )
+}
+
+/// `Authorization: Bearer <token>` security scheme for the OpenAPI document
+fn bearer_security() -> RequestHeaderInput {
+    let scheme = SecurityScheme {
+        description: Some("Access token returned by `/login` or an API key".to_string()),
+        data: SecuritySchemeData::Http {
+            scheme: "bearer".to_string(),
+            bearer_format: Some("JWT".to_string()),
+        },
+        extensions: Object::default(),
+    };
+    let mut requirement = SecurityRequirement::new();
+    requirement.insert("bearer".to_string(), Vec::new());
+
+    RequestHeaderInput::Security("bearer".to_string(), scheme, requirement)
+}
+
+/// Super admins, users with the `super_admin` role
+pub type AdminUser = RequireRole<SuperAdmin>;
+
+/// Permissions that can be required with [`RequirePermission`]
+pub mod permissions {
+    use crate::models::role::Permission;
+
+    pub trait PermissionMarker: Send + Sync {
+        const PERMISSION: Permission;
+    }
+
+    pub struct ManageBookings;
+    pub struct ManageRooms;
+    pub struct ManageUsers;
+
+    impl PermissionMarker for ManageBookings {
+        const PERMISSION: Permission = Permission::ManageBookings;
+    }
+
+    impl PermissionMarker for ManageRooms {
+        const PERMISSION: Permission = Permission::ManageRooms;
+    }
+
+    impl PermissionMarker for ManageUsers {
+        const PERMISSION: Permission = Permission::ManageUsers;
+    }
+}
+
+/// Roles that can be required with [`RequireRole`]
+pub mod roles {
+    use crate::models::role::SUPER_ADMIN;
+
+    pub trait RoleMarker: Send + Sync {
+        const ROLE: &'static str;
+    }
+
+    pub struct SuperAdmin;
+
+    impl RoleMarker for SuperAdmin {
+        const ROLE: &'static str = SUPER_ADMIN;
+    }
+}
+
+/// Authenticated user with the permission `P`, 403 if the user lacks it or an API key
+/// lacks its scope
+pub struct RequirePermission<P> {
+    pub user: User,
+    permission: PhantomData<P>,
+}
+
+#[rocket::async_trait]
+impl<'r, P: PermissionMarker> FromRequest<'r> for RequirePermission<P> {
+    type Error = ();
+
+    async fn from_request(request: &'r Request<'_>) -> Outcome<Self, Self::Error> {
+        let access = match request.guard::<AuthUser>().await {
+            Outcome::Success(auth_user) => auth_user,
+            Outcome::Error(e) => return Outcome::Error(e),
+            Outcome::Forward(status) => return Outcome::Forward(status),
+        };
+        let mut db = match request.guard::<DbConn>().await {
+            Outcome::Success(db) => db,
+            Outcome::Error(e) => return Outcome::Error(e),
+            Outcome::Forward(status) => return Outcome::Forward(status),
+        };
+
+        if let Err(e) = access.require_permission(&mut db, P::PERMISSION) {
+            return guard_error(request, e);
+        }
+
+        Outcome::Success(RequirePermission {
+            user: access.user,
+            permission: PhantomData,
+        })
+    }
+}
+
+impl<'r, P: PermissionMarker> OpenApiFromRequest<'r> for RequirePermission<P> {
+    fn from_request_input(
+        _gen: &mut OpenApiGenerator,
+        _name: String,
+        _required: bool,
+    ) -> rocket_okapi::Result<RequestHeaderInput> {
+        Ok(bearer_security())
+    }
+}
+
+/// Authenticated user with the role `R`, 403 if the user does not have it or used an API key
+pub struct RequireRole<R> {
+    pub user: User,
+    role: PhantomData<R>,
+}
+
+#[rocket::async_trait]
+impl<'r, R: RoleMarker> FromRequest<'r> for RequireRole<R> {
+    type Error = ();
+
+    async fn from_request(request: &'r Request<'_>) -> Outcome<Self, Self::Error> {
+        let access = match request.guard::<AuthUser>().await {
+            Outcome::Success(auth_user) => auth_user,
+            Outcome::Error(e) => return Outcome::Error(e),
+            Outcome::Forward(status) => return Outcome::Forward(status),
+        };
+        let mut db = match request.guard::<DbConn>().await {
+            Outcome::Success(db) => db,
+            Outcome::Error(e) => return Outcome::Error(e),
+            Outcome::Forward(status) => return Outcome::Forward(status),
+        };
+
+        if let Err(e) = access.require_role(&mut db, R::ROLE) {
+            return guard_error(request, e);
+        }
+
+        Outcome::Success(RequireRole {
+            user: access.user,
+            role: PhantomData,
+        })
+    }
+}
+
+impl<'r, R: RoleMarker> OpenApiFromRequest<'r> for RequireRole<R> {
+    fn from_request_input(
+        _gen: &mut OpenApiGenerator,
+        _name: String,
+        _required: bool,
+    ) -> rocket_okapi::Result<RequestHeaderInput> {
+        Ok(bearer_security())
+    }
+}
+
+/// Lifetime of access tokens in minutes if `ACCESS_TOKEN_MINUTES` is not set
+pub const DEFAULT_ACCESS_TOKEN_MINUTES: i64 = 15;
+/// Lifetime of refresh tokens in days if `REFRESH_TOKEN_DAYS` is not set
+pub const DEFAULT_REFRESH_TOKEN_DAYS: i64 = 30;
+/// Lifetime of password reset tokens in minutes if `PASSWORD_RESET_MINUTES` is not set
+pub const DEFAULT_PASSWORD_RESET_MINUTES: i64 = 60;
+/// Failed logins in a row before an account is locked if `LOGIN_MAX_FAILURES` is not set
+pub const DEFAULT_LOGIN_MAX_FAILURES: i32 = 5;
+/// Failed logins in a row before a client IP address is locked if
+/// `LOGIN_MAX_FAILURES_PER_IP` is not set
+pub const DEFAULT_LOGIN_MAX_FAILURES_PER_IP: i32 = 50;
+/// Minutes an account or IP address stays locked if `LOGIN_LOCKOUT_MINUTES` is not set
+pub const DEFAULT_LOGIN_LOCKOUT_MINUTES: i64 = 15;
+/// Seconds to enter the two-factor code after the password if
+/// `TWO_FACTOR_CHALLENGE_SECONDS` is not set
+pub const DEFAULT_TWO_FACTOR_CHALLENGE_SECONDS: i64 = 300;
+/// Lifetime of email verification tokens in hours if `EMAIL_VERIFICATION_HOURS` is not set
+pub const DEFAULT_EMAIL_VERIFICATION_HOURS: i64 = 48;
+/// Seconds between two verification mails if `VERIFICATION_RESEND_SECONDS` is not set
+pub const DEFAULT_VERIFICATION_RESEND_SECONDS: i64 = 60;
+
+fn env_or(key: &str, default: i64) -> i64 {
+    env::var(key)
+        .ok()
+        .and_then(|v| v.parse().ok())
+        .unwrap_or(default)
+}
+
+#[must_use]
+pub fn access_token_lifetime() -> chrono::Duration {
+    chrono::Duration::minutes(env_or("ACCESS_TOKEN_MINUTES", DEFAULT_ACCESS_TOKEN_MINUTES))
+}
+
+#[must_use]
+pub fn refresh_token_lifetime() -> chrono::Duration {
+    chrono::Duration::days(env_or("REFRESH_TOKEN_DAYS", DEFAULT_REFRESH_TOKEN_DAYS))
+}
+
+#[must_use]
+pub fn password_reset_token_lifetime() -> chrono::Duration {
+    chrono::Duration::minutes(env_or(
+        "PASSWORD_RESET_MINUTES",
+        DEFAULT_PASSWORD_RESET_MINUTES,
+    ))
+}
+
+#[must_use]
+pub fn email_verification_token_lifetime() -> chrono::Duration {
+    chrono::Duration::hours(env_or(
+        "EMAIL_VERIFICATION_HOURS",
+        DEFAULT_EMAIL_VERIFICATION_HOURS,
+    ))
+}
+
+/// Minimum time between two verification mails to the same user
+#[must_use]
+pub fn verification_resend_interval() -> chrono::Duration {
+    chrono::Duration::seconds(env_or(
+        "VERIFICATION_RESEND_SECONDS",
+        DEFAULT_VERIFICATION_RESEND_SECONDS,
+    ))
+}
+
+#[must_use]
+pub fn login_max_failures() -> i32 {
+    i32::try_from(env_or(
+        "LOGIN_MAX_FAILURES",
+        DEFAULT_LOGIN_MAX_FAILURES.into(),
+    ))
+    .unwrap_or(DEFAULT_LOGIN_MAX_FAILURES)
+}
+
+#[must_use]
+pub fn login_max_failures_per_ip() -> i32 {
+    i32::try_from(env_or(
+        "LOGIN_MAX_FAILURES_PER_IP",
+        DEFAULT_LOGIN_MAX_FAILURES_PER_IP.into(),
+    ))
+    .unwrap_or(DEFAULT_LOGIN_MAX_FAILURES_PER_IP)
+}
+
+/// How long an account or IP address stays locked, failures older than this are forgotten
+#[must_use]
+pub fn login_lockout() -> chrono::Duration {
+    chrono::Duration::minutes(env_or(
+        "LOGIN_LOCKOUT_MINUTES",
+        DEFAULT_LOGIN_LOCKOUT_MINUTES,
+    ))
+}
+
+#[must_use]
+pub fn two_factor_challenge_lifetime() -> chrono::Duration {
+    chrono::Duration::seconds(env_or(
+        "TWO_FACTOR_CHALLENGE_SECONDS",
+        DEFAULT_TWO_FACTOR_CHALLENGE_SECONDS,
+    ))
+}
+
+/// Whether the server is behind a reverse proxy that sets the client address header,
+/// `TRUST_IP_HEADER=true`
+#[must_use]
+pub fn trust_ip_header() -> bool {
+    env::var("TRUST_IP_HEADER").is_ok_and(|v| v == "true" || v == "1")
+}
+
+/// Default of the admin two-factor policy until an admin changes it at `PUT /settings`,
+/// `REQUIRE_ADMIN_2FA=true`
+#[must_use]
+pub fn admin_two_factor_required() -> bool {
+    env::var("REQUIRE_ADMIN_2FA").is_ok_and(|v| v == "true" || v == "1")
+}
+
+/// Whether the user needs two-factor authentication to use their permissions and roles,
+/// which is the case for everyone with a permission while the admin policy is enabled
+#[must_use]
+pub fn two_factor_required(conn: &mut SqliteConnection, user: &User) -> bool {
+    let Some(id) = user.id else {
+        return false;
+    };
+    Settings::load(conn).require_admin_2fa
+        && (user.is_admin.unwrap_or_default() || !Role::permissions_by_user_id(conn, id).is_empty())
+}
+
+/// Fails with 403 `two_factor_required` if the admin policy applies to the user and they
+/// have not enabled two-factor authentication
+fn require_admin_two_factor(conn: &mut SqliteConnection, user: &User) -> Result<(), ApiError> {
+    if two_factor_required(conn, user) && !user.id.is_some_and(|id| Totp::is_enabled(conn, id)) {
+        return Err(ApiError::new(
+            ErrorCode::TwoFactorRequired,
+            "Enable two-factor authentication at /me/2fa/totp to use admin features",
+        ));
+    }
+    Ok(())
+}
+
+/// What users can not do until they verified their email address
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum VerificationRequirement {
+    /// Unverified users can do everything
+    None,
+    /// Unverified users can log in but not book rooms
+    Bookings,
+    /// Unverified users can not log in
+    Login,
+}
+
+/// Configured with `REQUIRE_VERIFIED_EMAIL`: `none`, `bookings` (default) or `login`
+#[must_use]
+pub fn verification_requirement() -> VerificationRequirement {
+    match env::var("REQUIRE_VERIFIED_EMAIL").as_deref() {
+        Ok("none") => VerificationRequirement::None,
+        Ok("login") => VerificationRequirement::Login,
+        _ => VerificationRequirement::Bookings,
+    }
+}
+
+/// Fails with 403 `email_not_verified` if the requirement applies and the user is unverified
+///
+/// # Errors
+///
+/// Fails if `requirement` is configured and the email address of the user is not verified
+pub fn require_verified(user: &User, requirement: VerificationRequirement) -> Result<(), ApiError> {
+    let configured = verification_requirement();
+    let applies = match requirement {
+        VerificationRequirement::None => false,
+        VerificationRequirement::Bookings => configured != VerificationRequirement::None,
+        VerificationRequirement::Login => configured == VerificationRequirement::Login,
+    };
+
+    if applies && user.email_verified_at.is_none() {
+        return Err(ApiError::new(
+            ErrorCode::EmailNotVerified,
+            "Verify your email address first, follow the link in the verification mail",
+        ));
+    }
+    Ok(())
+}
+
+/// Random opaque token, e.g. for refresh tokens, hex encoded
+#[must_use]
+pub fn generate_token() -> String {
+    let mut bytes = [0u8; 32];
+    OsRng.fill_bytes(&mut bytes);
+    hex(&bytes)
+}
+
+/// SHA-256 of an opaque token, only the hash is stored in the database
+#[must_use]
+pub fn hash_token(token: &str) -> String {
+    hex(&Sha256::digest(token.as_bytes()))
+}
+
+fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{b:02x}")).collect()
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
 pub struct Claims {
     pub sub: String,
+    /// Session the token was issued for, revoking the session invalidates the token
+    pub sid: i32,
     pub exp: usize,
 }
 
+impl Claims {
+    #[must_use]
+    pub fn user_id(&self) -> Option<i32> {
+        self.sub.parse().ok()
+    }
+}
+
 #[must_use]
-pub fn encode_token(id: i32) -> Option<String> {
+pub fn encode_token(id: i32, session_id: i32) -> Option<String> {
     let claims = Claims {
         sub: id.to_string(),
-        exp: usize::try_from((Utc::now() + chrono::Duration::hours(24)).timestamp()).ok()?,
+        sid: session_id,
+        exp: usize::try_from((Utc::now() + access_token_lifetime()).timestamp()).ok()?,
     };
 
-    let header = jsonwebtoken::Header::new(jsonwebtoken::Algorithm::HS256);
-    let jwt_secret = env::var("JWT_SECRET").ok()?;
-    let key = EncodingKey::from_secret(jwt_secret.as_ref());
+    jwt::keys()?.encode(&claims)
+}
 
-    jsonwebtoken::encode(&header, &claims, &key).ok()
+/// Claims of a valid access token, the `Bearer ` prefix is optional
+///
+/// # Errors
+///
+/// `token_expired` if the token has expired, `invalid_token` if it is invalid otherwise
+pub fn decode_token(token: &str) -> Result<Claims, ApiError> {
+    let token = token.strip_prefix("Bearer ").unwrap_or(token);
+    let Some(keys) = jwt::keys() else {
+        return Err(ApiError::internal());
+    };
+
+    keys.decode(token).map_err(|e| match e.kind() {
+        ErrorKind::ExpiredSignature => ApiError::new(
+            ErrorCode::TokenExpired,
+            "Access token has expired, use the refresh token to get a new one",
+        ),
+        _ => ApiError::new(ErrorCode::InvalidToken, "Invalid access token"),
+    })
 }
 
+/// User of valid access token claims whose session has not been revoked
 #[must_use]
-#[allow(clippy::missing_panics_doc)]
-pub fn decode_token(token: &str) -> Option<i32> {
-    if token.is_empty() || token.len() < 7 {
+pub fn user_from_claims(conn: &mut SqliteConnection, claims: &Claims) -> Option<User> {
+    let id = claims.user_id()?;
+    if !Session::is_active(conn, claims.sid, id) {
         return None;
     }
+    User::by_id(conn, id)
+}
 
-    let token = if token.starts_with("Bearer") {
-        token[7..].to_string()
-    } else {
-        token.to_string()
-    };
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::db::test_connection;
 
-    let jwt_secret = env::var("JWT_SECRET").expect("jwt_secret must be set");
-    let key = DecodingKey::from_secret(jwt_secret.as_ref());
-    let validation = Validation::new(jsonwebtoken::Algorithm::HS256);
+    fn admin(conn: &mut SqliteConnection) -> User {
+        User::create_hash(
+            conn,
+            true,
+            "Ada",
+            "Admin",
+            "ada@example.com",
+            "adapass1234",
+            true,
+        )
+        .unwrap()
+    }
 
-    let Ok(token_data) = jsonwebtoken::decode::<Claims>(&token, &key, &validation) else {
-        return None;
-    };
+    #[test]
+    fn admin_without_two_factor_is_rejected_when_required() {
+        let mut conn = test_connection();
+        let user = admin(&mut conn);
+        Settings {
+            require_admin_2fa: true,
+        }
+        .save(&mut conn)
+        .unwrap();
 
-    token_data.claims.sub.parse::<i32>().ok()
-}
+        let error = require_admin_two_factor(&mut conn, &user).unwrap_err();
+        assert_eq!(error.code, ErrorCode::TwoFactorRequired);
+    }
 
-#[must_use]
-pub fn user_from_token(token: &str) -> Option<User> {
-    let id = decode_token(token)?;
-    User::by_id(id)
+    #[test]
+    fn admin_without_two_factor_is_allowed_when_not_required() {
+        let mut conn = test_connection();
+        let user = admin(&mut conn);
+        Settings {
+            require_admin_2fa: false,
+        }
+        .save(&mut conn)
+        .unwrap();
+
+        assert!(require_admin_two_factor(&mut conn, &user).is_ok());
+    }
+
+    #[test]
+    fn admin_with_two_factor_is_allowed_when_required() {
+        let mut conn = test_connection();
+        let user = admin(&mut conn);
+        let id = user.id.unwrap();
+        Totp::enroll(&mut conn, id).unwrap();
+        Totp::enable(&mut conn, id).unwrap();
+        Settings {
+            require_admin_2fa: true,
+        }
+        .save(&mut conn)
+        .unwrap();
+
+        assert!(require_admin_two_factor(&mut conn, &user).is_ok());
+    }
 }
@@ -0,0 +1,112 @@
+use std::error::Error;
+
+use clap::{Parser, Subcommand};
+use diesel::SqliteConnection;
+use diesel_migrations::MigrationHarness;
+
+use crate::{db::MIGRATIONS, util::load_test_data};
+
+pub type CliResult = Result<(), Box<dyn Error + Send + Sync>>;
+
+#[derive(Parser, Debug)]
+#[command(version, about = "Coworking space booking API")]
+pub struct Cli {
+    #[command(subcommand)]
+    pub command: Option<Command>,
+}
+
+#[derive(Subcommand, Debug)]
+pub enum Command {
+    /// Apply pending migrations and start the API server (default)
+    Serve,
+    /// Manage the database schema
+    Migrate {
+        #[command(subcommand)]
+        action: MigrateAction,
+    },
+    /// Load test users and bookings
+    Seed,
+    /// Revert all migrations and apply them again, deleting all data
+    Reset {
+        /// Confirm that all data should be deleted
+        #[arg(long)]
+        yes: bool,
+        /// Load test data after resetting
+        #[arg(long)]
+        seed: bool,
+    },
+}
+
+#[derive(Subcommand, Debug)]
+pub enum MigrateAction {
+    /// Apply all pending migrations
+    Up,
+    /// Revert the last applied migration
+    Down,
+    /// List applied and pending migrations
+    Status,
+}
+
+/// Applies all pending migrations and logs their versions
+fn migrate_up(conn: &mut SqliteConnection) -> CliResult {
+    for version in conn.run_pending_migrations(MIGRATIONS)? {
+        info!("Applied migration {version}");
+    }
+    Ok(())
+}
+
+/// Runs the database part of a subcommand, for `serve` that is applying pending migrations
+///
+/// # Errors
+///
+/// Fails if a migration could not be applied or reverted, or `reset` is not confirmed
+pub fn run(conn: &mut SqliteConnection, command: Command) -> CliResult {
+    match command {
+        Command::Serve
+        | Command::Migrate {
+            action: MigrateAction::Up,
+        } => migrate_up(conn),
+        Command::Migrate {
+            action: MigrateAction::Down,
+        } => {
+            let version = conn.revert_last_migration(MIGRATIONS)?;
+            info!("Reverted migration {version}");
+            Ok(())
+        }
+        Command::Migrate {
+            action: MigrateAction::Status,
+        } => {
+            let mut applied = conn.applied_migrations()?;
+            applied.sort();
+            for version in applied {
+                println!("[x] {version}");
+            }
+            for migration in conn.pending_migrations(MIGRATIONS)? {
+                println!("[ ] {}", migration.name());
+            }
+            Ok(())
+        }
+        Command::Seed => {
+            migrate_up(conn)?;
+            load_test_data(conn);
+            Ok(())
+        }
+        Command::Reset { yes, seed } => {
+            if !yes {
+                return Err("reset deletes all data, pass --yes to confirm".into());
+            }
+
+            warn!("Deleting all data");
+            for version in conn.revert_all_migrations(MIGRATIONS)? {
+                info!("Reverted migration {version}");
+            }
+            migrate_up(conn)?;
+            warn!("Database reset");
+
+            if seed {
+                load_test_data(conn);
+            }
+            Ok(())
+        }
+    }
+}
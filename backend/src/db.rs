@@ -0,0 +1,141 @@
+use std::{
+    env,
+    error::Error,
+    ops::{Deref, DerefMut},
+    time::Duration,
+};
+
+use diesel::{
+    connection::SimpleConnection,
+    r2d2::{ConnectionManager, CustomizeConnection, Pool, PooledConnection},
+    SqliteConnection,
+};
+use diesel_migrations::{embed_migrations, EmbeddedMigrations};
+use rocket::{
+    request::{FromRequest, Outcome},
+    Request, State,
+};
+use rocket_okapi::{
+    gen::OpenApiGenerator,
+    request::{OpenApiFromRequest, RequestHeaderInput},
+};
+
+use crate::error::{guard_error, ApiError, ErrorCode};
+
+/// Migrations from `migrations/`, compiled into the binary
+pub const MIGRATIONS: EmbeddedMigrations = embed_migrations!("migrations");
+
+pub type DbPool = Pool<ConnectionManager<SqliteConnection>>;
+
+/// Pool size used if `DATABASE_POOL_SIZE` is not set
+pub const DEFAULT_POOL_SIZE: u32 = 8;
+/// Busy timeout in milliseconds used if `DATABASE_BUSY_TIMEOUT` is not set
+pub const DEFAULT_BUSY_TIMEOUT: u64 = 5000;
+
+/// Applies the busy timeout to every connection the pool opens, so concurrent
+/// writers wait for the lock instead of failing with `SQLITE_BUSY`
+#[derive(Debug)]
+struct ConnectionOptions {
+    busy_timeout: Duration,
+}
+
+impl CustomizeConnection<SqliteConnection, diesel::r2d2::Error> for ConnectionOptions {
+    fn on_acquire(&self, conn: &mut SqliteConnection) -> Result<(), diesel::r2d2::Error> {
+        conn.batch_execute(&format!(
+            "PRAGMA busy_timeout = {};",
+            self.busy_timeout.as_millis()
+        ))
+        .map_err(diesel::r2d2::Error::QueryError)
+    }
+}
+
+/// Builds the connection pool from `DATABASE_URL`, `DATABASE_POOL_SIZE` and
+/// `DATABASE_BUSY_TIMEOUT`
+///
+/// # Errors
+///
+/// Fails if `DATABASE_URL` is not set or the database can not be opened
+pub fn pool() -> Result<DbPool, Box<dyn Error + Send + Sync>> {
+    let url = env::var("DATABASE_URL").map_err(|_| "DATABASE_URL must be set")?;
+    let size = env::var("DATABASE_POOL_SIZE")
+        .ok()
+        .and_then(|s| s.parse().ok())
+        .unwrap_or(DEFAULT_POOL_SIZE);
+    let busy_timeout = env::var("DATABASE_BUSY_TIMEOUT")
+        .ok()
+        .and_then(|s| s.parse().ok())
+        .unwrap_or(DEFAULT_BUSY_TIMEOUT);
+
+    Pool::builder()
+        .max_size(size)
+        .connection_customizer(Box::new(ConnectionOptions {
+            busy_timeout: Duration::from_millis(busy_timeout),
+        }))
+        .build(ConnectionManager::new(&url))
+        .map_err(|e| format!("Error connecting to {url}: {e}").into())
+}
+
+/// Connection checked out of the managed [`DbPool`] for the duration of a request
+pub struct DbConn(PooledConnection<ConnectionManager<SqliteConnection>>);
+
+impl Deref for DbConn {
+    type Target = SqliteConnection;
+
+    fn deref(&self) -> &Self::Target {
+        &self.0
+    }
+}
+
+impl DerefMut for DbConn {
+    fn deref_mut(&mut self) -> &mut Self::Target {
+        &mut self.0
+    }
+}
+
+#[rocket::async_trait]
+impl<'r> FromRequest<'r> for DbConn {
+    type Error = ();
+
+    async fn from_request(request: &'r Request<'_>) -> Outcome<Self, Self::Error> {
+        let Some(pool) = request.guard::<&State<DbPool>>().await.succeeded() else {
+            return guard_error(request, ApiError::internal());
+        };
+
+        // Waiting for a free connection blocks, keep it off the async workers
+        let pool = pool.inner().clone();
+        match rocket::tokio::task::spawn_blocking(move || pool.get()).await {
+            Ok(Ok(conn)) => Outcome::Success(DbConn(conn)),
+            Ok(Err(e)) => {
+                error!("Could not get a database connection: {e}");
+                let message = "No database connection available, try again later";
+                guard_error(
+                    request,
+                    ApiError::new(ErrorCode::ServiceUnavailable, message),
+                )
+            }
+            Err(_) => guard_error(request, ApiError::internal()),
+        }
+    }
+}
+
+impl<'r> OpenApiFromRequest<'r> for DbConn {
+    fn from_request_input(
+        _gen: &mut OpenApiGenerator,
+        _name: String,
+        _required: bool,
+    ) -> rocket_okapi::Result<RequestHeaderInput> {
+        Ok(RequestHeaderInput::None)
+    }
+}
+
+/// In-memory database with all migrations applied
+#[cfg(test)]
+#[allow(clippy::missing_panics_doc)]
+pub fn test_connection() -> SqliteConnection {
+    use diesel::Connection;
+    use diesel_migrations::MigrationHarness;
+
+    let mut conn = SqliteConnection::establish(":memory:").unwrap();
+    conn.run_pending_migrations(MIGRATIONS).unwrap();
+    conn
+}
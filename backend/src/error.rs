@@ -0,0 +1,436 @@
+use std::ops::{Deref, DerefMut};
+
+use rocket::{
+    data::{self, Data, FromData, Limits},
+    outcome::Outcome,
+    response::{self, Responder},
+    serde::json::Json,
+    Request, Response,
+};
+use rocket_http::Status;
+use rocket_okapi::{
+    gen::OpenApiGenerator,
+    okapi::{
+        openapi3::{RequestBody, Responses},
+        schemars::{self, JsonSchema},
+    },
+    request::OpenApiFromData,
+    response::OpenApiResponderInner,
+    util::add_schema_response,
+};
+use serde::{de::DeserializeOwned, Serialize};
+use serde_json::{json, Value};
+use validator::{ValidationErrors, ValidationErrorsKind};
+
+/// Machine readable reason of an [`ApiError`]
+#[derive(Clone, Copy, JsonSchema, Serialize, PartialEq, Eq, Debug)]
+#[serde(rename_all = "snake_case")]
+pub enum ErrorCode {
+    /// Malformed request
+    BadRequest,
+    /// No access token was sent
+    Unauthorized,
+    /// Wrong email or password
+    InvalidCredentials,
+    /// The access, refresh or password reset token is invalid, revoked or its user no longer exists
+    InvalidToken,
+    /// The access token has expired, exchange the refresh token for a new one
+    TokenExpired,
+    /// The refresh token has already been exchanged, its session has been revoked
+    TokenReused,
+    /// The user lacks the permission or role required
+    Forbidden,
+    /// The email address has to be verified first, see `POST /register/verify`
+    EmailNotVerified,
+    /// Admin features require two-factor authentication, see `GET /settings`
+    TwoFactorRequired,
+    NotFound,
+    Conflict,
+    /// The email address is already used by another user
+    EmailTaken,
+    /// The room is already booked at that time, `details` names the booking
+    BookingConflict,
+    /// The room still has upcoming bookings, `details` lists them
+    RoomHasBookings,
+    /// A field is missing or invalid, `details.fields` lists the invalid fields
+    ValidationFailed,
+    /// The booking can not change from its current status to the requested one
+    InvalidStatusTransition,
+    /// More attendees than the room has seats
+    CapacityExceeded,
+    /// Too many requests, `details.retry_after` is the number of seconds to wait
+    TooManyRequests,
+    /// Too many failed logins, `details.retry_after` is the number of seconds until it is unlocked
+    AccountLocked,
+    /// The identity provider of the single sign-on could not be reached or returned an error
+    IdentityProviderError,
+    /// No database connection is available, retry later
+    ServiceUnavailable,
+    InternalError,
+}
+
+impl ErrorCode {
+    #[must_use]
+    pub fn status(self) -> Status {
+        match self {
+            ErrorCode::BadRequest => Status::BadRequest,
+            ErrorCode::Unauthorized
+            | ErrorCode::InvalidCredentials
+            | ErrorCode::InvalidToken
+            | ErrorCode::TokenExpired
+            | ErrorCode::TokenReused => Status::Unauthorized,
+            ErrorCode::Forbidden | ErrorCode::EmailNotVerified | ErrorCode::TwoFactorRequired => {
+                Status::Forbidden
+            }
+            ErrorCode::NotFound => Status::NotFound,
+            ErrorCode::Conflict
+            | ErrorCode::EmailTaken
+            | ErrorCode::BookingConflict
+            | ErrorCode::RoomHasBookings => Status::Conflict,
+            ErrorCode::ValidationFailed
+            | ErrorCode::InvalidStatusTransition
+            | ErrorCode::CapacityExceeded => Status::UnprocessableEntity,
+            ErrorCode::TooManyRequests | ErrorCode::AccountLocked => Status::TooManyRequests,
+            ErrorCode::IdentityProviderError => Status::BadGateway,
+            ErrorCode::ServiceUnavailable => Status::ServiceUnavailable,
+            ErrorCode::InternalError => Status::InternalServerError,
+        }
+    }
+}
+
+/// JSON error body returned by all routes and catchers
+#[allow(clippy::module_name_repetitions)]
+#[derive(Clone, JsonSchema, Serialize, Debug)]
+pub struct ApiError {
+    #[serde(skip)]
+    pub status: Status,
+    pub code: ErrorCode,
+    /// Human readable description, not meant to be parsed
+    pub message: String,
+    /// Additional data depending on `code`, e.g. the id of a conflicting booking
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub details: Option<Value>,
+}
+
+impl ApiError {
+    #[must_use]
+    pub fn new(code: ErrorCode, message: impl Into<String>) -> Self {
+        ApiError {
+            status: code.status(),
+            code,
+            message: message.into(),
+            details: None,
+        }
+    }
+
+    #[must_use]
+    pub fn with_details(mut self, details: Value) -> Self {
+        self.details = Some(details);
+        self
+    }
+
+    #[must_use]
+    pub fn not_found(what: &str) -> Self {
+        ApiError::new(ErrorCode::NotFound, format!("{what} not found"))
+    }
+
+    #[must_use]
+    pub fn invalid(message: impl Into<String>) -> Self {
+        ApiError::new(ErrorCode::ValidationFailed, message)
+    }
+
+    #[must_use]
+    pub fn internal() -> Self {
+        ApiError::new(ErrorCode::InternalError, "Internal server error")
+    }
+}
+
+impl From<Status> for ApiError {
+    fn from(status: Status) -> Self {
+        let code = match status.code {
+            401 => ErrorCode::Unauthorized,
+            403 => ErrorCode::Forbidden,
+            404 => ErrorCode::NotFound,
+            409 => ErrorCode::Conflict,
+            422 => ErrorCode::ValidationFailed,
+            429 => ErrorCode::TooManyRequests,
+            503 => ErrorCode::ServiceUnavailable,
+            400..=499 => ErrorCode::BadRequest,
+            _ => ErrorCode::InternalError,
+        };
+
+        ApiError {
+            status,
+            code,
+            message: status.reason_lossy().to_string(),
+            details: None,
+        }
+    }
+}
+
+/// Invalid field of a request body, listed in `details.fields` of `validation_failed`
+#[derive(Clone, JsonSchema, Serialize, Debug)]
+pub struct FieldError {
+    /// Path of the field, nested fields are separated by `.`
+    pub field: String,
+    pub code: String,
+    pub message: String,
+}
+
+fn field_errors(prefix: &str, errors: &ValidationErrors, out: &mut Vec<FieldError>) {
+    for (field, kind) in errors.errors() {
+        let path = if prefix.is_empty() {
+            (*field).to_string()
+        } else {
+            format!("{prefix}.{field}")
+        };
+
+        match kind {
+            ValidationErrorsKind::Field(errors) => {
+                out.extend(errors.iter().map(|e| {
+                    FieldError {
+                        field: path.clone(),
+                        code: e.code.to_string(),
+                        message: e.message.as_ref().map_or_else(
+                            || format!("is invalid ({})", e.code),
+                            ToString::to_string,
+                        ),
+                    }
+                }));
+            }
+            ValidationErrorsKind::Struct(errors) => field_errors(&path, errors, out),
+            ValidationErrorsKind::List(list) => {
+                for (i, errors) in list {
+                    field_errors(&format!("{path}.{i}"), errors, out);
+                }
+            }
+        }
+    }
+}
+
+impl From<ValidationErrors> for ApiError {
+    fn from(errors: ValidationErrors) -> Self {
+        let mut fields = Vec::new();
+        field_errors("", &errors, &mut fields);
+        fields.sort_by(|a, b| a.field.cmp(&b.field));
+
+        let mut names: Vec<_> = fields.iter().map(|f| f.field.as_str()).collect();
+        names.dedup();
+        let message = format!("Invalid fields: {}", names.join(", "));
+        ApiError::invalid(message).with_details(json!({ "fields": fields }))
+    }
+}
+
+/// Lets database errors be returned from transactions with `?`
+impl From<diesel::result::Error> for ApiError {
+    fn from(e: diesel::result::Error) -> Self {
+        error!("Database error: {e}");
+        ApiError::internal()
+    }
+}
+
+impl<'r> Responder<'r, 'static> for ApiError {
+    fn respond_to(self, request: &'r Request<'_>) -> response::Result<'static> {
+        Response::build_from(Json(&self).respond_to(request)?)
+            .status(self.status)
+            .ok()
+    }
+}
+
+impl OpenApiResponderInner for ApiError {
+    fn responses(gen: &mut OpenApiGenerator) -> rocket_okapi::Result<Responses> {
+        let schema = gen.json_schema::<ApiError>();
+        let mut responses = Responses::default();
+        for status in [400, 401, 403, 404, 409, 422, 429, 500, 502, 503] {
+            add_schema_response(&mut responses, status, "application/json", schema.clone())?;
+        }
+        Ok(responses)
+    }
+}
+
+/// Error of a failed request guard, Rocket only hands the status to the catchers
+struct GuardError(Option<ApiError>);
+
+/// Fails a request or data guard with `error`, which the catchers then return as body
+pub fn guard_error<T, F>(request: &Request<'_>, error: ApiError) -> Outcome<T, (Status, ()), F> {
+    let status = error.status;
+    request.local_cache(|| GuardError(Some(error)));
+    Outcome::Error((status, ()))
+}
+
+/// Error a guard failed the request with, if the guard recorded one for `status`
+fn guard_failure(status: Status, request: &Request<'_>) -> Option<ApiError> {
+    request
+        .local_cache(|| GuardError(None))
+        .0
+        .clone()
+        .filter(|e| e.status == status)
+}
+
+/// JSON request body, like Rocket's `Json` but a body that does not match the expected
+/// fields is rejected with 422 `validation_failed` listing the invalid field in
+/// `details.fields`, e.g. a wrong type, an unknown or a missing field
+#[derive(Debug)]
+pub struct JsonBody<T>(pub T);
+
+impl<T> Deref for JsonBody<T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        &self.0
+    }
+}
+
+impl<T> DerefMut for JsonBody<T> {
+    fn deref_mut(&mut self) -> &mut T {
+        &mut self.0
+    }
+}
+
+#[rocket::async_trait]
+impl<'r, T: DeserializeOwned> FromData<'r> for JsonBody<T> {
+    type Error = ();
+
+    async fn from_data(request: &'r Request<'_>, data: Data<'r>) -> data::Outcome<'r, Self> {
+        let limit = request.limits().get("json").unwrap_or(Limits::JSON);
+        let body = match data.open(limit).into_string().await {
+            Ok(body) if body.is_complete() => body.into_inner(),
+            Ok(_) => {
+                let error = ApiError {
+                    message: format!("The request body is larger than {limit}"),
+                    ..ApiError::from(Status::PayloadTooLarge)
+                };
+                return guard_error(request, error);
+            }
+            Err(e) => {
+                let message = format!("The request body could not be read: {e}");
+                return guard_error(request, ApiError::new(ErrorCode::BadRequest, message));
+            }
+        };
+
+        let deserializer = &mut serde_json::Deserializer::from_str(&body);
+        match serde_path_to_error::deserialize(deserializer) {
+            Ok(value) => Outcome::Success(JsonBody(value)),
+            Err(e) if e.inner().is_data() => {
+                let field = body_field_error(&e);
+                let message = format!("Invalid fields: {}", field.field);
+                let error = ApiError::invalid(message).with_details(json!({ "fields": [field] }));
+                guard_error(request, error)
+            }
+            Err(e) => {
+                let message = format!("The request body is not valid JSON: {}", e.inner());
+                guard_error(request, ApiError::new(ErrorCode::BadRequest, message))
+            }
+        }
+    }
+}
+
+impl<'r, T: JsonSchema + DeserializeOwned> OpenApiFromData<'r> for JsonBody<T> {
+    fn request_body(gen: &mut OpenApiGenerator) -> rocket_okapi::Result<RequestBody> {
+        Json::<T>::request_body(gen)
+    }
+}
+
+/// Names the field a body was rejected for, serde reports missing fields at the
+/// enclosing object
+fn body_field_error(error: &serde_path_to_error::Error<serde_json::Error>) -> FieldError {
+    let message = error.inner().to_string();
+    // serde_json appends the position, e.g. " at line 1 column 5"
+    let message = message.split(" at line ").next().unwrap_or_default();
+    let path = error.path().to_string();
+    let path = path.trim_start_matches('.');
+
+    let missing = message
+        .strip_prefix("missing field ")
+        .and_then(|m| m.split('`').nth(1));
+    let (field, code, message) = match missing {
+        Some(name) if path.is_empty() => (name.to_string(), "required", "is required"),
+        Some(name) => (format!("{path}.{name}"), "required", "is required"),
+        None if message.starts_with("unknown field ") => {
+            (path.to_string(), "unknown_field", "is not allowed")
+        }
+        None => (path.to_string(), "invalid", message),
+    };
+    FieldError {
+        field,
+        code: code.to_string(),
+        message: message.to_string(),
+    }
+}
+
+#[catch(404)]
+pub fn not_found(request: &Request<'_>) -> ApiError {
+    ApiError::new(
+        ErrorCode::NotFound,
+        format!("No route for {} {}", request.method(), request.uri()),
+    )
+}
+
+#[catch(422)]
+pub fn unprocessable_entity(request: &Request<'_>) -> ApiError {
+    guard_failure(Status::UnprocessableEntity, request).unwrap_or_else(|| {
+        ApiError::invalid("The request body or parameters are missing or invalid")
+    })
+}
+
+#[catch(500)]
+pub fn internal_error() -> ApiError {
+    ApiError::internal()
+}
+
+/// Any other status, e.g. 401 and 403 of the authentication guards
+#[catch(default)]
+pub fn default_catcher(status: Status, request: &Request<'_>) -> ApiError {
+    guard_failure(status, request).unwrap_or_else(|| ApiError::from(status))
+}
+
+#[cfg(test)]
+mod tests {
+    use serde::Deserialize;
+
+    use super::*;
+
+    #[derive(Deserialize, Debug)]
+    #[serde(deny_unknown_fields)]
+    #[allow(dead_code)]
+    struct Body {
+        name: String,
+        nested: Option<Nested>,
+    }
+
+    #[derive(Deserialize, Debug)]
+    #[serde(deny_unknown_fields)]
+    #[allow(dead_code)]
+    struct Nested {
+        count: u32,
+    }
+
+    fn field_error(body: &str) -> FieldError {
+        let deserializer = &mut serde_json::Deserializer::from_str(body);
+        let error = serde_path_to_error::deserialize::<_, Body>(deserializer).unwrap_err();
+        body_field_error(&error)
+    }
+
+    #[test]
+    fn wrong_type_names_the_field() {
+        let error = field_error(r#"{"name":"a","nested":{"count":"x"}}"#);
+        assert_eq!(error.field, "nested.count");
+        assert_eq!(error.code, "invalid");
+        assert!(!error.message.contains("line"));
+    }
+
+    #[test]
+    fn unknown_field_is_named() {
+        let error = field_error(r#"{"name":"a","is_admin":true}"#);
+        assert_eq!(error.field, "is_admin");
+        assert_eq!(error.code, "unknown_field");
+    }
+
+    #[test]
+    fn missing_field_is_named_with_its_path() {
+        assert_eq!(field_error("{}").field, "name");
+        let error = field_error(r#"{"name":"a","nested":{}}"#);
+        assert_eq!(error.field, "nested.count");
+        assert_eq!(error.code, "required");
+    }
+}
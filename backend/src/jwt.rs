@@ -0,0 +1,286 @@
+use std::{env, fs, sync::OnceLock};
+
+use base64::{
+    engine::general_purpose::{STANDARD, URL_SAFE_NO_PAD},
+    Engine,
+};
+use jsonwebtoken::{
+    errors::{ErrorKind, Result as JwtResult},
+    Algorithm, DecodingKey, EncodingKey, Header, Validation,
+};
+use rocket_okapi::okapi::schemars::{self, JsonSchema};
+use rsa::{
+    pkcs1::DecodeRsaPublicKey, pkcs8::DecodePublicKey, traits::PublicKeyParts, RsaPublicKey,
+};
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+
+/// Minimum length of `JWT_SECRET` in bytes for HS256
+pub const MIN_SECRET_LENGTH: usize = 32;
+/// Minimum RSA modulus size in bits for RS256
+pub const MIN_RSA_BITS: usize = 2048;
+
+static KEYS: OnceLock<JwtKeys> = OnceLock::new();
+
+/// Public key in JSON Web Key format
+#[derive(JsonSchema, Serialize, Deserialize, Clone, Debug)]
+pub struct Jwk {
+    pub kty: String,
+    pub kid: String,
+    pub alg: String,
+    #[serde(rename = "use")]
+    pub use_: String,
+    /// RSA modulus
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub n: Option<String>,
+    /// RSA exponent
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub e: Option<String>,
+    /// Curve of an octet key pair
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub crv: Option<String>,
+    /// Public key of an octet key pair
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub x: Option<String>,
+}
+
+#[derive(JsonSchema, Serialize, Deserialize, Debug)]
+pub struct Jwks {
+    pub keys: Vec<Jwk>,
+}
+
+struct VerificationKey {
+    kid: String,
+    key: DecodingKey,
+    /// `None` for shared secrets, which must never be published
+    jwk: Option<Jwk>,
+}
+
+/// Signing key and all keys tokens are accepted from, loaded once at startup
+///
+/// Configured with
+/// - `JWT_ALGORITHM`: `HS256` (default), `RS256` or `EdDSA`
+/// - `JWT_KEY_ID`: `kid` of the signing key, defaults to `default`
+/// - `JWT_SECRET`: shared secret for HS256, at least [`MIN_SECRET_LENGTH`] bytes
+/// - `JWT_PRIVATE_KEY` and `JWT_PUBLIC_KEY`: PEM files for RS256 and EdDSA
+/// - `JWT_VERIFY_KEYS`: comma separated `kid=value` pairs of previous keys that are
+///   still accepted, the value is a public PEM file or the secret for HS256
+pub struct JwtKeys {
+    algorithm: Algorithm,
+    kid: String,
+    signing_key: EncodingKey,
+    verification_keys: Vec<VerificationKey>,
+}
+
+impl JwtKeys {
+    /// Loads and validates all keys from the environment
+    ///
+    /// # Errors
+    ///
+    /// Fails if a key is missing, can not be read, is too weak or the signing key
+    /// does not match its public key
+    pub fn from_env() -> Result<Self, String> {
+        let algorithm = match env::var("JWT_ALGORITHM").as_deref() {
+            Ok("HS256") | Err(_) => Algorithm::HS256,
+            Ok("RS256") => Algorithm::RS256,
+            Ok("EdDSA") => Algorithm::EdDSA,
+            Ok(a) => return Err(format!("Unsupported JWT_ALGORITHM {a}")),
+        };
+        let kid = env::var("JWT_KEY_ID").unwrap_or_else(|_| "default".to_string());
+
+        let (signing_key, current) = match algorithm {
+            Algorithm::HS256 => {
+                let secret = env::var("JWT_SECRET").map_err(|_| "JWT_SECRET must be set")?;
+                (
+                    EncodingKey::from_secret(secret.as_bytes()),
+                    verification_key(algorithm, &kid, &secret)?,
+                )
+            }
+            _ => {
+                let private_key = read_env_file("JWT_PRIVATE_KEY")?;
+                let signing_key = match algorithm {
+                    Algorithm::RS256 => EncodingKey::from_rsa_pem(&private_key),
+                    _ => EncodingKey::from_ed_pem(&private_key),
+                }
+                .map_err(|e| format!("Invalid JWT_PRIVATE_KEY: {e}"))?;
+
+                let public_key =
+                    env::var("JWT_PUBLIC_KEY").map_err(|_| "JWT_PUBLIC_KEY must be set")?;
+                (signing_key, verification_key(algorithm, &kid, &public_key)?)
+            }
+        };
+
+        let mut verification_keys = vec![current];
+        for entry in env::var("JWT_VERIFY_KEYS")
+            .unwrap_or_default()
+            .split(',')
+            .filter(|e| !e.trim().is_empty())
+        {
+            let Some((kid, value)) = entry.trim().split_once('=') else {
+                return Err(format!(
+                    "Invalid JWT_VERIFY_KEYS entry {entry}, expected kid=value"
+                ));
+            };
+            if verification_keys.iter().any(|k| k.kid == kid) {
+                return Err(format!("Duplicate JWT key id {kid}"));
+            }
+            verification_keys.push(verification_key(algorithm, kid, value)?);
+        }
+
+        let keys = JwtKeys {
+            algorithm,
+            kid,
+            signing_key,
+            verification_keys,
+        };
+
+        // Catches a private key that does not belong to the configured public key
+        let probe = keys
+            .encode(&serde_json::json!({ "exp": u64::MAX / 2 }))
+            .ok_or("Could not sign a token with JWT_PRIVATE_KEY")?;
+        if keys.decode::<serde_json::Value>(&probe).is_err() {
+            return Err("JWT_PRIVATE_KEY does not match JWT_PUBLIC_KEY".to_string());
+        }
+
+        Ok(keys)
+    }
+
+    #[must_use]
+    pub fn encode<T: Serialize>(&self, claims: &T) -> Option<String> {
+        let mut header = Header::new(self.algorithm);
+        header.kid = Some(self.kid.clone());
+
+        jsonwebtoken::encode(&header, claims, &self.signing_key).ok()
+    }
+
+    /// Verifies a token against the key named by its `kid`, tokens without `kid`
+    /// are checked against the current signing key
+    ///
+    /// # Errors
+    ///
+    /// Fails if the token is malformed, expired or not signed by a known key
+    pub fn decode<T: DeserializeOwned>(&self, token: &str) -> JwtResult<T> {
+        let header = jsonwebtoken::decode_header(token)?;
+        if header.alg != self.algorithm {
+            return Err(ErrorKind::InvalidAlgorithm.into());
+        }
+
+        let kid = header.kid.as_deref().unwrap_or(&self.kid);
+        let Some(key) = self.verification_keys.iter().find(|k| k.kid == kid) else {
+            return Err(ErrorKind::InvalidToken.into());
+        };
+
+        jsonwebtoken::decode::<T>(token, &key.key, &Validation::new(self.algorithm))
+            .map(|data| data.claims)
+    }
+
+    /// Public keys for verifying tokens, empty for HS256
+    #[must_use]
+    pub fn jwks(&self) -> Jwks {
+        Jwks {
+            keys: self
+                .verification_keys
+                .iter()
+                .filter_map(|k| k.jwk.clone())
+                .collect(),
+        }
+    }
+}
+
+/// Installs the keys used by [`keys`]
+///
+/// # Errors
+///
+/// Returns the keys back if they have already been installed
+pub fn init(keys: JwtKeys) -> Result<(), JwtKeys> {
+    KEYS.set(keys)
+}
+
+/// Keys loaded at startup, `None` if [`init`] has not been called
+#[must_use]
+pub fn keys() -> Option<&'static JwtKeys> {
+    KEYS.get()
+}
+
+fn read_env_file(var: &str) -> Result<Vec<u8>, String> {
+    let path = env::var(var).map_err(|_| format!("{var} must be set"))?;
+    fs::read(&path).map_err(|e| format!("Could not read {var} {path}: {e}"))
+}
+
+/// Builds a verification key, `value` is the secret for HS256 and a path to a
+/// public PEM file otherwise
+fn verification_key(
+    algorithm: Algorithm,
+    kid: &str,
+    value: &str,
+) -> Result<VerificationKey, String> {
+    match algorithm {
+        Algorithm::HS256 => {
+            if value.len() < MIN_SECRET_LENGTH {
+                return Err(format!(
+                    "JWT secret {kid} is too short, at least {MIN_SECRET_LENGTH} bytes are required"
+                ));
+            }
+            Ok(VerificationKey {
+                kid: kid.to_string(),
+                key: DecodingKey::from_secret(value.as_bytes()),
+                jwk: None,
+            })
+        }
+        Algorithm::RS256 => {
+            let pem = fs::read_to_string(value)
+                .map_err(|e| format!("Could not read public key {kid} {value}: {e}"))?;
+            let public_key = RsaPublicKey::from_public_key_pem(&pem)
+                .or_else(|_| RsaPublicKey::from_pkcs1_pem(&pem))
+                .map_err(|e| format!("Invalid RSA public key {kid}: {e}"))?;
+            if public_key.size() * 8 < MIN_RSA_BITS {
+                return Err(format!(
+                    "RSA key {kid} is too weak, at least {MIN_RSA_BITS} bits are required"
+                ));
+            }
+
+            Ok(VerificationKey {
+                kid: kid.to_string(),
+                key: DecodingKey::from_rsa_pem(pem.as_bytes())
+                    .map_err(|e| format!("Invalid RSA public key {kid}: {e}"))?,
+                jwk: Some(Jwk {
+                    kty: "RSA".to_string(),
+                    kid: kid.to_string(),
+                    alg: "RS256".to_string(),
+                    use_: "sig".to_string(),
+                    n: Some(URL_SAFE_NO_PAD.encode(public_key.n().to_bytes_be())),
+                    e: Some(URL_SAFE_NO_PAD.encode(public_key.e().to_bytes_be())),
+                    crv: None,
+                    x: None,
+                }),
+            })
+        }
+        _ => {
+            let pem = fs::read_to_string(value)
+                .map_err(|e| format!("Could not read public key {kid} {value}: {e}"))?;
+            let der: String = pem.lines().filter(|l| !l.starts_with("-----")).collect();
+            let der = STANDARD
+                .decode(der.trim())
+                .map_err(|e| format!("Invalid Ed25519 public key {kid}: {e}"))?;
+            // SubjectPublicKeyInfo of Ed25519 is a 12 byte prefix followed by the raw key
+            let Some(x) = der.get(12..).filter(|x| der.len() == 44 && x.len() == 32) else {
+                return Err(format!("Invalid Ed25519 public key {kid}"));
+            };
+
+            Ok(VerificationKey {
+                kid: kid.to_string(),
+                key: DecodingKey::from_ed_pem(pem.as_bytes())
+                    .map_err(|e| format!("Invalid Ed25519 public key {kid}: {e}"))?,
+                jwk: Some(Jwk {
+                    kty: "OKP".to_string(),
+                    kid: kid.to_string(),
+                    alg: "EdDSA".to_string(),
+                    use_: "sig".to_string(),
+                    n: None,
+                    e: None,
+                    crv: Some("Ed25519".to_string()),
+                    x: Some(URL_SAFE_NO_PAD.encode(x)),
+                }),
+            })
+        }
+    }
+}
@@ -1,42 +1,108 @@
 pub mod auth;
+pub mod cli;
+pub mod db;
+pub mod error;
+pub mod jwt;
+pub mod mail;
 pub mod models;
+pub mod oidc;
 pub mod routes;
 pub mod schema;
 pub mod util;
-
-use std::env;
+pub mod validation;
 
 use crate::routes::login::get_login;
-use crate::routes::rooms::get_rooms;
+use crate::routes::password::{post_me_password, post_password_forgot, post_password_reset};
+use crate::routes::profile::{delete_me, get_me, patch_me};
+use crate::routes::rooms::{
+    delete_rooms, get_room, get_room_availability, get_rooms, post_rooms, put_rooms,
+};
+use crate::routes::settings::{get_settings, put_settings};
 use crate::routes::users::delete_users;
 use crate::routes::users::get_users;
 use crate::routes::users::post_users;
-use crate::routes::users::put_users;
+use crate::routes::users::{get_roles, put_user_roles, put_users, unlock_users};
 use crate::routes::{
-    bookings::{delete_bookings, get_bookings, patch_bookings, post_bookings},
-    login::{post_login, post_register},
+    api_keys::{delete_me_api_keys, get_me_api_keys, post_me_api_keys},
+    bookings::{
+        approve_bookings, delete_bookings, get_bookings, patch_bookings, post_bookings,
+        reject_bookings,
+    },
+    login::{
+        get_jwks, post_login, post_login_2fa, post_logout, post_logout_all, post_register,
+        post_register_verify, post_register_verify_resend, post_token_refresh,
+    },
+    oidc::{get_oidc_authorize, post_oidc_callback},
+    two_factor::{
+        get_me_2fa, post_me_2fa_disable, post_me_2fa_recovery_codes, post_me_2fa_totp,
+        post_me_2fa_totp_confirm,
+    },
 };
+use std::process::ExitCode;
+
+use clap::Parser;
+use cli::{Cli, Command};
+use db::DbPool;
 use dotenvy::dotenv;
+use jwt::JwtKeys;
+use oidc::OidcConfig;
+use rocket::{Build, Rocket};
 use rocket_cors::{AllowedHeaders, AllowedOrigins};
 use rocket_okapi::{
     openapi_get_routes,
     swagger_ui::{make_swagger_ui, SwaggerUIConfig},
 };
-use routes::login::okapi_add_operation_for_get_login_;
-use routes::login::{okapi_add_operation_for_post_login_, okapi_add_operation_for_post_register_};
-use routes::rooms::okapi_add_operation_for_get_rooms_;
+use routes::login::{okapi_add_operation_for_get_jwks_, okapi_add_operation_for_get_login_};
+use routes::login::{
+    okapi_add_operation_for_post_login_, okapi_add_operation_for_post_login_2fa_,
+    okapi_add_operation_for_post_logout_, okapi_add_operation_for_post_logout_all_,
+    okapi_add_operation_for_post_register_, okapi_add_operation_for_post_register_verify_,
+    okapi_add_operation_for_post_register_verify_resend_,
+    okapi_add_operation_for_post_token_refresh_,
+};
+use routes::oidc::{
+    okapi_add_operation_for_get_oidc_authorize_, okapi_add_operation_for_post_oidc_callback_,
+};
+use routes::password::{
+    okapi_add_operation_for_post_me_password_, okapi_add_operation_for_post_password_forgot_,
+    okapi_add_operation_for_post_password_reset_,
+};
+use routes::profile::{
+    okapi_add_operation_for_delete_me_, okapi_add_operation_for_get_me_,
+    okapi_add_operation_for_patch_me_,
+};
+use routes::rooms::{
+    okapi_add_operation_for_delete_rooms_, okapi_add_operation_for_get_room_,
+    okapi_add_operation_for_get_room_availability_, okapi_add_operation_for_get_rooms_,
+    okapi_add_operation_for_post_rooms_, okapi_add_operation_for_put_rooms_,
+};
+use routes::settings::{
+    okapi_add_operation_for_get_settings_, okapi_add_operation_for_put_settings_,
+};
+use routes::two_factor::{
+    okapi_add_operation_for_get_me_2fa_, okapi_add_operation_for_post_me_2fa_disable_,
+    okapi_add_operation_for_post_me_2fa_recovery_codes_, okapi_add_operation_for_post_me_2fa_totp_,
+    okapi_add_operation_for_post_me_2fa_totp_confirm_,
+};
 use routes::users::okapi_add_operation_for_delete_users_;
 use routes::users::okapi_add_operation_for_post_users_;
-use routes::users::okapi_add_operation_for_put_users_;
+use routes::users::{
+    okapi_add_operation_for_get_roles_, okapi_add_operation_for_put_user_roles_,
+    okapi_add_operation_for_put_users_, okapi_add_operation_for_unlock_users_,
+};
 use routes::{
+    api_keys::{
+        okapi_add_operation_for_delete_me_api_keys_, okapi_add_operation_for_get_me_api_keys_,
+        okapi_add_operation_for_post_me_api_keys_,
+    },
     bookings::{
-        okapi_add_operation_for_delete_bookings_, okapi_add_operation_for_get_bookings_,
-        okapi_add_operation_for_patch_bookings_, okapi_add_operation_for_post_bookings_,
+        okapi_add_operation_for_approve_bookings_, okapi_add_operation_for_delete_bookings_,
+        okapi_add_operation_for_get_bookings_, okapi_add_operation_for_patch_bookings_,
+        okapi_add_operation_for_post_bookings_, okapi_add_operation_for_reject_bookings_,
     },
     users::okapi_add_operation_for_get_users_,
 };
-use util::init_data;
-use util::{load_test_data, setup_logger};
+use util::setup_logger;
 
 #[macro_use]
 extern crate rocket;
@@ -44,13 +110,86 @@ extern crate rocket;
 #[macro_use]
 extern crate log;
 
-#[launch]
-fn rocket() -> _ {
+#[rocket::main]
+async fn main() -> ExitCode {
+    let cli = Cli::parse();
     setup_logger();
     dotenv().ok();
 
-    init_data();
+    let pool = match db::pool() {
+        Ok(pool) => pool,
+        Err(e) => {
+            error!("{e}");
+            return ExitCode::FAILURE;
+        }
+    };
+
+    let command = cli.command.unwrap_or(Command::Serve);
+    let serve = matches!(command, Command::Serve);
+    let result = pool
+        .get()
+        .map_err(Into::into)
+        .and_then(|mut conn| cli::run(&mut conn, command));
+
+    if let Err(e) = result {
+        error!("{e}");
+        return ExitCode::FAILURE;
+    }
+    if !serve {
+        return ExitCode::SUCCESS;
+    }
+
+    let keys = match JwtKeys::from_env() {
+        Ok(keys) => keys,
+        Err(e) => {
+            error!("Invalid JWT configuration: {e}");
+            return ExitCode::FAILURE;
+        }
+    };
+    if jwt::init(keys).is_err() {
+        error!("JWT keys already initialized");
+        return ExitCode::FAILURE;
+    }
+
+    auth::init_dummy_hash();
+
+    let transport = match mail::from_env() {
+        Ok(transport) => transport,
+        Err(e) => {
+            error!("Invalid mail configuration: {e}");
+            return ExitCode::FAILURE;
+        }
+    };
+    if mail::init(transport).is_err() {
+        error!("Mail transport already initialized");
+        return ExitCode::FAILURE;
+    }
 
+    match OidcConfig::from_env() {
+        Ok(Some(config)) => {
+            info!("Single sign-on with {}", config.issuer.as_str());
+            if oidc::init(config).is_err() {
+                error!("Single sign-on already initialized");
+                return ExitCode::FAILURE;
+            }
+        }
+        Ok(None) => {}
+        Err(e) => {
+            error!("Invalid single sign-on configuration: {e}");
+            return ExitCode::FAILURE;
+        }
+    }
+
+    match rocket(pool).launch().await {
+        Ok(_) => ExitCode::SUCCESS,
+        Err(e) => {
+            error!("{e}");
+            ExitCode::FAILURE
+        }
+    }
+}
+
+fn rocket(pool: DbPool) -> Rocket<Build> {
     info!("Starting rocket");
     let cors = rocket_cors::CorsOptions::default()
         .allowed_origins(AllowedOrigins::all())
@@ -61,21 +200,66 @@ fn rocket() -> _ {
 
     rocket::build()
         .attach(cors)
+        .manage(pool)
+        .register(
+            "/",
+            catchers![
+                error::not_found,
+                error::unprocessable_entity,
+                error::internal_error,
+                error::default_catcher,
+            ],
+        )
         .mount(
             "/",
             openapi_get_routes![
                 post_login,
+                post_login_2fa,
                 post_register,
+                post_register_verify,
+                post_register_verify_resend,
+                post_token_refresh,
+                post_logout,
+                post_logout_all,
+                get_jwks,
                 get_login,
+                get_oidc_authorize,
+                post_oidc_callback,
+                post_password_forgot,
+                post_password_reset,
+                post_me_password,
+                get_me,
+                patch_me,
+                delete_me,
+                get_me_2fa,
+                post_me_2fa_totp,
+                post_me_2fa_totp_confirm,
+                post_me_2fa_recovery_codes,
+                post_me_2fa_disable,
+                get_me_api_keys,
+                post_me_api_keys,
+                delete_me_api_keys,
                 get_bookings,
                 post_bookings,
                 delete_bookings,
                 patch_bookings,
+                approve_bookings,
+                reject_bookings,
                 get_users,
                 post_users,
                 put_users,
                 delete_users,
+                unlock_users,
+                get_roles,
+                put_user_roles,
+                get_settings,
+                put_settings,
                 get_rooms,
+                get_room_availability,
+                get_room,
+                post_rooms,
+                put_rooms,
+                delete_rooms,
             ],
         )
         .mount(
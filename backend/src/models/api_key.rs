@@ -0,0 +1,197 @@
+use std::str::FromStr;
+
+use chrono::{Duration, NaiveDateTime, Utc};
+use diesel::{prelude::*, ExpressionMethods, QueryDsl, RunQueryDsl};
+use rocket_okapi::okapi::schemars::{self, JsonSchema};
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    auth::{generate_token, hash_token},
+    schema::api_key,
+};
+
+use super::role::Permission;
+
+/// Prefix of API keys, tells them apart from access tokens and makes leaked keys easy to find
+pub const API_KEY_PREFIX: &str = "cws_";
+/// Characters of a key that are stored in plain text to recognize it in the list
+const KEY_PREFIX_LENGTH: usize = 12;
+/// `last_used_at` is only updated if it is older, so requests do not all write
+const LAST_USED_PRECISION_SECONDS: i64 = 60;
+
+/// What an API key may be used for, permissions still have to be granted to its user
+#[derive(Clone, Copy, JsonSchema, PartialEq, Eq, Serialize, Deserialize, Debug)]
+#[serde(rename_all = "snake_case")]
+pub enum ApiKeyScope {
+    /// `GET` requests, e.g. for room displays
+    Read,
+    /// All requests the user may make, implies `read`
+    Write,
+    /// Use the `manage_bookings` permission of the user
+    ManageBookings,
+    /// Use the `manage_rooms` permission of the user
+    ManageRooms,
+    /// Use the `manage_users` permission of the user
+    ManageUsers,
+}
+
+impl ApiKeyScope {
+    #[must_use]
+    pub fn as_str(self) -> &'static str {
+        match self {
+            ApiKeyScope::Read => "read",
+            ApiKeyScope::Write => "write",
+            ApiKeyScope::ManageBookings => "manage_bookings",
+            ApiKeyScope::ManageRooms => "manage_rooms",
+            ApiKeyScope::ManageUsers => "manage_users",
+        }
+    }
+
+    /// Permission the scope allows to use, `None` for `read` and `write`
+    #[must_use]
+    pub fn permission(self) -> Option<Permission> {
+        match self {
+            ApiKeyScope::Read | ApiKeyScope::Write => None,
+            ApiKeyScope::ManageBookings => Some(Permission::ManageBookings),
+            ApiKeyScope::ManageRooms => Some(Permission::ManageRooms),
+            ApiKeyScope::ManageUsers => Some(Permission::ManageUsers),
+        }
+    }
+}
+
+impl FromStr for ApiKeyScope {
+    type Err = ();
+
+    fn from_str(scope: &str) -> Result<Self, Self::Err> {
+        match scope {
+            "read" => Ok(ApiKeyScope::Read),
+            "write" => Ok(ApiKeyScope::Write),
+            "manage_bookings" => Ok(ApiKeyScope::ManageBookings),
+            "manage_rooms" => Ok(ApiKeyScope::ManageRooms),
+            "manage_users" => Ok(ApiKeyScope::ManageUsers),
+            _ => Err(()),
+        }
+    }
+}
+
+/// Long-lived credential of a user for scripts and devices
+#[allow(clippy::module_name_repetitions)]
+#[derive(Queryable, Selectable, Insertable, Clone, PartialEq, Debug)]
+#[diesel(table_name = crate::schema::api_key)]
+#[diesel(check_for_backend(diesel::sqlite::Sqlite))]
+pub struct ApiKey {
+    pub id: Option<i32>,
+    pub fk_user_id: i32,
+    pub name: String,
+    /// SHA-256 of the key, the key itself is never stored
+    pub key_hash: String,
+    /// Start of the key, e.g. `cws_1a2b3c4d`
+    pub key_prefix: String,
+    /// Space separated [`ApiKeyScope`]s
+    pub scopes: String,
+    pub expires_at: Option<NaiveDateTime>,
+    pub last_used_at: Option<NaiveDateTime>,
+    pub created_at: Option<NaiveDateTime>,
+    pub revoked_at: Option<NaiveDateTime>,
+}
+
+impl ApiKey {
+    /// Creates a key for the user and returns it together with the key in plain text
+    ///
+    /// # Errors
+    ///
+    /// Fails if the key could not be stored
+    pub fn create(
+        conn: &mut SqliteConnection,
+        fk_user_id: i32,
+        name: &str,
+        scopes: &[ApiKeyScope],
+        expires_at: Option<NaiveDateTime>,
+    ) -> QueryResult<(ApiKey, String)> {
+        let key = format!("{API_KEY_PREFIX}{}", generate_token());
+        let mut scope_names: Vec<_> = scopes.iter().map(|s| s.as_str()).collect();
+        scope_names.sort_unstable();
+        scope_names.dedup();
+
+        let api_key = diesel::insert_into(api_key::table)
+            .values(&ApiKey {
+                id: None,
+                fk_user_id,
+                name: name.to_string(),
+                key_hash: hash_token(&key),
+                key_prefix: key[..KEY_PREFIX_LENGTH].to_string(),
+                scopes: scope_names.join(" "),
+                expires_at,
+                last_used_at: None,
+                created_at: None,
+                revoked_at: None,
+            })
+            .returning(ApiKey::as_returning())
+            .get_result(conn)?;
+
+        Ok((api_key, key))
+    }
+
+    /// Keys of the user that have not been revoked, newest first
+    #[must_use]
+    pub fn by_user_id(conn: &mut SqliteConnection, fk_user_id: i32) -> Vec<ApiKey> {
+        api_key::table
+            .filter(api_key::fk_user_id.eq(fk_user_id))
+            .filter(api_key::revoked_at.is_null())
+            .order(api_key::id.desc())
+            .load(conn)
+            .unwrap_or_default()
+    }
+
+    /// Valid key matching `key`, `None` if it is unknown, expired or revoked
+    ///
+    /// Records when the key was last used
+    #[must_use]
+    pub fn authenticate(conn: &mut SqliteConnection, key: &str) -> Option<ApiKey> {
+        let now = Utc::now().naive_utc();
+        let api_key: ApiKey = api_key::table
+            .filter(api_key::key_hash.eq(hash_token(key)))
+            .filter(api_key::revoked_at.is_null())
+            .first(conn)
+            .ok()?;
+        if api_key.expires_at.is_some_and(|e| e <= now) {
+            return None;
+        }
+
+        let stale = now - Duration::seconds(LAST_USED_PRECISION_SECONDS);
+        if api_key.last_used_at.is_none_or(|l| l < stale) {
+            let updated = diesel::update(api_key::table.filter(api_key::id.eq(api_key.id)))
+                .set(api_key::last_used_at.eq(now))
+                .execute(conn);
+            if let Err(e) = updated {
+                warn!("Could not record use of API key {:?}: {e}", api_key.id);
+            }
+        }
+        Some(api_key)
+    }
+
+    /// Revokes a key of the user, `false` if the user has no such key
+    ///
+    /// # Errors
+    ///
+    /// Fails if the key could not be updated
+    pub fn revoke(conn: &mut SqliteConnection, id: i32, fk_user_id: i32) -> QueryResult<bool> {
+        let updated = diesel::update(
+            api_key::table
+                .filter(api_key::id.eq(id))
+                .filter(api_key::fk_user_id.eq(fk_user_id))
+                .filter(api_key::revoked_at.is_null()),
+        )
+        .set(api_key::revoked_at.eq(Utc::now().naive_utc()))
+        .execute(conn)?;
+        Ok(updated > 0)
+    }
+
+    #[must_use]
+    pub fn scopes(&self) -> Vec<ApiKeyScope> {
+        self.scopes
+            .split_whitespace()
+            .filter_map(|s| s.parse().ok())
+            .collect()
+    }
+}
@@ -0,0 +1,106 @@
+use chrono::NaiveDate;
+use diesel::SqliteConnection;
+use rocket_okapi::{okapi::schemars, JsonSchema};
+use serde::{Deserialize, Serialize};
+
+use super::{booking::Booking, room::Room};
+
+/// Upper bound for the number of days in an availability grid
+pub const MAX_DAYS: usize = 31;
+
+#[derive(JsonSchema, Clone, PartialEq, Serialize, Deserialize, Debug)]
+pub struct TimeSlot {
+    /// HH:MM
+    pub start_time: String,
+    /// HH:MM
+    pub end_time: String,
+}
+
+#[derive(JsonSchema, PartialEq, Serialize, Deserialize, Debug)]
+pub struct RoomAvailability {
+    pub room: Room,
+    /// Whether the room is free for the whole requested time range
+    pub free: bool,
+    /// Free intervals within the requested time range
+    pub free_slots: Vec<TimeSlot>,
+}
+
+impl RoomAvailability {
+    /// Free intervals of `room` between `from` and `to`, using the same overlap rules as new bookings
+    #[must_use]
+    pub fn new(room: Room, bookings: &[&Booking], from: &str, to: &str) -> Self {
+        let mut taken: Vec<&&Booking> = bookings
+            .iter()
+            .filter(|b| Some(b.fk_room_id) == room.id && b.blocks(from, to))
+            .collect();
+        taken.sort_by(|a, b| a.start_time.cmp(&b.start_time));
+
+        let mut free_slots = Vec::new();
+        let mut cursor = from;
+        for b in &taken {
+            if b.start_time.as_str() > cursor {
+                free_slots.push(TimeSlot {
+                    start_time: cursor.to_string(),
+                    end_time: b.start_time.clone(),
+                });
+            }
+            if b.end_time.as_str() > cursor {
+                cursor = &b.end_time;
+            }
+        }
+        if cursor < to {
+            free_slots.push(TimeSlot {
+                start_time: cursor.to_string(),
+                end_time: to.to_string(),
+            });
+        }
+
+        RoomAvailability {
+            room,
+            free: taken.is_empty(),
+            free_slots,
+        }
+    }
+}
+
+#[derive(JsonSchema, PartialEq, Serialize, Deserialize, Debug)]
+pub struct DayAvailability {
+    pub date: NaiveDate,
+    pub rooms: Vec<RoomAvailability>,
+}
+
+impl DayAvailability {
+    /// Availability of all rooms for every day from `date` to `until` (inclusive)
+    ///
+    /// Returns `None` if `until` is before `date` or the range exceeds [`MAX_DAYS`]
+    #[must_use]
+    pub fn new(
+        conn: &mut SqliteConnection,
+        date: NaiveDate,
+        until: NaiveDate,
+        from: &str,
+        to: &str,
+    ) -> Option<Vec<Self>> {
+        let days = usize::try_from(until.signed_duration_since(date).num_days()).ok()? + 1;
+        if days > MAX_DAYS {
+            return None;
+        }
+
+        let rooms = Room::all(conn);
+        let bookings = Booking::by_date_range(conn, date, until);
+
+        let mut grid = Vec::new();
+        for day in date.iter_days().take(days) {
+            let day_bookings: Vec<&Booking> = bookings.iter().filter(|b| b.date == day).collect();
+
+            let rooms = rooms
+                .iter()
+                .map(|r| RoomAvailability::new(r.clone(), &day_bookings, from, to))
+                .collect();
+
+            grid.push(DayAvailability { date: day, rooms });
+        }
+
+        Some(grid)
+    }
+}
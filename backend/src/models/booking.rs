@@ -1,23 +1,175 @@
-use diesel::{prelude::*, ExpressionMethods, QueryDsl, RunQueryDsl};
+use std::{collections::HashMap, str::FromStr};
+
+use chrono::{DateTime, NaiveDate, NaiveDateTime, NaiveTime, Utc};
+use diesel::{
+    backend::Backend,
+    deserialize::{self, FromSql, FromSqlRow},
+    expression::AsExpression,
+    prelude::*,
+    serialize::{self, IsNull, Output, ToSql},
+    sql_types::Text,
+    sqlite::Sqlite,
+    ExpressionMethods, QueryDsl, RunQueryDsl,
+};
 use rocket_okapi::JsonSchema;
 use serde::{Deserialize, Serialize};
 
-use crate::{schema::booking, util::conn};
+use crate::schema::{booking, room, user};
 use rocket_okapi::okapi::schemars;
 
-use super::{room::Room, user::User};
+use super::{
+    room::Room,
+    user::{PublicUser, User},
+};
+
+/// Format of `start_time` and `end_time`
+pub const TIME_FORMAT: &str = "%H:%M";
+
+/// Format of dates in requests and query parameters
+pub const DATE_FORMAT: &str = "%Y-%m-%d";
+
+/// Earliest start time of the coworking space
+pub const OPENING_TIME: &str = "08:00";
+
+/// Latest end time of the coworking space
+pub const CLOSING_TIME: &str = "17:00";
+
+/// Start and end time of the former duration codes
+///
+/// 0 = Morning, 1 = Afternoon, 2 = Day
+#[must_use]
+pub fn duration_times(duration: i32) -> Option<(&'static str, &'static str)> {
+    match duration {
+        0 => Some((OPENING_TIME, "12:00")),
+        1 => Some(("12:00", CLOSING_TIME)),
+        2 => Some((OPENING_TIME, CLOSING_TIME)),
+        _ => None,
+    }
+}
+
+/// Parses a `YYYY-MM-DD` date
+#[must_use]
+pub fn parse_date(date: &str) -> Option<NaiveDate> {
+    NaiveDate::parse_from_str(date, DATE_FORMAT).ok()
+}
+
+/// Parses two `HH:MM` times and normalizes them
+///
+/// Returns `None` if either time is invalid or `start` is not before `end`
+#[must_use]
+pub fn parse_time_range(start: &str, end: &str) -> Option<(String, String)> {
+    let start = NaiveTime::parse_from_str(start, TIME_FORMAT).ok()?;
+    let end = NaiveTime::parse_from_str(end, TIME_FORMAT).ok()?;
+
+    if start >= end {
+        return None;
+    }
+
+    Some((
+        start.format(TIME_FORMAT).to_string(),
+        end.format(TIME_FORMAT).to_string(),
+    ))
+}
+
+#[allow(clippy::module_name_repetitions)]
+#[derive(
+    AsExpression, FromSqlRow, Clone, Copy, JsonSchema, Serialize, Deserialize, Debug, PartialEq, Eq,
+)]
+#[diesel(sql_type = Text)]
+pub enum BookingStatus {
+    Pending,
+    Approved,
+    Rejected,
+    Cancelled,
+    Completed,
+    NoShow,
+}
+
+impl BookingStatus {
+    #[must_use]
+    pub fn as_str(self) -> &'static str {
+        match self {
+            BookingStatus::Pending => "Pending",
+            BookingStatus::Approved => "Approved",
+            BookingStatus::Rejected => "Rejected",
+            BookingStatus::Cancelled => "Cancelled",
+            BookingStatus::Completed => "Completed",
+            BookingStatus::NoShow => "NoShow",
+        }
+    }
+
+    /// Allowed status changes
+    ///
+    /// Pending -> Approved, Rejected, Cancelled
+    ///
+    /// Approved -> Cancelled, Completed, NoShow
+    ///
+    /// Rejected, Cancelled, Completed and NoShow are final
+    #[must_use]
+    pub fn can_transition_to(self, next: BookingStatus) -> bool {
+        matches!(
+            (self, next),
+            (
+                BookingStatus::Pending,
+                BookingStatus::Approved | BookingStatus::Rejected | BookingStatus::Cancelled
+            ) | (
+                BookingStatus::Approved,
+                BookingStatus::Cancelled | BookingStatus::Completed | BookingStatus::NoShow
+            )
+        )
+    }
+
+    /// Whether a booking with this status keeps the room occupied
+    #[must_use]
+    pub fn blocks_slot(self) -> bool {
+        !matches!(self, BookingStatus::Rejected | BookingStatus::Cancelled)
+    }
+}
+
+impl FromStr for BookingStatus {
+    type Err = ();
+
+    fn from_str(status: &str) -> Result<Self, Self::Err> {
+        match status {
+            "Pending" => Ok(BookingStatus::Pending),
+            "Approved" => Ok(BookingStatus::Approved),
+            "Rejected" => Ok(BookingStatus::Rejected),
+            "Cancelled" => Ok(BookingStatus::Cancelled),
+            "Completed" => Ok(BookingStatus::Completed),
+            "NoShow" => Ok(BookingStatus::NoShow),
+            _ => Err(()),
+        }
+    }
+}
+
+impl ToSql<Text, Sqlite> for BookingStatus {
+    fn to_sql<'b>(&'b self, out: &mut Output<'b, '_, Sqlite>) -> serialize::Result {
+        out.set_value(self.as_str());
+        Ok(IsNull::No)
+    }
+}
+
+impl FromSql<Text, Sqlite> for BookingStatus {
+    fn from_sql(bytes: <Sqlite as Backend>::RawValue<'_>) -> deserialize::Result<Self> {
+        let status = <String as FromSql<Text, Sqlite>>::from_sql(bytes)?;
+        status
+            .parse()
+            .map_err(|()| format!("Unknown booking status: {status}").into())
+    }
+}
 
 #[allow(clippy::module_name_repetitions)]
 #[derive(Clone, JsonSchema, Serialize, Deserialize, Debug, AsChangeset)]
 #[diesel(table_name = crate::schema::booking)]
 pub struct UpdateBooking {
     pub reason: Option<String>,
-    /// 0 = Morning, 1 = Afternoon, 2 = Day
-    pub duration: Option<i32>,
-    /// Pending, Rejected, Approved
-    pub status: Option<String>,
-    /// YYYY-MM-DD
-    pub date: Option<String>,
+    pub status: Option<BookingStatus>,
+    pub date: Option<NaiveDate>,
+    /// HH:MM
+    pub start_time: Option<String>,
+    /// HH:MM
+    pub end_time: Option<String>,
+    pub attendees: Option<i32>,
 }
 
 #[derive(
@@ -36,15 +188,22 @@ pub struct UpdateBooking {
 pub struct Booking {
     pub id: Option<i32>,
     pub reason: String,
-    /// 0 = Morning, 1 = Afternoon, 2 = Day
-    pub duration: i32,
-    /// Pending, Rejected, Approved
-    pub status: String,
-    /// YYYY-MM-DD
-    pub date: String,
+    pub status: BookingStatus,
+    pub date: NaiveDate,
     pub fk_room_id: i32,
     pub fk_user_id: i32,
-    pub created_at: Option<String>,
+    #[serde(with = "crate::util::utc_timestamp")]
+    #[schemars(with = "Option<DateTime<Utc>>")]
+    pub created_at: Option<NaiveDateTime>,
+    pub fk_series_id: Option<i32>,
+    /// HH:MM
+    pub start_time: String,
+    /// HH:MM
+    pub end_time: String,
+    /// Expected number of attendees, at most the capacity of the room
+    pub attendees: Option<i32>,
+    /// Set by the admin approving or rejecting the booking
+    pub status_comment: Option<String>,
 }
 
 #[allow(clippy::module_name_repetitions)]
@@ -52,133 +211,437 @@ pub struct Booking {
 pub struct SerializeBooking {
     pub id: Option<i32>,
     pub reason: String,
-    /// 0 = Morning, 1 = Afternoon, 2 = Day
-    pub duration: i32,
-    /// Pending, Rejected, Approved
-    pub status: String,
-    /// YYYY-MM-DD
-    pub date: String,
+    pub status: BookingStatus,
+    pub date: NaiveDate,
+    /// HH:MM
+    pub start_time: String,
+    /// HH:MM
+    pub end_time: String,
+    pub attendees: Option<i32>,
+    pub status_comment: Option<String>,
     pub room: Room,
-    pub user: User,
-    pub created_at: Option<String>,
+    pub user: PublicUser,
+    #[serde(with = "crate::util::utc_timestamp")]
+    #[schemars(with = "Option<DateTime<Utc>>")]
+    pub created_at: Option<NaiveDateTime>,
+    pub series_id: Option<i32>,
 }
 
 impl SerializeBooking {
     #[must_use]
-    pub fn from_booking(booking: Booking) -> Option<Self> {
-        let room = Room::by_id(booking.fk_room_id)?;
-        let user = User::by_id(booking.fk_user_id)?;
+    pub fn from_booking(conn: &mut SqliteConnection, booking: Booking) -> Option<Self> {
+        let room = Room::by_id(conn, booking.fk_room_id)?;
+        let user = User::by_id(conn, booking.fk_user_id)?;
+
+        Some(Self::new(booking, room, user))
+    }
+
+    /// All bookings with their room and user, loaded in a single joined query
+    #[must_use]
+    pub fn all(conn: &mut SqliteConnection) -> Vec<Self> {
+        SerializeBooking::load(conn, None, None)
+    }
+
+    #[must_use]
+    pub fn by_user_id(conn: &mut SqliteConnection, id: i32) -> Vec<Self> {
+        SerializeBooking::load(conn, Some(id), None)
+    }
+
+    /// Bookings of a series ordered by date
+    #[must_use]
+    pub fn by_series_id(conn: &mut SqliteConnection, id: i32) -> Vec<Self> {
+        SerializeBooking::load(conn, None, Some(id))
+    }
+
+    fn load(
+        conn: &mut SqliteConnection,
+        fk_user_id: Option<i32>,
+        fk_series_id: Option<i32>,
+    ) -> Vec<Self> {
+        let mut query = booking::table
+            .inner_join(room::table)
+            .inner_join(user::table)
+            .select((Booking::as_select(), Room::as_select(), User::as_select()))
+            .into_boxed();
+
+        if let Some(id) = fk_user_id {
+            query = query.filter(booking::fk_user_id.eq(id));
+        }
+        if let Some(id) = fk_series_id {
+            query = query
+                .filter(booking::fk_series_id.eq(id))
+                .order((booking::date.asc(), booking::id.asc()));
+        } else {
+            query = query.order(booking::id.asc());
+        }
+
+        query
+            .load::<(Booking, Room, User)>(conn)
+            .unwrap_or_default()
+            .into_iter()
+            .map(|(booking, room, user)| Self::new(booking, room, user))
+            .collect()
+    }
 
-        Some(Self {
+    fn new(booking: Booking, room: Room, user: User) -> Self {
+        Self {
             id: booking.id,
             reason: booking.reason,
-            duration: booking.duration,
             status: booking.status,
             date: booking.date,
+            start_time: booking.start_time,
+            end_time: booking.end_time,
+            attendees: booking.attendees,
+            status_comment: booking.status_comment,
             room,
-            user,
+            user: user.into(),
             created_at: booking.created_at,
-        })
+            series_id: booking.fk_series_id,
+        }
     }
 }
 
 impl Booking {
+    #[allow(clippy::too_many_arguments)]
     #[must_use]
     pub fn create(
+        conn: &mut SqliteConnection,
         reason: &str,
-        duration: i32,
-        date: &str,
+        date: NaiveDate,
+        start_time: &str,
+        end_time: &str,
         fk_room_id: i32,
         fk_user_id: i32,
+        fk_series_id: Option<i32>,
+        attendees: Option<i32>,
     ) -> Option<Booking> {
         let new_booking = Booking {
             id: None,
             reason: reason.to_string(),
-            duration,
-            status: "Pending".to_string(),
-            date: date.to_string(),
+            status: BookingStatus::Pending,
+            date,
             fk_room_id,
             fk_user_id,
             created_at: None,
+            fk_series_id,
+            start_time: start_time.to_string(),
+            end_time: end_time.to_string(),
+            attendees,
+            status_comment: None,
         };
 
         diesel::insert_into(booking::table)
             .values(&new_booking)
-            .execute(&mut conn())
-            .ok()?;
-
-        booking::table
-            .order(booking::id.desc())
-            .first(&mut conn())
+            .returning(Booking::as_returning())
+            .get_result(conn)
             .ok()
     }
 
     #[must_use]
-    pub fn all() -> Vec<Booking> {
-        booking::table.load(&mut conn()).unwrap_or_default()
+    pub fn all(conn: &mut SqliteConnection) -> Vec<Booking> {
+        booking::table.load(conn).unwrap_or_default()
     }
 
     #[must_use]
-    pub fn by_id(id: i32) -> Option<Booking> {
-        booking::table
-            .filter(booking::id.eq(id))
-            .first(&mut conn())
-            .ok()
+    pub fn by_id(conn: &mut SqliteConnection, id: i32) -> Option<Booking> {
+        booking::table.filter(booking::id.eq(id)).first(conn).ok()
     }
 
     #[must_use]
-    pub fn by_user_id(id: i32) -> Vec<Booking> {
+    pub fn by_user_id(conn: &mut SqliteConnection, id: i32) -> Vec<Booking> {
         booking::table
             .filter(booking::fk_user_id.eq(id))
-            .load(&mut conn())
+            .load(conn)
             .unwrap_or_default()
     }
 
     #[must_use]
-    pub fn by_date(date: &str) -> Vec<Booking> {
+    pub fn by_date(conn: &mut SqliteConnection, date: NaiveDate) -> Vec<Booking> {
         booking::table
             .filter(booking::date.eq(date))
-            .load(&mut conn())
+            .load(conn)
             .unwrap_or_default()
     }
 
+    /// All bookings from `from` to `until`, both inclusive
     #[must_use]
-    pub fn by_room_id(id: i32) -> Vec<Booking> {
+    pub fn by_date_range(
+        conn: &mut SqliteConnection,
+        from: NaiveDate,
+        until: NaiveDate,
+    ) -> Vec<Booking> {
+        booking::table
+            .filter(booking::date.ge(from))
+            .filter(booking::date.le(until))
+            .load(conn)
+            .unwrap_or_default()
+    }
+
+    #[must_use]
+    pub fn by_series_id(conn: &mut SqliteConnection, id: i32) -> Vec<Booking> {
+        booking::table
+            .filter(booking::fk_series_id.eq(id))
+            .order(booking::date.asc())
+            .load(conn)
+            .unwrap_or_default()
+    }
+
+    /// Whether this booking intersects the time range `start`..`end`
+    ///
+    /// Ranges are half-open, a booking ending at 12:00 does not overlap one starting at 12:00.
+    /// Times are normalized `HH:MM` strings, so they can be compared as text
+    #[must_use]
+    pub fn overlaps(&self, start: &str, end: &str) -> bool {
+        self.start_time.as_str() < end && start < self.end_time.as_str()
+    }
+
+    /// Whether this booking keeps its room occupied during `start`..`end`
+    ///
+    /// Rejected and cancelled bookings free their slot
+    #[must_use]
+    pub fn blocks(&self, start: &str, end: &str) -> bool {
+        self.status.blocks_slot() && self.overlaps(start, end)
+    }
+
+    /// Whether a booking for `room_id` on `date` from `start` to `end` would overlap an existing one
+    #[must_use]
+    pub fn is_slot_taken(
+        conn: &mut SqliteConnection,
+        room_id: i32,
+        date: NaiveDate,
+        start: &str,
+        end: &str,
+    ) -> bool {
+        Booking::conflicting(conn, room_id, date, start, end, None).is_some()
+    }
+
+    /// First existing booking of `room_id` on `date` that overlaps `start` to `end`
+    ///
+    /// The booking with id `except` is ignored, so a booking that is moved does not
+    /// conflict with itself
+    #[must_use]
+    pub fn conflicting(
+        conn: &mut SqliteConnection,
+        room_id: i32,
+        date: NaiveDate,
+        start: &str,
+        end: &str,
+        except: Option<i32>,
+    ) -> Option<Booking> {
+        Booking::by_date(conn, date).into_iter().find(|b| {
+            b.fk_room_id == room_id && b.blocks(start, end) && (except.is_none() || b.id != except)
+        })
+    }
+
+    /// Bookings of all rooms, grouped by room id
+    #[must_use]
+    pub fn all_by_room(conn: &mut SqliteConnection) -> HashMap<i32, Vec<Booking>> {
+        let rows: Vec<Booking> = booking::table
+            .order(booking::id.asc())
+            .load(conn)
+            .unwrap_or_default();
+
+        let mut bookings: HashMap<i32, Vec<Booking>> = HashMap::new();
+        for b in rows {
+            bookings.entry(b.fk_room_id).or_default().push(b);
+        }
+        bookings
+    }
+
+    #[must_use]
+    pub fn by_room_id(conn: &mut SqliteConnection, id: i32) -> Vec<Booking> {
         booking::table
             .filter(booking::fk_room_id.eq(id))
-            .load(&mut conn())
+            .load(conn)
             .unwrap_or_default()
     }
 
+    /// Bookings of a room on or after `from` that keep it occupied
     #[must_use]
-    pub fn update_status(id: i32, status: &str) -> Option<Booking> {
+    pub fn upcoming_by_room_id(
+        conn: &mut SqliteConnection,
+        id: i32,
+        from: NaiveDate,
+    ) -> Vec<Booking> {
+        booking::table
+            .filter(booking::fk_room_id.eq(id))
+            .filter(booking::date.ge(from))
+            .load::<Booking>(conn)
+            .unwrap_or_default()
+            .into_iter()
+            .filter(|b| b.status.blocks_slot())
+            .collect()
+    }
+
+    /// Cancels pending and approved bookings of a user on or after `from`, freeing the rooms
+    ///
+    /// # Errors
+    ///
+    /// Fails if the bookings could not be updated
+    pub fn cancel_upcoming_by_user_id(
+        conn: &mut SqliteConnection,
+        id: i32,
+        from: NaiveDate,
+        comment: &str,
+    ) -> QueryResult<usize> {
+        diesel::update(
+            booking::table
+                .filter(booking::fk_user_id.eq(id))
+                .filter(booking::date.ge(from))
+                .filter(booking::status.eq_any([BookingStatus::Pending, BookingStatus::Approved])),
+        )
+        .set((
+            booking::status.eq(BookingStatus::Cancelled),
+            booking::status_comment.eq(comment),
+        ))
+        .execute(conn)
+    }
+
+    #[must_use]
+    pub fn update_status(
+        conn: &mut SqliteConnection,
+        id: i32,
+        status: BookingStatus,
+        comment: Option<&str>,
+    ) -> Option<Booking> {
         diesel::update(booking::table.filter(booking::id.eq(id)))
-            .set(booking::status.eq(status))
-            .execute(&mut conn())
+            .set((
+                booking::status.eq(status),
+                booking::status_comment.eq(comment),
+            ))
+            .execute(conn)
             .ok()?;
 
-        booking::table
-            .filter(booking::id.eq(id))
-            .first(&mut conn())
-            .ok()
+        booking::table.filter(booking::id.eq(id)).first(conn).ok()
     }
 
     #[must_use]
-    pub fn delete(id: i32) -> bool {
+    pub fn delete(conn: &mut SqliteConnection, id: i32) -> bool {
         diesel::delete(booking::table.filter(booking::id.eq(id)))
-            .execute(&mut conn())
+            .execute(conn)
             .is_ok()
     }
 
-    pub fn update(id: i32, update_booking: UpdateBooking) -> Option<Booking> {
+    pub fn update(
+        conn: &mut SqliteConnection,
+        id: i32,
+        update_booking: UpdateBooking,
+    ) -> Option<Booking> {
         diesel::update(booking::table.filter(booking::id.eq(id)))
             .set(&update_booking)
-            .execute(&mut conn())
+            .execute(conn)
             .ok()?;
 
-        booking::table
-            .filter(booking::id.eq(id))
-            .first(&mut conn())
-            .ok()
+        booking::table.filter(booking::id.eq(id)).first(conn).ok()
+    }
+
+    /// Updates all bookings of a series, optionally only those on or after `from_date`
+    #[must_use]
+    pub fn update_series(
+        conn: &mut SqliteConnection,
+        series_id: i32,
+        from_date: Option<NaiveDate>,
+        update_booking: &UpdateBooking,
+    ) -> bool {
+        let mut query = diesel::update(booking::table)
+            .filter(booking::fk_series_id.eq(series_id))
+            .into_boxed();
+        if let Some(from_date) = from_date {
+            query = query.filter(booking::date.ge(from_date));
+        }
+
+        query.set(update_booking).execute(conn).is_ok()
+    }
+
+    /// Deletes all bookings of a series, optionally only those on or after `from_date`
+    #[must_use]
+    pub fn delete_series(
+        conn: &mut SqliteConnection,
+        series_id: i32,
+        from_date: Option<NaiveDate>,
+    ) -> bool {
+        let mut query = diesel::delete(booking::table)
+            .filter(booking::fk_series_id.eq(series_id))
+            .into_boxed();
+        if let Some(from_date) = from_date {
+            query = query.filter(booking::date.ge(from_date));
+        }
+
+        query.execute(conn).is_ok()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn booking(start: &str, end: &str, status: BookingStatus) -> Booking {
+        Booking {
+            id: Some(1),
+            reason: "Meeting".to_string(),
+            status,
+            date: parse_date("2024-01-02").unwrap(),
+            fk_room_id: 1,
+            fk_user_id: 1,
+            created_at: None,
+            fk_series_id: None,
+            start_time: start.to_string(),
+            end_time: end.to_string(),
+            attendees: None,
+            status_comment: None,
+        }
+    }
+
+    #[test]
+    fn overlapping_ranges() {
+        let b = booking("09:30", "11:00", BookingStatus::Approved);
+        assert!(b.overlaps("09:00", "10:00"));
+        assert!(b.overlaps("10:00", "12:00"));
+        assert!(b.overlaps("09:30", "11:00"));
+        assert!(b.overlaps("08:00", "17:00"));
+        assert!(b.overlaps("10:00", "10:30"));
+    }
+
+    #[test]
+    fn adjacent_ranges_do_not_overlap() {
+        let b = booking("09:30", "11:00", BookingStatus::Approved);
+        assert!(!b.overlaps("08:00", "09:30"));
+        assert!(!b.overlaps("11:00", "12:00"));
+        assert!(!b.overlaps("13:00", "14:00"));
+    }
+
+    #[test]
+    fn only_live_bookings_block() {
+        for status in [
+            BookingStatus::Pending,
+            BookingStatus::Approved,
+            BookingStatus::Completed,
+            BookingStatus::NoShow,
+        ] {
+            assert!(booking("09:00", "12:00", status).blocks("10:00", "11:00"));
+        }
+        for status in [BookingStatus::Rejected, BookingStatus::Cancelled] {
+            assert!(!booking("09:00", "12:00", status).blocks("10:00", "11:00"));
+        }
+        assert!(!booking("09:00", "12:00", BookingStatus::Approved).blocks("12:00", "13:00"));
+    }
+
+    #[test]
+    fn time_ranges_are_normalized() {
+        assert_eq!(
+            parse_time_range("9:30", "11:00"),
+            Some(("09:30".to_string(), "11:00".to_string()))
+        );
+        assert_eq!(parse_time_range("11:00", "11:00"), None);
+        assert_eq!(parse_time_range("12:00", "11:00"), None);
+        assert_eq!(parse_time_range("25:00", "26:00"), None);
+    }
+
+    #[test]
+    fn duration_codes() {
+        assert_eq!(duration_times(0), Some(("08:00", "12:00")));
+        assert_eq!(duration_times(1), Some(("12:00", "17:00")));
+        assert_eq!(duration_times(2), Some(("08:00", "17:00")));
+        assert_eq!(duration_times(3), None);
     }
 }
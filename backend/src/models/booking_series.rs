@@ -0,0 +1,244 @@
+use chrono::{DateTime, Days, Months, NaiveDate, NaiveDateTime, Utc};
+use diesel::{prelude::*, ExpressionMethods, QueryDsl, RunQueryDsl};
+use rocket_okapi::okapi::schemars;
+use rocket_okapi::JsonSchema;
+use serde::{Deserialize, Serialize};
+use validator::Validate;
+
+use crate::{schema::booking_series, validation};
+
+use super::booking::parse_date;
+
+/// Upper bound for the number of bookings a single series may generate
+pub const MAX_OCCURRENCES: usize = 366;
+
+#[derive(Clone, Copy, JsonSchema, Serialize, Deserialize, Debug, PartialEq)]
+pub enum Frequency {
+    Daily,
+    Weekly,
+    Monthly,
+}
+
+impl Frequency {
+    #[must_use]
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Frequency::Daily => "Daily",
+            Frequency::Weekly => "Weekly",
+            Frequency::Monthly => "Monthly",
+        }
+    }
+
+    /// Date of the `n`-th occurrence, always counted from `start` so monthly
+    /// series on the 31st do not drift after a short month
+    #[must_use]
+    pub fn nth_date(self, start: NaiveDate, n: u32) -> Option<NaiveDate> {
+        match self {
+            Frequency::Daily => start.checked_add_days(Days::new(u64::from(n))),
+            Frequency::Weekly => start.checked_add_days(Days::new(u64::from(n) * 7)),
+            Frequency::Monthly => start.checked_add_months(Months::new(n)),
+        }
+    }
+}
+
+#[derive(Clone, JsonSchema, Serialize, Deserialize, Validate, Debug)]
+pub struct Recurrence {
+    pub frequency: Frequency,
+    /// YYYY-MM-DD, last date the series may run on (inclusive)
+    #[validate(custom(function = "validation::date"))]
+    pub until: Option<String>,
+    /// Number of occurrences including the first one
+    #[validate(range(min = 1, max = 366, message = "must be between 1 and 366"))]
+    pub count: Option<u32>,
+}
+
+impl Recurrence {
+    /// All occurrence dates starting at `start`
+    ///
+    /// Returns `None` if neither `until` nor `count` is set, `until` is not a
+    /// valid date or the series would exceed [`MAX_OCCURRENCES`]
+    #[must_use]
+    pub fn dates(&self, start: NaiveDate) -> Option<Vec<NaiveDate>> {
+        let until = match &self.until {
+            Some(u) => Some(parse_date(u)?),
+            None => None,
+        };
+
+        if until.is_none() && self.count.is_none() {
+            return None;
+        }
+
+        let mut dates = Vec::new();
+        let mut n = 0;
+        loop {
+            if self.count.is_some_and(|c| dates.len() >= c as usize) {
+                break;
+            }
+
+            let date = self.frequency.nth_date(start, n)?;
+            if until.is_some_and(|u| date > u) {
+                break;
+            }
+
+            if dates.len() >= MAX_OCCURRENCES {
+                return None;
+            }
+
+            dates.push(date);
+            n += 1;
+        }
+
+        Some(dates)
+    }
+}
+
+#[allow(clippy::module_name_repetitions)]
+#[derive(
+    JsonSchema,
+    Queryable,
+    PartialEq,
+    Selectable,
+    Insertable,
+    Serialize,
+    Deserialize,
+    Debug,
+    AsChangeset,
+)]
+#[diesel(table_name = crate::schema::booking_series)]
+#[diesel(check_for_backend(diesel::sqlite::Sqlite))]
+pub struct BookingSeries {
+    pub id: Option<i32>,
+    /// Daily, Weekly, Monthly
+    pub frequency: String,
+    pub until: Option<NaiveDate>,
+    pub occurrences: Option<i32>,
+    pub fk_room_id: i32,
+    pub fk_user_id: i32,
+    #[serde(with = "crate::util::utc_timestamp")]
+    #[schemars(with = "Option<DateTime<Utc>>")]
+    pub created_at: Option<NaiveDateTime>,
+}
+
+impl BookingSeries {
+    #[must_use]
+    pub fn create(
+        conn: &mut SqliteConnection,
+        recurrence: &Recurrence,
+        fk_room_id: i32,
+        fk_user_id: i32,
+    ) -> Option<BookingSeries> {
+        let new_series = BookingSeries {
+            id: None,
+            frequency: recurrence.frequency.as_str().to_string(),
+            until: recurrence.until.as_deref().and_then(parse_date),
+            occurrences: recurrence.count.and_then(|c| i32::try_from(c).ok()),
+            fk_room_id,
+            fk_user_id,
+            created_at: None,
+        };
+
+        diesel::insert_into(booking_series::table)
+            .values(&new_series)
+            .returning(BookingSeries::as_returning())
+            .get_result(conn)
+            .ok()
+    }
+
+    #[must_use]
+    pub fn by_id(conn: &mut SqliteConnection, id: i32) -> Option<BookingSeries> {
+        booking_series::table
+            .filter(booking_series::id.eq(id))
+            .first(conn)
+            .ok()
+    }
+
+    #[must_use]
+    pub fn delete(conn: &mut SqliteConnection, id: i32) -> bool {
+        diesel::delete(booking_series::table.filter(booking_series::id.eq(id)))
+            .execute(conn)
+            .is_ok()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn date(s: &str) -> NaiveDate {
+        parse_date(s).unwrap()
+    }
+
+    fn recurrence(frequency: Frequency, until: Option<&str>, count: Option<u32>) -> Recurrence {
+        Recurrence {
+            frequency,
+            until: until.map(str::to_string),
+            count,
+        }
+    }
+
+    #[test]
+    fn weekly_with_count() {
+        let dates = recurrence(Frequency::Weekly, None, Some(3))
+            .dates(date("2024-01-02"))
+            .unwrap();
+        assert_eq!(
+            dates,
+            [date("2024-01-02"), date("2024-01-09"), date("2024-01-16")]
+        );
+    }
+
+    #[test]
+    fn until_is_inclusive() {
+        let dates = recurrence(Frequency::Daily, Some("2024-01-04"), None)
+            .dates(date("2024-01-02"))
+            .unwrap();
+        assert_eq!(dates.len(), 3);
+        assert_eq!(dates.last(), Some(&date("2024-01-04")));
+    }
+
+    #[test]
+    fn count_and_until_stop_at_the_first_limit() {
+        let r = recurrence(Frequency::Daily, Some("2024-01-10"), Some(2));
+        assert_eq!(r.dates(date("2024-01-02")).unwrap().len(), 2);
+
+        let r = recurrence(Frequency::Daily, Some("2024-01-03"), Some(5));
+        assert_eq!(r.dates(date("2024-01-02")).unwrap().len(), 2);
+    }
+
+    #[test]
+    fn monthly_does_not_drift_after_short_months() {
+        let dates = recurrence(Frequency::Monthly, None, Some(3))
+            .dates(date("2024-01-31"))
+            .unwrap();
+        assert_eq!(
+            dates,
+            [date("2024-01-31"), date("2024-02-29"), date("2024-03-31")]
+        );
+    }
+
+    #[test]
+    fn until_before_start_has_no_dates() {
+        let r = recurrence(Frequency::Weekly, Some("2024-01-01"), None);
+        assert_eq!(r.dates(date("2024-01-02")), Some(Vec::new()));
+    }
+
+    #[test]
+    fn invalid_recurrences() {
+        let start = date("2024-01-02");
+        assert_eq!(recurrence(Frequency::Daily, None, None).dates(start), None);
+        assert_eq!(
+            recurrence(Frequency::Daily, Some("2024-13-01"), None).dates(start),
+            None
+        );
+        assert_eq!(
+            recurrence(Frequency::Daily, Some("2026-01-02"), None).dates(start),
+            None
+        );
+        assert_eq!(
+            recurrence(Frequency::Daily, Some("2025-01-01"), None)
+                .dates(start)
+                .map(|d| d.len()),
+            Some(MAX_OCCURRENCES)
+        );
+    }
+}
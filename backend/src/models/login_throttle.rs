@@ -0,0 +1,297 @@
+use std::net::IpAddr;
+
+use chrono::{Duration, NaiveDateTime, Utc};
+use diesel::{prelude::*, ExpressionMethods, QueryDsl, RunQueryDsl};
+
+use crate::{auth, schema::login_throttle};
+
+/// What failed logins are counted for
+#[derive(Clone, Debug)]
+pub enum ThrottleKey {
+    /// Email address used to log in, whether or not an account exists for it
+    Account(String),
+    /// Address of the client that tried to log in
+    Ip(IpAddr),
+}
+
+impl ThrottleKey {
+    #[must_use]
+    pub fn account(email: &str) -> Self {
+        ThrottleKey::Account(email.trim().to_lowercase())
+    }
+
+    fn key(&self) -> String {
+        match self {
+            ThrottleKey::Account(email) => format!("account:{email}"),
+            ThrottleKey::Ip(ip) => format!("ip:{ip}"),
+        }
+    }
+
+    fn max_failures(&self) -> i32 {
+        match self {
+            ThrottleKey::Account(_) => auth::login_max_failures(),
+            ThrottleKey::Ip(_) => auth::login_max_failures_per_ip(),
+        }
+    }
+
+    /// Wait before the next attempt after `failures` failures in a row, grows
+    /// exponentially from the third failure on. IP addresses are only locked, many
+    /// users may share one
+    fn delay(&self, failures: i32) -> Duration {
+        match self {
+            ThrottleKey::Account(_) if failures >= 3 => {
+                Duration::seconds((1i64 << (failures - 2).min(6)).min(60))
+            }
+            _ => Duration::zero(),
+        }
+    }
+}
+
+/// Why a login attempt is refused before the password is checked
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Throttled {
+    /// Too many failures in a row, retry after the delay
+    Delayed(Duration),
+    /// Locked until the lockout has passed or an admin unlocks the account
+    Locked(Duration),
+}
+
+/// Failed login attempts in a row of one account or IP address
+#[allow(clippy::module_name_repetitions)]
+#[derive(Queryable, Selectable, Insertable, AsChangeset, PartialEq, Debug)]
+#[diesel(table_name = crate::schema::login_throttle)]
+#[diesel(check_for_backend(diesel::sqlite::Sqlite))]
+#[diesel(treat_none_as_null = true)]
+pub struct LoginThrottle {
+    /// `account:<email>` or `ip:<address>`
+    pub key: String,
+    pub failures: i32,
+    pub last_failure_at: NaiveDateTime,
+    pub locked_until: Option<NaiveDateTime>,
+}
+
+impl LoginThrottle {
+    #[must_use]
+    pub fn by_key(conn: &mut SqliteConnection, key: &ThrottleKey) -> Option<LoginThrottle> {
+        login_throttle::table
+            .filter(login_throttle::key.eq(key.key()))
+            .first(conn)
+            .ok()
+    }
+
+    /// Whether a login for any of the keys has to be refused right now
+    #[must_use]
+    pub fn check(conn: &mut SqliteConnection, keys: &[ThrottleKey]) -> Option<Throttled> {
+        let now = Utc::now().naive_utc();
+
+        keys.iter()
+            .filter_map(|key| {
+                let throttle = LoginThrottle::by_key(conn, key)?;
+                if let Some(locked_until) = throttle.locked_until.filter(|l| *l > now) {
+                    return Some(Throttled::Locked(locked_until - now));
+                }
+
+                let retry_at = throttle.last_failure_at + key.delay(throttle.failures);
+                (retry_at > now).then(|| Throttled::Delayed(retry_at - now))
+            })
+            .max_by_key(|t| match t {
+                Throttled::Locked(d) => (1, *d),
+                Throttled::Delayed(d) => (0, *d),
+            })
+    }
+
+    /// Counts a failed login for all keys, locking those that reach their threshold
+    ///
+    /// Failures older than the lockout are forgotten
+    ///
+    /// # Errors
+    ///
+    /// Fails if the counters could not be updated
+    pub fn record_failure(conn: &mut SqliteConnection, keys: &[ThrottleKey]) -> QueryResult<()> {
+        let now = Utc::now().naive_utc();
+        let lockout = auth::login_lockout();
+
+        conn.immediate_transaction(|conn| {
+            diesel::delete(
+                login_throttle::table
+                    .filter(login_throttle::last_failure_at.lt(now - lockout))
+                    .filter(
+                        login_throttle::locked_until
+                            .is_null()
+                            .or(login_throttle::locked_until.lt(now)),
+                    ),
+            )
+            .execute(conn)?;
+
+            for key in keys {
+                let failures = LoginThrottle::by_key(conn, key)
+                    .filter(|t| t.locked_until.is_none_or(|l| l < now))
+                    .map_or(0, |t| t.failures)
+                    + 1;
+
+                let locked = failures >= key.max_failures();
+                if locked {
+                    warn!("Locking {} after {failures} failed logins", key.key());
+                }
+
+                let throttle = LoginThrottle {
+                    key: key.key(),
+                    failures: if locked { 0 } else { failures },
+                    last_failure_at: now,
+                    locked_until: locked.then_some(now + lockout),
+                };
+                diesel::insert_into(login_throttle::table)
+                    .values(&throttle)
+                    .on_conflict(login_throttle::key)
+                    .do_update()
+                    .set(&throttle)
+                    .execute(conn)?;
+            }
+            Ok(())
+        })
+    }
+
+    /// Forgets all failures of the key, e.g. after a successful login or to unlock an account
+    ///
+    /// # Errors
+    ///
+    /// Fails if the counter could not be deleted
+    pub fn reset(conn: &mut SqliteConnection, key: &ThrottleKey) -> QueryResult<()> {
+        diesel::delete(login_throttle::table.filter(login_throttle::key.eq(key.key())))
+            .execute(conn)?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::db::test_connection;
+
+    fn account() -> ThrottleKey {
+        ThrottleKey::account("User@Example.com ")
+    }
+
+    fn ip() -> ThrottleKey {
+        ThrottleKey::Ip("192.0.2.1".parse().unwrap())
+    }
+
+    fn fail(conn: &mut SqliteConnection, keys: &[ThrottleKey], times: i32) {
+        for _ in 0..times {
+            LoginThrottle::record_failure(conn, keys).unwrap();
+        }
+    }
+
+    #[test]
+    fn accounts_are_normalized() {
+        assert_eq!(account().key(), "account:user@example.com");
+        assert_eq!(ip().key(), "ip:192.0.2.1");
+    }
+
+    #[test]
+    fn account_delay_grows_from_the_third_failure() {
+        let key = account();
+        assert_eq!(key.delay(0), Duration::zero());
+        assert_eq!(key.delay(2), Duration::zero());
+        assert_eq!(key.delay(3), Duration::seconds(2));
+        assert_eq!(key.delay(4), Duration::seconds(4));
+        assert_eq!(key.delay(7), Duration::seconds(32));
+        assert_eq!(key.delay(8), Duration::seconds(60));
+        assert_eq!(key.delay(30), Duration::seconds(60));
+    }
+
+    #[test]
+    fn ip_addresses_are_never_delayed() {
+        assert_eq!(ip().delay(3), Duration::zero());
+        assert_eq!(ip().delay(40), Duration::zero());
+    }
+
+    #[test]
+    fn first_failures_are_not_throttled() {
+        let mut conn = test_connection();
+        assert_eq!(LoginThrottle::check(&mut conn, &[account(), ip()]), None);
+
+        fail(&mut conn, &[account(), ip()], 2);
+        assert_eq!(LoginThrottle::check(&mut conn, &[account(), ip()]), None);
+        assert_eq!(
+            LoginThrottle::by_key(&mut conn, &account()).map(|t| t.failures),
+            Some(2)
+        );
+    }
+
+    #[test]
+    fn third_failure_delays_the_account() {
+        let mut conn = test_connection();
+        fail(&mut conn, &[account(), ip()], 3);
+
+        match LoginThrottle::check(&mut conn, &[account(), ip()]) {
+            Some(Throttled::Delayed(d)) => {
+                assert!(d > Duration::zero() && d <= Duration::seconds(2))
+            }
+            other => panic!("expected a delay, got {other:?}"),
+        }
+        assert_eq!(LoginThrottle::check(&mut conn, &[ip()]), None);
+    }
+
+    #[test]
+    fn too_many_failures_lock_the_account() {
+        let mut conn = test_connection();
+        fail(&mut conn, &[account()], auth::login_max_failures());
+
+        let throttle = LoginThrottle::by_key(&mut conn, &account()).unwrap();
+        assert_eq!(throttle.failures, 0);
+        assert!(throttle.locked_until.is_some());
+        match LoginThrottle::check(&mut conn, &[account()]) {
+            Some(Throttled::Locked(d)) => assert!(d > auth::login_lockout() - Duration::minutes(1)),
+            other => panic!("expected a lock, got {other:?}"),
+        }
+    }
+
+    #[test]
+    fn expired_lock_starts_counting_again() {
+        let mut conn = test_connection();
+        let long_ago = Utc::now().naive_utc() - auth::login_lockout() - Duration::minutes(1);
+        diesel::insert_into(login_throttle::table)
+            .values(&LoginThrottle {
+                key: account().key(),
+                failures: 0,
+                last_failure_at: long_ago,
+                locked_until: Some(long_ago + Duration::seconds(30)),
+            })
+            .execute(&mut conn)
+            .unwrap();
+        assert_eq!(LoginThrottle::check(&mut conn, &[account()]), None);
+
+        fail(&mut conn, &[account()], 1);
+        let throttle = LoginThrottle::by_key(&mut conn, &account()).unwrap();
+        assert_eq!(throttle.failures, 1);
+        assert_eq!(throttle.locked_until, None);
+    }
+
+    #[test]
+    fn old_failures_are_forgotten() {
+        let mut conn = test_connection();
+        let long_ago = Utc::now().naive_utc() - auth::login_lockout() - Duration::minutes(1);
+        diesel::insert_into(login_throttle::table)
+            .values(&LoginThrottle {
+                key: ip().key(),
+                failures: 4,
+                last_failure_at: long_ago,
+                locked_until: None,
+            })
+            .execute(&mut conn)
+            .unwrap();
+
+        fail(&mut conn, &[account()], 1);
+        assert_eq!(LoginThrottle::by_key(&mut conn, &ip()), None);
+    }
+
+    #[test]
+    fn reset_forgets_failures() {
+        let mut conn = test_connection();
+        fail(&mut conn, &[account()], 3);
+        LoginThrottle::reset(&mut conn, &account()).unwrap();
+        assert_eq!(LoginThrottle::check(&mut conn, &[account()]), None);
+        assert_eq!(LoginThrottle::by_key(&mut conn, &account()), None);
+    }
+}
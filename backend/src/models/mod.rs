@@ -1,3 +1,14 @@
+pub mod api_key;
+pub mod availability;
 pub mod booking;
+pub mod booking_series;
+pub mod login_throttle;
+pub mod oidc;
+pub mod preferences;
+pub mod role;
 pub mod room;
+pub mod session;
+pub mod setting;
+pub mod two_factor;
 pub mod user;
+pub mod user_token;
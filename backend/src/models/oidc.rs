@@ -0,0 +1,111 @@
+use chrono::{Duration, NaiveDateTime, Utc};
+use diesel::{prelude::*, ExpressionMethods, QueryDsl, RunQueryDsl};
+
+use crate::{
+    auth::hash_token,
+    schema::{oidc_login, user_identity},
+};
+
+/// Started single sign-on, kept until the identity provider sends the user back
+#[derive(Queryable, Selectable, Insertable, PartialEq, Debug)]
+#[diesel(table_name = crate::schema::oidc_login)]
+#[diesel(check_for_backend(diesel::sqlite::Sqlite))]
+pub struct OidcLogin {
+    /// SHA-256 of the `state` parameter
+    pub state_hash: String,
+    pub nonce: String,
+    pub pkce_verifier: String,
+    pub expires_at: NaiveDateTime,
+}
+
+impl OidcLogin {
+    /// Remembers a login until the callback, forgetting logins that were never finished
+    ///
+    /// # Errors
+    ///
+    /// Fails if the login could not be stored
+    pub fn start(
+        conn: &mut SqliteConnection,
+        state: &str,
+        nonce: &str,
+        pkce_verifier: &str,
+        lifetime: Duration,
+    ) -> QueryResult<()> {
+        let now = Utc::now();
+
+        diesel::delete(oidc_login::table.filter(oidc_login::expires_at.lt(now.naive_utc())))
+            .execute(conn)?;
+        diesel::insert_into(oidc_login::table)
+            .values(&OidcLogin {
+                state_hash: hash_token(state),
+                nonce: nonce.to_string(),
+                pkce_verifier: pkce_verifier.to_string(),
+                expires_at: (now + lifetime).naive_utc(),
+            })
+            .execute(conn)?;
+        Ok(())
+    }
+
+    /// Removes and returns the login of the `state`, `None` if it is unknown or expired
+    ///
+    /// # Errors
+    ///
+    /// Fails if the login could not be deleted
+    pub fn take(conn: &mut SqliteConnection, state: &str) -> QueryResult<Option<OidcLogin>> {
+        let login =
+            diesel::delete(oidc_login::table.filter(oidc_login::state_hash.eq(hash_token(state))))
+                .returning(OidcLogin::as_returning())
+                .get_result(conn)
+                .optional()?;
+
+        Ok(login.filter(|l| l.expires_at > Utc::now().naive_utc()))
+    }
+}
+
+/// Account of the identity provider linked to a user
+#[allow(clippy::module_name_repetitions)]
+#[derive(Queryable, Selectable, Insertable, PartialEq, Debug)]
+#[diesel(table_name = crate::schema::user_identity)]
+#[diesel(check_for_backend(diesel::sqlite::Sqlite))]
+pub struct UserIdentity {
+    pub issuer: String,
+    /// `sub` claim, unique and stable per issuer unlike the email address
+    pub subject: String,
+    pub fk_user_id: i32,
+    pub created_at: Option<NaiveDateTime>,
+}
+
+impl UserIdentity {
+    /// Id of the user linked to the account of the identity provider
+    #[must_use]
+    pub fn user_id(conn: &mut SqliteConnection, issuer: &str, subject: &str) -> Option<i32> {
+        user_identity::table
+            .filter(user_identity::issuer.eq(issuer))
+            .filter(user_identity::subject.eq(subject))
+            .select(user_identity::fk_user_id)
+            .first(conn)
+            .ok()
+    }
+
+    /// Links the account of the identity provider to the user
+    ///
+    /// # Errors
+    ///
+    /// Fails if the account is already linked
+    pub fn link(
+        conn: &mut SqliteConnection,
+        issuer: &str,
+        subject: &str,
+        fk_user_id: i32,
+    ) -> QueryResult<()> {
+        diesel::insert_into(user_identity::table)
+            .values(&UserIdentity {
+                issuer: issuer.to_string(),
+                subject: subject.to_string(),
+                fk_user_id,
+                created_at: None,
+            })
+            .execute(conn)?;
+        Ok(())
+    }
+}
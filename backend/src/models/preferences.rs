@@ -0,0 +1,186 @@
+use std::str::FromStr;
+
+use diesel::{
+    backend::Backend,
+    deserialize::{self, FromSql, FromSqlRow},
+    expression::AsExpression,
+    prelude::*,
+    serialize::{self, IsNull, Output, ToSql},
+    sql_types::Text,
+    sqlite::Sqlite,
+    ExpressionMethods, QueryDsl, RunQueryDsl,
+};
+use rocket_okapi::okapi::schemars::{self, JsonSchema};
+use serde::{Deserialize, Serialize};
+
+use crate::schema::user_preference;
+
+/// Language of the frontend
+#[derive(
+    AsExpression,
+    FromSqlRow,
+    Clone,
+    Copy,
+    Default,
+    JsonSchema,
+    Serialize,
+    Deserialize,
+    Debug,
+    PartialEq,
+    Eq,
+)]
+#[diesel(sql_type = Text)]
+#[serde(rename_all = "lowercase")]
+pub enum Language {
+    #[default]
+    En,
+    De,
+}
+
+impl Language {
+    #[must_use]
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Language::En => "en",
+            Language::De => "de",
+        }
+    }
+}
+
+impl FromStr for Language {
+    type Err = ();
+
+    fn from_str(language: &str) -> Result<Self, Self::Err> {
+        match language {
+            "en" => Ok(Language::En),
+            "de" => Ok(Language::De),
+            _ => Err(()),
+        }
+    }
+}
+
+impl ToSql<Text, Sqlite> for Language {
+    fn to_sql<'b>(&'b self, out: &mut Output<'b, '_, Sqlite>) -> serialize::Result {
+        out.set_value(self.as_str());
+        Ok(IsNull::No)
+    }
+}
+
+impl FromSql<Text, Sqlite> for Language {
+    fn from_sql(bytes: <Sqlite as Backend>::RawValue<'_>) -> deserialize::Result<Self> {
+        let language = <String as FromSql<Text, Sqlite>>::from_sql(bytes)?;
+        language
+            .parse()
+            .map_err(|()| format!("Unknown language: {language}").into())
+    }
+}
+
+/// Color scheme of the frontend, `system` follows the operating system
+#[derive(
+    AsExpression,
+    FromSqlRow,
+    Clone,
+    Copy,
+    Default,
+    JsonSchema,
+    Serialize,
+    Deserialize,
+    Debug,
+    PartialEq,
+    Eq,
+)]
+#[diesel(sql_type = Text)]
+#[serde(rename_all = "lowercase")]
+pub enum Theme {
+    #[default]
+    System,
+    Light,
+    Dark,
+}
+
+impl Theme {
+    #[must_use]
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Theme::System => "system",
+            Theme::Light => "light",
+            Theme::Dark => "dark",
+        }
+    }
+}
+
+impl FromStr for Theme {
+    type Err = ();
+
+    fn from_str(theme: &str) -> Result<Self, Self::Err> {
+        match theme {
+            "system" => Ok(Theme::System),
+            "light" => Ok(Theme::Light),
+            "dark" => Ok(Theme::Dark),
+            _ => Err(()),
+        }
+    }
+}
+
+impl ToSql<Text, Sqlite> for Theme {
+    fn to_sql<'b>(&'b self, out: &mut Output<'b, '_, Sqlite>) -> serialize::Result {
+        out.set_value(self.as_str());
+        Ok(IsNull::No)
+    }
+}
+
+impl FromSql<Text, Sqlite> for Theme {
+    fn from_sql(bytes: <Sqlite as Backend>::RawValue<'_>) -> deserialize::Result<Self> {
+        let theme = <String as FromSql<Text, Sqlite>>::from_sql(bytes)?;
+        theme
+            .parse()
+            .map_err(|()| format!("Unknown theme: {theme}").into())
+    }
+}
+
+/// Settings of a user that follow them across devices
+#[derive(
+    Queryable,
+    Selectable,
+    Clone,
+    Copy,
+    Default,
+    JsonSchema,
+    Serialize,
+    Deserialize,
+    Debug,
+    PartialEq,
+    Eq,
+)]
+#[diesel(table_name = crate::schema::user_preference)]
+#[diesel(check_for_backend(diesel::sqlite::Sqlite))]
+pub struct Preferences {
+    pub language: Language,
+    pub theme: Theme,
+}
+
+impl Preferences {
+    /// Preferences of the user, the defaults if they never changed any
+    #[must_use]
+    pub fn by_user_id(conn: &mut SqliteConnection, id: i32) -> Preferences {
+        user_preference::table
+            .filter(user_preference::fk_user_id.eq(id))
+            .select(Preferences::as_select())
+            .first(conn)
+            .unwrap_or_default()
+    }
+
+    /// # Errors
+    ///
+    /// Fails if the preferences could not be stored
+    pub fn save(&self, conn: &mut SqliteConnection, id: i32) -> QueryResult<()> {
+        diesel::replace_into(user_preference::table)
+            .values((
+                user_preference::fk_user_id.eq(id),
+                user_preference::language.eq(self.language),
+                user_preference::theme.eq(self.theme),
+            ))
+            .execute(conn)?;
+        Ok(())
+    }
+}
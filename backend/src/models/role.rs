@@ -0,0 +1,246 @@
+use std::str::FromStr;
+
+use diesel::{prelude::*, ExpressionMethods, QueryDsl, RunQueryDsl};
+use rocket_okapi::{okapi::schemars, JsonSchema};
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    models::user::User,
+    schema::{role, role_permission, user, user_role},
+};
+
+/// Role every new user gets
+pub const MEMBER: &str = "member";
+/// Role with all permissions, kept in sync with `user.is_admin`
+pub const SUPER_ADMIN: &str = "super_admin";
+
+#[derive(Clone, Copy, JsonSchema, PartialEq, Eq, Serialize, Deserialize, Debug)]
+#[serde(rename_all = "snake_case")]
+pub enum Permission {
+    /// See, change, approve, reject and delete bookings of all users
+    ManageBookings,
+    /// Create, change and delete rooms
+    ManageRooms,
+    /// Create, change and delete users and assign roles
+    ManageUsers,
+}
+
+impl Permission {
+    #[must_use]
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Permission::ManageBookings => "manage_bookings",
+            Permission::ManageRooms => "manage_rooms",
+            Permission::ManageUsers => "manage_users",
+        }
+    }
+}
+
+impl FromStr for Permission {
+    type Err = ();
+
+    fn from_str(permission: &str) -> Result<Self, Self::Err> {
+        match permission {
+            "manage_bookings" => Ok(Permission::ManageBookings),
+            "manage_rooms" => Ok(Permission::ManageRooms),
+            "manage_users" => Ok(Permission::ManageUsers),
+            _ => Err(()),
+        }
+    }
+}
+
+/// Why the roles of a user could not be replaced
+#[derive(Debug)]
+pub enum SetRolesError {
+    /// The roles with these names do not exist
+    UnknownRole(Vec<String>),
+    /// The user is the last super admin and would lose the role
+    LastSuperAdmin,
+    Database(diesel::result::Error),
+}
+
+impl From<diesel::result::Error> for SetRolesError {
+    fn from(e: diesel::result::Error) -> Self {
+        SetRolesError::Database(e)
+    }
+}
+
+#[derive(Queryable, Selectable, PartialEq, Debug)]
+#[diesel(table_name = crate::schema::role)]
+#[diesel(check_for_backend(diesel::sqlite::Sqlite))]
+pub struct Role {
+    pub id: Option<i32>,
+    pub name: String,
+}
+
+#[allow(clippy::module_name_repetitions)]
+#[derive(JsonSchema, Serialize, Deserialize, Debug)]
+pub struct RoleResponse {
+    pub name: String,
+    pub permissions: Vec<Permission>,
+}
+
+impl Role {
+    /// All roles with their permissions
+    #[must_use]
+    pub fn all(conn: &mut SqliteConnection) -> Vec<RoleResponse> {
+        let roles: Vec<Role> = role::table
+            .order(role::id.asc())
+            .load(conn)
+            .unwrap_or_default();
+        let permissions: Vec<(i32, String)> = role_permission::table
+            .select((role_permission::fk_role_id, role_permission::permission))
+            .load(conn)
+            .unwrap_or_default();
+
+        roles
+            .into_iter()
+            .map(|r| RoleResponse {
+                permissions: permissions
+                    .iter()
+                    .filter(|(id, _)| Some(*id) == r.id)
+                    .filter_map(|(_, p)| p.parse().ok())
+                    .collect(),
+                name: r.name,
+            })
+            .collect()
+    }
+
+    /// Names of the roles of a user
+    #[must_use]
+    pub fn names_by_user_id(conn: &mut SqliteConnection, id: i32) -> Vec<String> {
+        user_role::table
+            .inner_join(role::table)
+            .filter(user_role::fk_user_id.eq(id))
+            .select(role::name)
+            .load(conn)
+            .unwrap_or_default()
+    }
+
+    /// Permissions granted to a user by any of their roles
+    #[must_use]
+    pub fn permissions_by_user_id(conn: &mut SqliteConnection, id: i32) -> Vec<Permission> {
+        user_role::table
+            .inner_join(role::table.inner_join(role_permission::table))
+            .filter(user_role::fk_user_id.eq(id))
+            .select(role_permission::permission)
+            .distinct()
+            .load::<String>(conn)
+            .unwrap_or_default()
+            .iter()
+            .filter_map(|p| p.parse().ok())
+            .collect()
+    }
+
+    /// Replaces the roles of a user and updates `is_admin` to match [`SUPER_ADMIN`]
+    ///
+    /// # Errors
+    ///
+    /// Fails if one of the roles does not exist, the last super admin would lose the role
+    /// or the roles could not be stored
+    pub fn set_for_user(
+        conn: &mut SqliteConnection,
+        id: i32,
+        names: &[String],
+    ) -> Result<(), SetRolesError> {
+        conn.transaction(|conn| {
+            let roles: Vec<Role> = role::table.filter(role::name.eq_any(names)).load(conn)?;
+            let unknown: Vec<String> = names
+                .iter()
+                .filter(|n| !roles.iter().any(|r| &r.name == *n))
+                .cloned()
+                .collect();
+            if !unknown.is_empty() {
+                return Err(SetRolesError::UnknownRole(unknown));
+            }
+
+            let is_admin = roles.iter().any(|r| r.name == SUPER_ADMIN);
+            let was_admin = user::table
+                .filter(user::id.eq(id))
+                .select(user::is_admin)
+                .first::<Option<bool>>(conn)?
+                .unwrap_or_default();
+            if was_admin && !is_admin && User::admin_count(conn) <= 1 {
+                return Err(SetRolesError::LastSuperAdmin);
+            }
+
+            diesel::delete(user_role::table.filter(user_role::fk_user_id.eq(id))).execute(conn)?;
+            let rows: Vec<_> = roles
+                .iter()
+                .filter_map(|r| r.id)
+                .map(|role_id| {
+                    (
+                        user_role::fk_user_id.eq(id),
+                        user_role::fk_role_id.eq(role_id),
+                    )
+                })
+                .collect();
+            if !rows.is_empty() {
+                diesel::insert_into(user_role::table)
+                    .values(&rows)
+                    .execute(conn)?;
+            }
+
+            diesel::update(user::table.filter(user::id.eq(id)))
+                .set(user::is_admin.eq(is_admin))
+                .execute(conn)?;
+            Ok(())
+        })
+    }
+
+    /// Grants or removes [`SUPER_ADMIN`] so the role matches the `is_admin` flag
+    ///
+    /// # Errors
+    ///
+    /// Fails if the role could not be granted or removed
+    pub fn sync_admin(conn: &mut SqliteConnection, id: i32, is_admin: bool) -> QueryResult<()> {
+        let super_admin = role::table
+            .filter(role::name.eq(SUPER_ADMIN))
+            .select(role::id)
+            .first::<Option<i32>>(conn)?
+            .unwrap_or_default();
+
+        if is_admin {
+            diesel::insert_or_ignore_into(user_role::table)
+                .values((
+                    user_role::fk_user_id.eq(id),
+                    user_role::fk_role_id.eq(super_admin),
+                ))
+                .execute(conn)?;
+        } else {
+            diesel::delete(
+                user_role::table
+                    .filter(user_role::fk_user_id.eq(id))
+                    .filter(user_role::fk_role_id.eq(super_admin)),
+            )
+            .execute(conn)?;
+        }
+        Ok(())
+    }
+
+    /// Gives a new user the [`MEMBER`] role and [`SUPER_ADMIN`] if they are an admin
+    ///
+    /// # Errors
+    ///
+    /// Fails if a role could not be granted
+    pub fn assign_defaults(
+        conn: &mut SqliteConnection,
+        id: i32,
+        is_admin: bool,
+    ) -> QueryResult<()> {
+        let member = role::table
+            .filter(role::name.eq(MEMBER))
+            .select(role::id)
+            .first::<Option<i32>>(conn)?
+            .unwrap_or_default();
+
+        diesel::insert_or_ignore_into(user_role::table)
+            .values((
+                user_role::fk_user_id.eq(id),
+                user_role::fk_role_id.eq(member),
+            ))
+            .execute(conn)?;
+
+        Role::sync_admin(conn, id, is_admin)
+    }
+}
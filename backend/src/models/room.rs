@@ -1,32 +1,194 @@
+use std::{collections::HashMap, str::FromStr};
+
 use diesel::{prelude::*, ExpressionMethods, QueryDsl, RunQueryDsl};
+use rocket::FromFormField;
 use rocket_okapi::{okapi::schemars, JsonSchema};
 use serde::{Deserialize, Serialize};
+use validator::Validate;
 
-use crate::{schema::room, util::conn};
+use crate::schema::{booking, booking_series, room, room_amenity};
 
 use super::booking::Booking;
 
+#[derive(
+    FromFormField, Clone, Copy, JsonSchema, PartialEq, Eq, Hash, Serialize, Deserialize, Debug,
+)]
+#[serde(rename_all = "snake_case")]
+pub enum Amenity {
+    Projector,
+    Whiteboard,
+    #[field(value = "video_conferencing")]
+    VideoConferencing,
+    #[field(value = "standing_desks")]
+    StandingDesks,
+}
+
+impl Amenity {
+    #[must_use]
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Amenity::Projector => "projector",
+            Amenity::Whiteboard => "whiteboard",
+            Amenity::VideoConferencing => "video_conferencing",
+            Amenity::StandingDesks => "standing_desks",
+        }
+    }
+}
+
+impl FromStr for Amenity {
+    type Err = ();
+
+    fn from_str(amenity: &str) -> Result<Self, Self::Err> {
+        match amenity {
+            "projector" => Ok(Amenity::Projector),
+            "whiteboard" => Ok(Amenity::Whiteboard),
+            "video_conferencing" => Ok(Amenity::VideoConferencing),
+            "standing_desks" => Ok(Amenity::StandingDesks),
+            _ => Err(()),
+        }
+    }
+}
+
+#[allow(clippy::module_name_repetitions)]
+#[derive(Queryable, Selectable, Insertable, PartialEq, Debug)]
+#[diesel(table_name = crate::schema::room_amenity)]
+#[diesel(check_for_backend(diesel::sqlite::Sqlite))]
+pub struct RoomAmenity {
+    pub fk_room_id: i32,
+    pub amenity: String,
+}
+
+impl RoomAmenity {
+    /// Amenities of all rooms, grouped by room id
+    #[must_use]
+    pub fn all(conn: &mut SqliteConnection) -> HashMap<i32, Vec<Amenity>> {
+        let rows: Vec<RoomAmenity> = room_amenity::table.load(conn).unwrap_or_default();
+
+        let mut amenities: HashMap<i32, Vec<Amenity>> = HashMap::new();
+        for r in rows {
+            if let Ok(a) = r.amenity.parse() {
+                amenities.entry(r.fk_room_id).or_default().push(a);
+            }
+        }
+        amenities
+    }
+
+    #[must_use]
+    pub fn by_room_id(conn: &mut SqliteConnection, id: i32) -> Vec<Amenity> {
+        room_amenity::table
+            .filter(room_amenity::fk_room_id.eq(id))
+            .select(room_amenity::amenity)
+            .load::<String>(conn)
+            .unwrap_or_default()
+            .iter()
+            .filter_map(|a| a.parse().ok())
+            .collect()
+    }
+
+    /// Replaces the amenities of a room
+    fn set(conn: &mut SqliteConnection, id: i32, amenities: &[Amenity]) -> QueryResult<()> {
+        diesel::delete(room_amenity::table.filter(room_amenity::fk_room_id.eq(id)))
+            .execute(conn)?;
+
+        let mut rows: Vec<RoomAmenity> = Vec::new();
+        for a in amenities {
+            let row = RoomAmenity {
+                fk_room_id: id,
+                amenity: a.as_str().to_string(),
+            };
+            if !rows.contains(&row) {
+                rows.push(row);
+            }
+        }
+
+        if !rows.is_empty() {
+            diesel::insert_into(room_amenity::table)
+                .values(&rows)
+                .execute(conn)?;
+        }
+        Ok(())
+    }
+}
+
+/// Body of `POST /rooms` and `PUT /rooms/<id>`
+#[allow(clippy::module_name_repetitions)]
+#[derive(JsonSchema, Serialize, Deserialize, Validate, Debug)]
+pub struct RoomRequest {
+    #[validate(length(min = 1, max = 100, message = "must be between 1 and 100 characters"))]
+    pub name: String,
+    /// Maximum number of attendees, unlimited if not set
+    #[validate(range(min = 1, message = "must be at least 1"))]
+    pub capacity: Option<i32>,
+    pub floor: Option<i32>,
+    #[validate(length(max = 200, message = "must be at most 200 characters"))]
+    pub location: Option<String>,
+    #[serde(default)]
+    pub amenities: Vec<Amenity>,
+}
+
 #[allow(clippy::module_name_repetitions)]
 #[derive(JsonSchema, PartialEq, Serialize, Deserialize, Debug)]
 pub struct RoomResponse {
     pub room: Room,
+    pub amenities: Vec<Amenity>,
     pub bookings: Vec<Booking>,
 }
 
 impl RoomResponse {
     #[must_use]
-    pub fn new() -> Vec<Self> {
-        let rooms = Room::all();
+    pub fn new(conn: &mut SqliteConnection) -> Vec<Self> {
+        RoomResponse::filtered(conn, None, &[])
+    }
+
+    /// Rooms with at least `min_capacity` seats and all of the given amenities
+    ///
+    /// Rooms without a capacity have unlimited seats and always match `min_capacity`
+    #[must_use]
+    pub fn filtered(
+        conn: &mut SqliteConnection,
+        min_capacity: Option<i32>,
+        amenities: &[Amenity],
+    ) -> Vec<Self> {
+        let rooms = Room::all(conn);
+        let mut room_amenities = RoomAmenity::all(conn);
+        let mut room_bookings = Booking::all_by_room(conn);
         let mut room_responses = Vec::new();
         for r in rooms {
-            let bookings = Booking::by_room_id(r.id.unwrap_or_default());
-            room_responses.push(RoomResponse { room: r, bookings });
+            if min_capacity.is_some_and(|min| r.capacity.is_some_and(|c| c < min)) {
+                continue;
+            }
+
+            let id = r.id.unwrap_or_default();
+            let ra = room_amenities.remove(&id).unwrap_or_default();
+            if !amenities.iter().all(|a| ra.contains(a)) {
+                continue;
+            }
+
+            let bookings = room_bookings.remove(&id).unwrap_or_default();
+            room_responses.push(RoomResponse {
+                room: r,
+                amenities: ra,
+                bookings,
+            });
         }
         room_responses
     }
+
+    #[must_use]
+    pub fn by_id(conn: &mut SqliteConnection, id: i32) -> Option<Self> {
+        let room = Room::by_id(conn, id)?;
+        let amenities = RoomAmenity::by_room_id(conn, id);
+        let bookings = Booking::by_room_id(conn, id);
+        Some(RoomResponse {
+            room,
+            amenities,
+            bookings,
+        })
+    }
 }
 
 #[derive(
+    Clone,
     JsonSchema,
     Queryable,
     PartialEq,
@@ -42,42 +204,81 @@ impl RoomResponse {
 pub struct Room {
     pub id: Option<i32>,
     pub name: String,
+    /// Maximum number of attendees, unlimited if not set
+    pub capacity: Option<i32>,
+    pub floor: Option<i32>,
+    pub location: Option<String>,
 }
 
 impl Room {
     #[must_use]
-    pub fn create(name: &str) -> Option<Room> {
+    pub fn create(conn: &mut SqliteConnection, post_room: &RoomRequest) -> Option<Room> {
         let new_room = Room {
             id: None,
-            name: name.to_string(),
+            name: post_room.name.clone(),
+            capacity: post_room.capacity,
+            floor: post_room.floor,
+            location: post_room.location.clone(),
         };
 
-        diesel::insert_into(room::table)
-            .values(&new_room)
-            .execute(&mut conn())
-            .ok()?;
+        conn.transaction::<_, diesel::result::Error, _>(|conn| {
+            let room = diesel::insert_into(room::table)
+                .values(&new_room)
+                .returning(Room::as_returning())
+                .get_result(conn)?;
 
-        room::table
-            .filter(room::name.eq(&name))
-            .order(room::id.desc())
-            .first(&mut conn())
-            .ok()
+            RoomAmenity::set(conn, room.id.unwrap_or_default(), &post_room.amenities)?;
+            Ok(room)
+        })
+        .ok()
     }
 
+    /// Whether `attendees` people fit into the room, rooms without capacity fit everyone
     #[must_use]
-    pub fn all() -> Vec<Room> {
-        room::table.load(&mut conn()).unwrap_or_default()
+    pub fn fits(&self, attendees: i32) -> bool {
+        attendees > 0 && self.capacity.is_none_or(|c| attendees <= c)
     }
 
     #[must_use]
-    pub fn by_id(id: i32) -> Option<Room> {
-        room::table.filter(room::id.eq(id)).first(&mut conn()).ok()
+    pub fn all(conn: &mut SqliteConnection) -> Vec<Room> {
+        room::table.load(conn).unwrap_or_default()
+    }
+
+    #[must_use]
+    pub fn by_id(conn: &mut SqliteConnection, id: i32) -> Option<Room> {
+        room::table.filter(room::id.eq(id)).first(conn).ok()
+    }
+
+    #[must_use]
+    pub fn update(conn: &mut SqliteConnection, id: i32, put_room: &RoomRequest) -> Option<Room> {
+        conn.transaction::<_, diesel::result::Error, _>(|conn| {
+            diesel::update(room::table.filter(room::id.eq(id)))
+                .set((
+                    room::name.eq(&put_room.name),
+                    room::capacity.eq(put_room.capacity),
+                    room::floor.eq(put_room.floor),
+                    room::location.eq(&put_room.location),
+                ))
+                .execute(conn)?;
+
+            RoomAmenity::set(conn, id, &put_room.amenities)?;
+
+            room::table.filter(room::id.eq(id)).first(conn)
+        })
+        .ok()
     }
 
+    /// Deletes the room together with all its bookings, booking series and amenities
     #[must_use]
-    pub fn delete(id: i32) -> bool {
-        diesel::delete(room::table.filter(room::id.eq(id)))
-            .execute(&mut conn())
-            .is_ok()
+    pub fn delete(conn: &mut SqliteConnection, id: i32) -> bool {
+        conn.transaction::<_, diesel::result::Error, _>(|conn| {
+            diesel::delete(booking::table.filter(booking::fk_room_id.eq(id))).execute(conn)?;
+            diesel::delete(booking_series::table.filter(booking_series::fk_room_id.eq(id)))
+                .execute(conn)?;
+            RoomAmenity::set(conn, id, &[])?;
+            diesel::delete(room::table.filter(room::id.eq(id))).execute(conn)?;
+            Ok(())
+        })
+        .is_ok()
     }
 }
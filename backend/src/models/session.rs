@@ -0,0 +1,167 @@
+use chrono::{NaiveDateTime, Utc};
+use diesel::{prelude::*, ExpressionMethods, QueryDsl, RunQueryDsl};
+
+use crate::{
+    auth::{generate_token, hash_token, refresh_token_lifetime},
+    schema::{refresh_token, session},
+};
+
+/// A login of a user on one device, all refresh tokens rotated from the same
+/// login belong to the same session
+#[derive(Queryable, Selectable, Insertable, PartialEq, Debug)]
+#[diesel(table_name = crate::schema::session)]
+#[diesel(check_for_backend(diesel::sqlite::Sqlite))]
+pub struct Session {
+    pub id: Option<i32>,
+    pub fk_user_id: i32,
+    pub created_at: Option<NaiveDateTime>,
+    pub revoked_at: Option<NaiveDateTime>,
+}
+
+impl Session {
+    /// Starts a new session and returns it together with its first refresh token
+    #[must_use]
+    pub fn start(conn: &mut SqliteConnection, fk_user_id: i32) -> Option<(Session, String)> {
+        conn.transaction::<_, diesel::result::Error, _>(|conn| {
+            let session = diesel::insert_into(session::table)
+                .values(&Session {
+                    id: None,
+                    fk_user_id,
+                    created_at: None,
+                    revoked_at: None,
+                })
+                .returning(Session::as_returning())
+                .get_result(conn)?;
+
+            let token = RefreshToken::issue(conn, session.id.unwrap_or_default())?;
+            Ok((session, token))
+        })
+        .ok()
+    }
+
+    #[must_use]
+    pub fn by_id(conn: &mut SqliteConnection, id: i32) -> Option<Session> {
+        session::table.filter(session::id.eq(id)).first(conn).ok()
+    }
+
+    /// Whether the session exists, belongs to the user and has not been revoked
+    #[must_use]
+    pub fn is_active(conn: &mut SqliteConnection, id: i32, fk_user_id: i32) -> bool {
+        Session::by_id(conn, id)
+            .is_some_and(|s| s.fk_user_id == fk_user_id && s.revoked_at.is_none())
+    }
+
+    #[must_use]
+    pub fn revoke(conn: &mut SqliteConnection, id: i32) -> bool {
+        diesel::update(session::table.filter(session::id.eq(id)))
+            .filter(session::revoked_at.is_null())
+            .set(session::revoked_at.eq(Utc::now().naive_utc()))
+            .execute(conn)
+            .is_ok()
+    }
+
+    /// Revokes all sessions of a user, e.g. to log out on every device
+    #[must_use]
+    pub fn revoke_all(conn: &mut SqliteConnection, fk_user_id: i32) -> bool {
+        diesel::update(session::table.filter(session::fk_user_id.eq(fk_user_id)))
+            .filter(session::revoked_at.is_null())
+            .set(session::revoked_at.eq(Utc::now().naive_utc()))
+            .execute(conn)
+            .is_ok()
+    }
+}
+
+/// Outcome of exchanging a refresh token
+#[derive(Debug)]
+pub enum Refresh {
+    /// The token was valid, contains the session and the new refresh token
+    Rotated(Session, String),
+    /// Unknown, expired or revoked token
+    Invalid,
+    /// The token had already been exchanged, the whole session has been revoked
+    Reused,
+}
+
+#[allow(clippy::module_name_repetitions)]
+#[derive(Queryable, Selectable, Insertable, PartialEq, Debug)]
+#[diesel(table_name = crate::schema::refresh_token)]
+#[diesel(check_for_backend(diesel::sqlite::Sqlite))]
+pub struct RefreshToken {
+    pub id: Option<i32>,
+    /// SHA-256 of the token, the token itself is never stored
+    pub token_hash: String,
+    pub fk_session_id: i32,
+    pub expires_at: NaiveDateTime,
+    /// Set once the token has been exchanged for a new one
+    pub used_at: Option<NaiveDateTime>,
+    pub created_at: Option<NaiveDateTime>,
+}
+
+impl RefreshToken {
+    /// Creates a new refresh token for the session and returns it in plain text
+    ///
+    /// # Errors
+    ///
+    /// Fails if the token could not be stored
+    pub fn issue(conn: &mut SqliteConnection, fk_session_id: i32) -> QueryResult<String> {
+        let token = generate_token();
+
+        diesel::insert_into(refresh_token::table)
+            .values(&RefreshToken {
+                id: None,
+                token_hash: hash_token(&token),
+                fk_session_id,
+                expires_at: (Utc::now() + refresh_token_lifetime()).naive_utc(),
+                used_at: None,
+                created_at: None,
+            })
+            .execute(conn)?;
+
+        Ok(token)
+    }
+
+    /// Exchanges a refresh token for a new one of the same session
+    ///
+    /// Replaying a token that has already been exchanged revokes the session,
+    /// so a stolen token can be used at most once before the family is invalidated
+    #[must_use]
+    pub fn rotate(conn: &mut SqliteConnection, token: &str) -> Option<Refresh> {
+        conn.immediate_transaction::<_, diesel::result::Error, _>(|conn| {
+            let Some(refresh_token) = refresh_token::table
+                .filter(refresh_token::token_hash.eq(hash_token(token)))
+                .first::<RefreshToken>(conn)
+                .optional()?
+            else {
+                return Ok(Refresh::Invalid);
+            };
+
+            let Some(session) = Session::by_id(conn, refresh_token.fk_session_id) else {
+                return Ok(Refresh::Invalid);
+            };
+            if session.revoked_at.is_some() {
+                return Ok(Refresh::Invalid);
+            }
+
+            if refresh_token.used_at.is_some() {
+                warn!("Refresh token reused, revoking session {:?}", session.id);
+                diesel::update(session::table.filter(session::id.eq(refresh_token.fk_session_id)))
+                    .set(session::revoked_at.eq(Utc::now().naive_utc()))
+                    .execute(conn)?;
+                return Ok(Refresh::Reused);
+            }
+
+            let now = Utc::now().naive_utc();
+            if refresh_token.expires_at < now {
+                return Ok(Refresh::Invalid);
+            }
+
+            diesel::update(refresh_token::table.filter(refresh_token::id.eq(refresh_token.id)))
+                .set(refresh_token::used_at.eq(now))
+                .execute(conn)?;
+
+            let token = RefreshToken::issue(conn, refresh_token.fk_session_id)?;
+            Ok(Refresh::Rotated(session, token))
+        })
+        .ok()
+    }
+}
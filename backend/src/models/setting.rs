@@ -0,0 +1,67 @@
+use diesel::{prelude::*, ExpressionMethods, QueryDsl, RunQueryDsl};
+use rocket_okapi::okapi::schemars::{self, JsonSchema};
+use serde::{Deserialize, Serialize};
+
+use crate::{auth, schema::setting};
+
+const REQUIRE_ADMIN_2FA: &str = "require_admin_2fa";
+
+/// Policies admins change at runtime with `PUT /settings`
+#[derive(Clone, Copy, JsonSchema, Serialize, Deserialize, Debug, PartialEq, Eq)]
+#[serde(deny_unknown_fields)]
+pub struct Settings {
+    /// Users with a permission or role can only use it once they enabled two-factor
+    /// authentication, defaults to `REQUIRE_ADMIN_2FA` until changed
+    pub require_admin_2fa: bool,
+}
+
+impl Settings {
+    /// Stored settings, those an admin never changed fall back to the environment
+    #[must_use]
+    pub fn load(conn: &mut SqliteConnection) -> Settings {
+        let stored: Vec<(String, String)> = setting::table
+            .select((setting::key, setting::value))
+            .load(conn)
+            .unwrap_or_default();
+        let flag = |key: &str| {
+            stored
+                .iter()
+                .find(|(k, _)| k == key)
+                .map(|(_, v)| v == "true")
+        };
+
+        Settings {
+            require_admin_2fa: flag(REQUIRE_ADMIN_2FA)
+                .unwrap_or_else(auth::admin_two_factor_required),
+        }
+    }
+
+    /// # Errors
+    ///
+    /// Fails if the settings could not be stored
+    pub fn save(&self, conn: &mut SqliteConnection) -> QueryResult<()> {
+        diesel::replace_into(setting::table)
+            .values((
+                setting::key.eq(REQUIRE_ADMIN_2FA),
+                setting::value.eq(self.require_admin_2fa.to_string()),
+            ))
+            .execute(conn)?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::db::test_connection;
+
+    #[test]
+    fn saved_settings_are_loaded() {
+        let mut conn = test_connection();
+        for require_admin_2fa in [true, false] {
+            let settings = Settings { require_admin_2fa };
+            settings.save(&mut conn).unwrap();
+            assert_eq!(Settings::load(&mut conn), settings);
+        }
+    }
+}
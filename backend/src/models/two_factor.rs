@@ -0,0 +1,315 @@
+use chrono::{NaiveDateTime, Utc};
+use diesel::{prelude::*, ExpressionMethods, QueryDsl, RunQueryDsl};
+use totp_rs::{Algorithm, Secret, TOTP};
+
+use crate::{
+    auth::{generate_token, hash_token},
+    schema::{recovery_code, totp},
+};
+
+/// Issuer shown in authenticator apps
+pub const TOTP_ISSUER: &str = "CoworkingSpace";
+/// Seconds a code is valid for
+pub const TOTP_STEP: u64 = 30;
+/// Number of recovery codes generated at once
+pub const RECOVERY_CODES: usize = 10;
+
+/// TOTP secret of a user, two-factor authentication is enabled once the first code
+/// has been confirmed
+#[derive(Queryable, Selectable, Insertable, PartialEq, Debug)]
+#[diesel(table_name = crate::schema::totp)]
+#[diesel(check_for_backend(diesel::sqlite::Sqlite))]
+pub struct Totp {
+    pub fk_user_id: i32,
+    /// Base32 encoded secret
+    pub secret: String,
+    pub enabled_at: Option<NaiveDateTime>,
+    /// Time step of the last accepted code, every code is only accepted once
+    pub last_used_step: Option<i64>,
+    pub created_at: Option<NaiveDateTime>,
+}
+
+impl Totp {
+    #[must_use]
+    pub fn by_user_id(conn: &mut SqliteConnection, id: i32) -> Option<Totp> {
+        totp::table.filter(totp::fk_user_id.eq(id)).first(conn).ok()
+    }
+
+    /// Whether the user has confirmed a TOTP secret
+    #[must_use]
+    pub fn is_enabled(conn: &mut SqliteConnection, id: i32) -> bool {
+        Totp::by_user_id(conn, id).is_some_and(|t| t.enabled_at.is_some())
+    }
+
+    /// Starts enrollment with a new secret, replacing one that has not been confirmed
+    ///
+    /// # Errors
+    ///
+    /// Fails if the secret could not be stored
+    pub fn enroll(conn: &mut SqliteConnection, id: i32) -> QueryResult<Totp> {
+        let secret = Secret::generate_secret().to_encoded().to_string();
+
+        diesel::replace_into(totp::table)
+            .values(&Totp {
+                fk_user_id: id,
+                secret,
+                enabled_at: None,
+                last_used_step: None,
+                created_at: None,
+            })
+            .returning(Totp::as_returning())
+            .get_result(conn)
+    }
+
+    fn totp(&self, account: &str) -> Option<TOTP> {
+        let secret = Secret::Encoded(self.secret.clone()).to_bytes().ok()?;
+        TOTP::new(
+            Algorithm::SHA1,
+            6,
+            0,
+            TOTP_STEP,
+            secret,
+            Some(TOTP_ISSUER.to_string()),
+            account.to_string(),
+        )
+        .ok()
+    }
+
+    /// `otpauth://` URI for authenticator apps, usually shown as QR code
+    #[must_use]
+    pub fn uri(&self, account: &str) -> Option<String> {
+        Some(self.totp(account)?.get_url())
+    }
+
+    /// Checks a code of the current, previous or next time step and remembers its step
+    ///
+    /// A code is rejected if the same or a later step has already been used, so a code
+    /// seen by someone else can not be replayed
+    ///
+    /// # Errors
+    ///
+    /// Fails if the used step could not be stored
+    pub fn verify(&self, conn: &mut SqliteConnection, code: &str) -> QueryResult<bool> {
+        let Some(totp) = self.totp("") else {
+            return Ok(false);
+        };
+        let code: String = code.chars().filter(|c| !c.is_whitespace()).collect();
+        let now = u64::try_from(Utc::now().timestamp()).unwrap_or_default() / TOTP_STEP;
+
+        let Some(step) = (now.saturating_sub(1)..=now + 1)
+            .filter(|step| totp.check(&code, step * TOTP_STEP))
+            .filter_map(|step| i64::try_from(step).ok())
+            .find(|step| self.last_used_step.is_none_or(|last| *step > last))
+        else {
+            return Ok(false);
+        };
+
+        // Compare and set, of two concurrent requests with the same code only one succeeds
+        let updated = diesel::update(
+            totp::table
+                .filter(totp::fk_user_id.eq(self.fk_user_id))
+                .filter(
+                    totp::last_used_step
+                        .is_null()
+                        .or(totp::last_used_step.lt(step)),
+                ),
+        )
+        .set(totp::last_used_step.eq(step))
+        .execute(conn)?;
+        Ok(updated == 1)
+    }
+
+    /// Enables two-factor authentication and returns the first recovery codes
+    ///
+    /// # Errors
+    ///
+    /// Fails if the user could not be updated
+    pub fn enable(conn: &mut SqliteConnection, id: i32) -> QueryResult<Vec<String>> {
+        conn.transaction(|conn| {
+            diesel::update(totp::table.filter(totp::fk_user_id.eq(id)))
+                .set(totp::enabled_at.eq(Utc::now().naive_utc()))
+                .execute(conn)?;
+            RecoveryCode::regenerate(conn, id)
+        })
+    }
+
+    /// Disables two-factor authentication and deletes the secret and recovery codes
+    ///
+    /// # Errors
+    ///
+    /// Fails if they could not be deleted
+    pub fn disable(conn: &mut SqliteConnection, id: i32) -> QueryResult<()> {
+        conn.transaction(|conn| {
+            diesel::delete(totp::table.filter(totp::fk_user_id.eq(id))).execute(conn)?;
+            diesel::delete(recovery_code::table.filter(recovery_code::fk_user_id.eq(id)))
+                .execute(conn)?;
+            Ok(())
+        })
+    }
+}
+
+/// One-time code to log in without the authenticator app
+#[derive(Queryable, Selectable, Insertable, PartialEq, Debug)]
+#[diesel(table_name = crate::schema::recovery_code)]
+#[diesel(check_for_backend(diesel::sqlite::Sqlite))]
+pub struct RecoveryCode {
+    pub id: Option<i32>,
+    pub fk_user_id: i32,
+    /// SHA-256 of the code without dashes, the code itself is never stored
+    pub code_hash: String,
+    pub used_at: Option<NaiveDateTime>,
+}
+
+impl RecoveryCode {
+    /// Replaces all recovery codes of the user and returns the new ones in plain text,
+    /// formatted as `xxxx-xxxx-xxxx-xxxx`
+    ///
+    /// # Errors
+    ///
+    /// Fails if the codes could not be stored
+    pub fn regenerate(conn: &mut SqliteConnection, id: i32) -> QueryResult<Vec<String>> {
+        let codes: Vec<String> = (0..RECOVERY_CODES)
+            .map(|_| {
+                let token = generate_token();
+                [&token[0..4], &token[4..8], &token[8..12], &token[12..16]].join("-")
+            })
+            .collect();
+        let rows: Vec<_> = codes
+            .iter()
+            .map(|code| RecoveryCode {
+                id: None,
+                fk_user_id: id,
+                code_hash: hash_recovery_code(code),
+                used_at: None,
+            })
+            .collect();
+
+        conn.transaction(|conn| {
+            diesel::delete(recovery_code::table.filter(recovery_code::fk_user_id.eq(id)))
+                .execute(conn)?;
+            diesel::insert_into(recovery_code::table)
+                .values(&rows)
+                .execute(conn)
+        })?;
+
+        Ok(codes)
+    }
+
+    /// Marks an unused recovery code of the user as used, `false` if there is none
+    ///
+    /// # Errors
+    ///
+    /// Fails if the code could not be updated
+    pub fn consume(conn: &mut SqliteConnection, id: i32, code: &str) -> QueryResult<bool> {
+        let updated = diesel::update(
+            recovery_code::table
+                .filter(recovery_code::fk_user_id.eq(id))
+                .filter(recovery_code::code_hash.eq(hash_recovery_code(code)))
+                .filter(recovery_code::used_at.is_null()),
+        )
+        .set(recovery_code::used_at.eq(Utc::now().naive_utc()))
+        .execute(conn)?;
+        Ok(updated > 0)
+    }
+
+    /// Number of recovery codes the user has not used yet
+    #[must_use]
+    pub fn remaining(conn: &mut SqliteConnection, id: i32) -> i64 {
+        recovery_code::table
+            .filter(recovery_code::fk_user_id.eq(id))
+            .filter(recovery_code::used_at.is_null())
+            .count()
+            .get_result(conn)
+            .unwrap_or_default()
+    }
+}
+
+/// Codes are accepted with or without dashes and in any case
+fn hash_recovery_code(code: &str) -> String {
+    let normalized: String = code
+        .chars()
+        .filter(char::is_ascii_alphanumeric)
+        .map(|c| c.to_ascii_lowercase())
+        .collect();
+    hash_token(&normalized)
+}
+
+/// Accepts a current TOTP code or an unused recovery code of the user
+///
+/// # Errors
+///
+/// Fails if the used code could not be stored
+pub fn verify_code(conn: &mut SqliteConnection, id: i32, code: &str) -> QueryResult<bool> {
+    let Some(totp) = Totp::by_user_id(conn, id).filter(|t| t.enabled_at.is_some()) else {
+        return Ok(false);
+    };
+    if totp.verify(conn, code)? {
+        return Ok(true);
+    }
+    RecoveryCode::consume(conn, id, code)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::db::test_connection;
+
+    fn enrolled(conn: &mut SqliteConnection) -> Totp {
+        Totp::enroll(conn, 1).unwrap()
+    }
+
+    fn code(totp: &Totp, step: u64) -> String {
+        totp.totp("").unwrap().generate(step * TOTP_STEP)
+    }
+
+    fn current_step() -> u64 {
+        u64::try_from(Utc::now().timestamp()).unwrap() / TOTP_STEP
+    }
+
+    #[test]
+    fn current_code_is_accepted_once() {
+        let mut conn = test_connection();
+        let totp = enrolled(&mut conn);
+        let code = code(&totp, current_step());
+
+        assert!(totp.verify(&mut conn, &code).unwrap());
+        let reloaded = Totp::by_user_id(&mut conn, 1).unwrap();
+        assert!(!reloaded.verify(&mut conn, &code).unwrap());
+    }
+
+    #[test]
+    fn older_step_is_rejected_after_a_newer_one() {
+        let mut conn = test_connection();
+        let totp = enrolled(&mut conn);
+        let now = current_step();
+
+        assert!(totp.verify(&mut conn, &code(&totp, now)).unwrap());
+        let reloaded = Totp::by_user_id(&mut conn, 1).unwrap();
+        assert!(!reloaded.verify(&mut conn, &code(&totp, now - 1)).unwrap());
+    }
+
+    #[test]
+    fn wrong_and_expired_codes_are_rejected() {
+        let mut conn = test_connection();
+        let totp = enrolled(&mut conn);
+        let now = current_step();
+
+        assert!(!totp.verify(&mut conn, "000000x").unwrap());
+        assert!(!totp.verify(&mut conn, &code(&totp, now - 10)).unwrap());
+        assert!(Totp::by_user_id(&mut conn, 1)
+            .unwrap()
+            .last_used_step
+            .is_none());
+    }
+
+    #[test]
+    fn spaces_in_codes_are_ignored() {
+        let mut conn = test_connection();
+        let totp = enrolled(&mut conn);
+        let code = code(&totp, current_step());
+
+        assert!(totp
+            .verify(&mut conn, &format!("{} {}", &code[..3], &code[3..]))
+            .unwrap());
+    }
+}
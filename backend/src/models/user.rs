@@ -1,49 +1,72 @@
+use std::fmt;
+
+use chrono::{DateTime, NaiveDateTime, Utc};
 use diesel::{prelude::*, ExpressionMethods, QueryDsl, RunQueryDsl};
 use rocket_okapi::okapi::schemars;
 use rocket_okapi::okapi::schemars::JsonSchema;
 use serde::{Deserialize, Serialize};
+use validator::Validate;
 
 use crate::{
     auth::hash_password,
     schema::{
-        self,
+        self, api_key, recovery_code, session, totp,
         user::{self},
+        user_identity, user_preference, user_role, user_token,
     },
-    util::conn,
+    validation,
 };
 
+use super::role::{Permission, Role};
+
+/// Placeholder for secrets in `Debug` output
+pub const REDACTED: &str = "[redacted]";
+
 #[allow(clippy::module_name_repetitions)]
-#[derive(JsonSchema, Serialize, Deserialize, Debug, AsChangeset)]
+#[derive(JsonSchema, Serialize, Deserialize, Validate, AsChangeset)]
 #[diesel(table_name = crate::schema::user)]
 pub struct PostUser {
     pub is_admin: bool,
+    #[validate(length(min = 1, max = 100, message = "must be between 1 and 100 characters"))]
     pub first_name: String,
+    #[validate(length(min = 1, max = 100, message = "must be between 1 and 100 characters"))]
     pub last_name: String,
+    #[validate(email(message = "must be a valid email address"))]
     pub email: String,
+    /// At least 8 characters with a letter and a digit
+    #[validate(custom(function = "validation::password"))]
     pub password: String,
 }
 
+impl fmt::Debug for PostUser {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("PostUser")
+            .field("is_admin", &self.is_admin)
+            .field("first_name", &self.first_name)
+            .field("last_name", &self.last_name)
+            .field("email", &self.email)
+            .field("password", &REDACTED)
+            .finish()
+    }
+}
+
 #[allow(clippy::module_name_repetitions)]
-#[derive(JsonSchema, Serialize, Deserialize, Debug, AsChangeset)]
+#[derive(JsonSchema, Serialize, Deserialize, Validate, Debug, AsChangeset)]
 #[diesel(table_name = crate::schema::user)]
 pub struct PutUser {
     pub is_admin: bool,
+    #[validate(length(min = 1, max = 100, message = "must be between 1 and 100 characters"))]
     pub first_name: String,
+    #[validate(length(min = 1, max = 100, message = "must be between 1 and 100 characters"))]
     pub last_name: String,
+    #[validate(email(message = "must be a valid email address"))]
     pub email: String,
 }
 
-#[derive(
-    Queryable,
-    PartialEq,
-    Selectable,
-    Insertable,
-    Serialize,
-    Deserialize,
-    Debug,
-    AsChangeset,
-    JsonSchema,
-)]
+/// User as stored in the database, including the password hash
+///
+/// Never serialized, use [`PublicUser`] in responses
+#[derive(Clone, Queryable, PartialEq, Selectable, Insertable, AsChangeset)]
 #[diesel(table_name = crate::schema::user)]
 #[diesel(check_for_backend(diesel::sqlite::Sqlite))]
 pub struct User {
@@ -53,35 +76,90 @@ pub struct User {
     pub last_name: String,
     pub email: String,
     pub password: String,
-    pub created_at: Option<String>,
+    pub created_at: Option<NaiveDateTime>,
+    /// `None` until the user followed the link of the verification mail
+    pub email_verified_at: Option<NaiveDateTime>,
+}
+
+impl fmt::Debug for User {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("User")
+            .field("id", &self.id)
+            .field("is_admin", &self.is_admin)
+            .field("first_name", &self.first_name)
+            .field("last_name", &self.last_name)
+            .field("email", &self.email)
+            .field("password", &REDACTED)
+            .field("created_at", &self.created_at)
+            .field("email_verified_at", &self.email_verified_at)
+            .finish()
+    }
+}
+
+/// User without the password hash, safe to return to clients
+#[allow(clippy::module_name_repetitions)]
+#[derive(JsonSchema, PartialEq, Serialize, Deserialize, Debug)]
+pub struct PublicUser {
+    pub id: Option<i32>,
+    pub is_admin: Option<bool>,
+    pub first_name: String,
+    pub last_name: String,
+    pub email: String,
+    #[serde(with = "crate::util::utc_timestamp")]
+    #[schemars(with = "Option<DateTime<Utc>>")]
+    pub created_at: Option<NaiveDateTime>,
+    /// `null` until the email address has been verified
+    #[serde(with = "crate::util::utc_timestamp")]
+    #[schemars(with = "Option<DateTime<Utc>>")]
+    pub email_verified_at: Option<NaiveDateTime>,
+}
+
+impl From<User> for PublicUser {
+    fn from(user: User) -> Self {
+        PublicUser {
+            id: user.id,
+            is_admin: user.is_admin,
+            first_name: user.first_name,
+            last_name: user.last_name,
+            email: user.email,
+            created_at: user.created_at,
+            email_verified_at: user.email_verified_at,
+        }
+    }
 }
 
 #[allow(clippy::missing_errors_doc, clippy::missing_panics_doc)]
 impl User {
     #[must_use]
     pub fn create_hash(
+        conn: &mut SqliteConnection,
         is_admin: bool,
         first_name: &str,
         last_name: &str,
         email: &str,
         password: &str,
+        email_verified: bool,
     ) -> Option<User> {
         User::create(
+            conn,
             is_admin,
             first_name,
             last_name,
             email,
             &hash_password(password),
+            email_verified,
         )
     }
 
     #[must_use]
     pub fn create(
+        conn: &mut SqliteConnection,
         is_admin: bool,
         first_name: &str,
         last_name: &str,
         email: &str,
         password: &str,
+        email_verified: bool,
     ) -> Option<User> {
         let new_user = User {
             id: None,
@@ -91,62 +169,206 @@ impl User {
             email: email.to_string(),
             password: password.to_string(),
             created_at: None,
+            email_verified_at: email_verified.then(|| Utc::now().naive_utc()),
         };
 
-        diesel::insert_into(schema::user::table)
-            .values(&new_user)
-            .execute(&mut conn())
-            .ok()?;
+        conn.transaction::<_, diesel::result::Error, _>(|conn| {
+            let user = diesel::insert_into(schema::user::table)
+                .values(&new_user)
+                .returning(User::as_returning())
+                .get_result(conn)?;
 
-        crate::schema::user::table
-            .filter(user::email.eq(email))
-            .first(&mut conn())
-            .ok()
+            Role::assign_defaults(conn, user.id.unwrap_or_default(), is_admin)?;
+            Ok(user)
+        })
+        .ok()
     }
 
     #[must_use]
-    pub fn all() -> Vec<User> {
-        schema::user::table.load(&mut conn()).unwrap_or_default()
+    pub fn all(conn: &mut SqliteConnection) -> Vec<User> {
+        schema::user::table.load(conn).unwrap_or_default()
     }
 
     #[must_use]
-    pub fn by_id(id: i32) -> Option<User> {
-        schema::user::table
-            .filter(user::id.eq(id))
-            .first(&mut conn())
-            .ok()
+    pub fn by_id(conn: &mut SqliteConnection, id: i32) -> Option<User> {
+        schema::user::table.filter(user::id.eq(id)).first(conn).ok()
     }
 
     #[must_use]
-    pub fn by_email(email: &str) -> Option<User> {
+    pub fn by_email(conn: &mut SqliteConnection, email: &str) -> Option<User> {
         schema::user::table
             .filter(user::email.eq(email))
-            .first(&mut conn())
+            .first(conn)
             .ok()
     }
 
     #[must_use]
-    pub fn count() -> Option<i64> {
-        schema::user::table.count().get_result(&mut conn()).ok()
+    pub fn count(conn: &mut SqliteConnection) -> Option<i64> {
+        schema::user::table.count().get_result(conn).ok()
     }
 
     #[must_use]
-    pub fn update(id: i32, put_user: &PutUser) -> Option<User> {
-        diesel::update(schema::user::table.filter(user::id.eq(id)))
-            .set(put_user)
-            .execute(&mut conn())
-            .ok()?;
+    pub fn update(conn: &mut SqliteConnection, id: i32, put_user: &PutUser) -> Option<User> {
+        conn.transaction::<_, diesel::result::Error, _>(|conn| {
+            diesel::update(schema::user::table.filter(user::id.eq(id)))
+                .set(put_user)
+                .execute(conn)?;
+
+            Role::sync_admin(conn, id, put_user.is_admin)?;
+            schema::user::table.filter(user::id.eq(id)).first(conn)
+        })
+        .ok()
+    }
+
+    /// Changes name and email address of the user, a new email address has to be
+    /// verified again
+    ///
+    /// # Errors
+    ///
+    /// Fails if the user could not be updated
+    pub fn update_profile(
+        conn: &mut SqliteConnection,
+        user: &User,
+        first_name: &str,
+        last_name: &str,
+        email: &str,
+    ) -> QueryResult<User> {
+        let email_verified_at = if email == user.email {
+            user.email_verified_at
+        } else {
+            None
+        };
 
+        diesel::update(schema::user::table.filter(user::id.eq(user.id)))
+            .set((
+                user::first_name.eq(first_name),
+                user::last_name.eq(last_name),
+                user::email.eq(email),
+                user::email_verified_at.eq(email_verified_at),
+            ))
+            .returning(User::as_returning())
+            .get_result(conn)
+    }
+
+    /// Number of users with admin rights
+    #[must_use]
+    pub fn admin_count(conn: &mut SqliteConnection) -> i64 {
         schema::user::table
-            .filter(user::id.eq(id))
-            .first(&mut conn())
-            .ok()
+            .filter(user::is_admin.eq(true))
+            .count()
+            .get_result(conn)
+            .unwrap_or_default()
+    }
+
+    /// Hashes and stores a new password and revokes all sessions of the user except
+    /// `keep_session`, logging out everyone who may know the old password
+    pub fn change_password(
+        conn: &mut SqliteConnection,
+        id: i32,
+        password: &str,
+        keep_session: Option<i32>,
+    ) -> QueryResult<()> {
+        diesel::update(schema::user::table.filter(user::id.eq(id)))
+            .set(user::password.eq(hash_password(password)))
+            .execute(conn)?;
+
+        diesel::update(
+            session::table
+                .filter(session::fk_user_id.eq(id))
+                .filter(session::id.ne(keep_session.unwrap_or_default()))
+                .filter(session::revoked_at.is_null()),
+        )
+        .set(session::revoked_at.eq(Utc::now().naive_utc()))
+        .execute(conn)?;
+        Ok(())
+    }
+
+    /// Grants or revokes admin rights, e.g. from the groups of the identity provider.
+    /// The last admin keeps their rights, so nobody is left to manage the others
+    pub fn set_admin(conn: &mut SqliteConnection, id: i32, is_admin: bool) -> QueryResult<()> {
+        conn.immediate_transaction(|conn| {
+            let was_admin = User::by_id(conn, id).is_some_and(|u| u.is_admin == Some(true));
+            if was_admin && !is_admin && User::admin_count(conn) <= 1 {
+                warn!("User {id} stays admin, they are the last admin");
+                return Ok(());
+            }
+
+            diesel::update(schema::user::table.filter(user::id.eq(id)))
+                .set(user::is_admin.eq(is_admin))
+                .execute(conn)?;
+            Role::sync_admin(conn, id, is_admin)
+        })
+    }
+
+    /// Marks the email address of the user as verified
+    pub fn verify_email(conn: &mut SqliteConnection, id: i32) -> QueryResult<()> {
+        diesel::update(schema::user::table.filter(user::id.eq(id)))
+            .set(user::email_verified_at.eq(Utc::now().naive_utc()))
+            .execute(conn)?;
+        Ok(())
     }
 
+    /// Whether any role of the user grants the permission
     #[must_use]
-    pub fn delete(id: i32) -> bool {
-        diesel::delete(schema::user::table.filter(user::id.eq(id)))
-            .execute(&mut conn())
-            .is_ok()
+    pub fn has_permission(&self, conn: &mut SqliteConnection, permission: Permission) -> bool {
+        self.id
+            .is_some_and(|id| Role::permissions_by_user_id(conn, id).contains(&permission))
+    }
+
+    #[must_use]
+    pub fn delete(conn: &mut SqliteConnection, id: i32) -> bool {
+        conn.transaction::<_, diesel::result::Error, _>(|conn| {
+            diesel::delete(user_role::table.filter(user_role::fk_user_id.eq(id))).execute(conn)?;
+            diesel::delete(user_token::table.filter(user_token::fk_user_id.eq(id)))
+                .execute(conn)?;
+            diesel::delete(totp::table.filter(totp::fk_user_id.eq(id))).execute(conn)?;
+            diesel::delete(api_key::table.filter(api_key::fk_user_id.eq(id))).execute(conn)?;
+            diesel::delete(user_identity::table.filter(user_identity::fk_user_id.eq(id)))
+                .execute(conn)?;
+            diesel::delete(recovery_code::table.filter(recovery_code::fk_user_id.eq(id)))
+                .execute(conn)?;
+            diesel::delete(user_preference::table.filter(user_preference::fk_user_id.eq(id)))
+                .execute(conn)?;
+            diesel::delete(schema::user::table.filter(user::id.eq(id))).execute(conn)?;
+            Ok(())
+        })
+        .is_ok()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::db::test_connection;
+
+    fn admin(conn: &mut SqliteConnection, email: &str) -> i32 {
+        User::create_hash(conn, true, "Ada", "Admin", email, "adapass1234", true)
+            .and_then(|u| u.id)
+            .unwrap()
+    }
+
+    fn is_admin(conn: &mut SqliteConnection, id: i32) -> bool {
+        User::by_id(conn, id).unwrap().is_admin == Some(true)
+    }
+
+    #[test]
+    fn last_admin_keeps_admin_rights() {
+        let mut conn = test_connection();
+        let id = admin(&mut conn, "ada@example.com");
+
+        User::set_admin(&mut conn, id, false).unwrap();
+        assert!(is_admin(&mut conn, id));
+    }
+
+    #[test]
+    fn admin_is_demoted_while_another_admin_remains() {
+        let mut conn = test_connection();
+        let id = admin(&mut conn, "ada@example.com");
+        let other = admin(&mut conn, "bob@example.com");
+
+        User::set_admin(&mut conn, id, false).unwrap();
+        assert!(!is_admin(&mut conn, id));
+        User::set_admin(&mut conn, other, false).unwrap();
+        assert!(is_admin(&mut conn, other));
     }
 }
@@ -0,0 +1,147 @@
+use chrono::{Duration, NaiveDateTime, Utc};
+use diesel::{prelude::*, ExpressionMethods, QueryDsl, RunQueryDsl};
+
+use crate::{
+    auth::{generate_token, hash_token},
+    schema::user_token,
+};
+
+/// What a [`UserToken`] was issued for, a token is only accepted for its own purpose
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum TokenPurpose {
+    /// Sent by `POST /password/forgot`, allows to set a new password
+    PasswordReset,
+    /// Sent after registering, confirms the email address belongs to the user
+    EmailVerification,
+    /// Returned by `POST /login` instead of a session if two-factor authentication is
+    /// enabled, exchanged together with a code at `POST /login/2fa`
+    TwoFactorChallenge,
+}
+
+impl TokenPurpose {
+    #[must_use]
+    pub fn as_str(self) -> &'static str {
+        match self {
+            TokenPurpose::PasswordReset => "password_reset",
+            TokenPurpose::EmailVerification => "email_verification",
+            TokenPurpose::TwoFactorChallenge => "two_factor_challenge",
+        }
+    }
+}
+
+/// Single use token mailed to a user, e.g. to reset the password
+#[allow(clippy::module_name_repetitions)]
+#[derive(Queryable, Selectable, Insertable, PartialEq, Debug)]
+#[diesel(table_name = crate::schema::user_token)]
+#[diesel(check_for_backend(diesel::sqlite::Sqlite))]
+pub struct UserToken {
+    pub id: Option<i32>,
+    /// SHA-256 of the token, the token itself is never stored
+    pub token_hash: String,
+    pub purpose: String,
+    pub fk_user_id: i32,
+    pub expires_at: NaiveDateTime,
+    /// Set once the token has been used or a newer token of the same purpose was issued
+    pub used_at: Option<NaiveDateTime>,
+    pub created_at: Option<NaiveDateTime>,
+}
+
+impl UserToken {
+    /// Creates a token for the user and returns it in plain text
+    ///
+    /// Unused tokens of the same purpose are invalidated, only the latest mail works
+    ///
+    /// # Errors
+    ///
+    /// Fails if the token could not be stored
+    pub fn issue(
+        conn: &mut SqliteConnection,
+        fk_user_id: i32,
+        purpose: TokenPurpose,
+        lifetime: Duration,
+    ) -> QueryResult<String> {
+        let token = generate_token();
+        let now = Utc::now();
+
+        conn.transaction(|conn| {
+            diesel::update(
+                user_token::table
+                    .filter(user_token::fk_user_id.eq(fk_user_id))
+                    .filter(user_token::purpose.eq(purpose.as_str()))
+                    .filter(user_token::used_at.is_null()),
+            )
+            .set(user_token::used_at.eq(now.naive_utc()))
+            .execute(conn)?;
+
+            diesel::insert_into(user_token::table)
+                .values(&UserToken {
+                    id: None,
+                    token_hash: hash_token(&token),
+                    purpose: purpose.as_str().to_string(),
+                    fk_user_id,
+                    expires_at: (now + lifetime).naive_utc(),
+                    used_at: None,
+                    created_at: None,
+                })
+                .execute(conn)
+        })?;
+
+        Ok(token)
+    }
+
+    /// When the latest token of the purpose was issued to the user, e.g. to rate limit mails
+    #[must_use]
+    pub fn last_issued_at(
+        conn: &mut SqliteConnection,
+        fk_user_id: i32,
+        purpose: TokenPurpose,
+    ) -> Option<NaiveDateTime> {
+        user_token::table
+            .filter(user_token::fk_user_id.eq(fk_user_id))
+            .filter(user_token::purpose.eq(purpose.as_str()))
+            .select(diesel::dsl::max(user_token::created_at))
+            .first::<Option<NaiveDateTime>>(conn)
+            .ok()
+            .flatten()
+    }
+
+    /// Id of the user of a valid token without using it up
+    #[must_use]
+    pub fn user_id(conn: &mut SqliteConnection, token: &str, purpose: TokenPurpose) -> Option<i32> {
+        user_token::table
+            .filter(user_token::token_hash.eq(hash_token(token)))
+            .filter(user_token::purpose.eq(purpose.as_str()))
+            .filter(user_token::used_at.is_null())
+            .filter(user_token::expires_at.gt(Utc::now().naive_utc()))
+            .select(user_token::fk_user_id)
+            .first(conn)
+            .ok()
+    }
+
+    /// Marks a token as used and returns the id of its user
+    ///
+    /// `None` if the token is unknown, expired, already used or was issued for another purpose
+    ///
+    /// # Errors
+    ///
+    /// Fails if the token could not be updated
+    pub fn consume(
+        conn: &mut SqliteConnection,
+        token: &str,
+        purpose: TokenPurpose,
+    ) -> QueryResult<Option<i32>> {
+        let now = Utc::now().naive_utc();
+
+        diesel::update(
+            user_token::table
+                .filter(user_token::token_hash.eq(hash_token(token)))
+                .filter(user_token::purpose.eq(purpose.as_str()))
+                .filter(user_token::used_at.is_null())
+                .filter(user_token::expires_at.gt(now)),
+        )
+        .set(user_token::used_at.eq(now))
+        .returning(user_token::fk_user_id)
+        .get_result(conn)
+        .optional()
+    }
+}
@@ -0,0 +1,231 @@
+use std::{
+    collections::HashMap,
+    env, fmt,
+    sync::{Mutex, OnceLock},
+    time::{Duration, Instant},
+};
+
+use openidconnect::{
+    core::{
+        CoreAuthDisplay, CoreAuthPrompt, CoreErrorResponseType, CoreGenderClaim, CoreJsonWebKey,
+        CoreJsonWebKeyType, CoreJsonWebKeyUse, CoreJweContentEncryptionAlgorithm,
+        CoreJwsSigningAlgorithm, CoreProviderMetadata, CoreRevocableToken,
+        CoreRevocationErrorResponse, CoreTokenIntrospectionResponse, CoreTokenType,
+    },
+    reqwest::async_http_client,
+    AdditionalClaims, Client, ClientId, ClientSecret, EmptyExtraTokenFields, IdTokenClaims,
+    IdTokenFields, IssuerUrl, RedirectUrl, StandardErrorResponse, StandardTokenResponse,
+};
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+
+use crate::mail;
+
+/// Claim listing the groups of a user if `OIDC_GROUPS_CLAIM` is not set
+pub const DEFAULT_GROUPS_CLAIM: &str = "groups";
+/// Minutes between starting a login and the callback
+pub const LOGIN_LIFETIME_MINUTES: i64 = 10;
+/// HttpOnly cookie holding the `state` of the login started in this browser
+pub const STATE_COOKIE: &str = "oidc_state";
+/// How long discovered provider metadata and signing keys are used before fetching them again
+const METADATA_TTL: Duration = Duration::from_secs(60 * 60);
+
+static CONFIG: OnceLock<OidcConfig> = OnceLock::new();
+
+/// Claims of the ID token beyond the standard claims, e.g. the groups of the user
+#[derive(Clone, Serialize, Deserialize, Debug)]
+pub struct ExtraClaims {
+    #[serde(flatten)]
+    pub claims: HashMap<String, Value>,
+}
+
+impl AdditionalClaims for ExtraClaims {}
+
+pub type OidcIdTokenClaims = IdTokenClaims<ExtraClaims, CoreGenderClaim>;
+
+type OidcTokenResponse = StandardTokenResponse<
+    IdTokenFields<
+        ExtraClaims,
+        EmptyExtraTokenFields,
+        CoreGenderClaim,
+        CoreJweContentEncryptionAlgorithm,
+        CoreJwsSigningAlgorithm,
+        CoreJsonWebKeyType,
+    >,
+    CoreTokenType,
+>;
+
+/// `CoreClient` that keeps the additional claims of ID tokens
+pub type OidcClient = Client<
+    ExtraClaims,
+    CoreAuthDisplay,
+    CoreGenderClaim,
+    CoreJweContentEncryptionAlgorithm,
+    CoreJwsSigningAlgorithm,
+    CoreJsonWebKeyType,
+    CoreJsonWebKeyUse,
+    CoreJsonWebKey,
+    CoreAuthPrompt,
+    StandardErrorResponse<CoreErrorResponseType>,
+    OidcTokenResponse,
+    CoreTokenType,
+    CoreTokenIntrospectionResponse,
+    CoreRevocableToken,
+    CoreRevocationErrorResponse,
+>;
+
+/// Single sign-on with an OpenID Connect identity provider
+#[allow(clippy::module_name_repetitions)]
+pub struct OidcConfig {
+    pub issuer: IssuerUrl,
+    pub client_id: ClientId,
+    client_secret: Option<ClientSecret>,
+    /// Frontend page the identity provider sends the user back to
+    pub redirect_url: RedirectUrl,
+    /// Requested in addition to `openid`
+    pub scopes: Vec<String>,
+    pub groups_claim: String,
+    /// Members of any of these groups are admins, the others are not.
+    /// Empty to manage admins only in the backend
+    pub admin_groups: Vec<String>,
+    /// Whether unknown users get an account on their first login
+    pub auto_create: bool,
+    client: Mutex<Option<(Instant, OidcClient)>>,
+}
+
+impl fmt::Debug for OidcConfig {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("OidcConfig")
+            .field("issuer", &self.issuer.as_str())
+            .field("client_id", &self.client_id.as_str())
+            .field("redirect_url", &self.redirect_url.as_str())
+            .field("scopes", &self.scopes)
+            .field("groups_claim", &self.groups_claim)
+            .field("admin_groups", &self.admin_groups)
+            .field("auto_create", &self.auto_create)
+            .finish_non_exhaustive()
+    }
+}
+
+impl OidcConfig {
+    /// Loads the single sign-on configured with
+    /// - `OIDC_ISSUER_URL`: issuer of the identity provider, single sign-on is disabled if unset
+    /// - `OIDC_CLIENT_ID` / `OIDC_CLIENT_SECRET`: credentials of this app at the identity
+    ///   provider, the secret can be omitted for public clients
+    /// - `OIDC_REDIRECT_URL`: defaults to `<APP_URL>/oidc/callback`
+    /// - `OIDC_SCOPES`: space separated, defaults to `email profile`
+    /// - `OIDC_GROUPS_CLAIM`: defaults to [`DEFAULT_GROUPS_CLAIM`]
+    /// - `OIDC_ADMIN_GROUPS`: comma separated groups whose members are admins
+    /// - `OIDC_AUTO_CREATE`: `false` to only let existing users log in
+    ///
+    /// # Errors
+    ///
+    /// Fails if a setting is missing or invalid
+    pub fn from_env() -> Result<Option<Self>, String> {
+        let Ok(issuer) = env::var("OIDC_ISSUER_URL") else {
+            return Ok(None);
+        };
+        let issuer = IssuerUrl::new(issuer).map_err(|e| format!("Invalid OIDC_ISSUER_URL: {e}"))?;
+        let client_id = env::var("OIDC_CLIENT_ID").map_err(|_| "OIDC_CLIENT_ID must be set")?;
+        let redirect_url = env::var("OIDC_REDIRECT_URL")
+            .unwrap_or_else(|_| format!("{}/oidc/callback", mail::app_url()));
+
+        Ok(Some(OidcConfig {
+            issuer,
+            client_id: ClientId::new(client_id),
+            client_secret: env::var("OIDC_CLIENT_SECRET").ok().map(ClientSecret::new),
+            redirect_url: RedirectUrl::new(redirect_url)
+                .map_err(|e| format!("Invalid OIDC_REDIRECT_URL: {e}"))?,
+            scopes: env::var("OIDC_SCOPES")
+                .as_deref()
+                .unwrap_or("email profile")
+                .split_whitespace()
+                .filter(|s| *s != "openid")
+                .map(str::to_string)
+                .collect(),
+            groups_claim: env::var("OIDC_GROUPS_CLAIM")
+                .unwrap_or_else(|_| DEFAULT_GROUPS_CLAIM.to_string()),
+            admin_groups: env::var("OIDC_ADMIN_GROUPS")
+                .unwrap_or_default()
+                .split(',')
+                .map(str::trim)
+                .filter(|g| !g.is_empty())
+                .map(str::to_string)
+                .collect(),
+            auto_create: env::var("OIDC_AUTO_CREATE").map_or(true, |v| v != "false" && v != "0"),
+            client: Mutex::new(None),
+        }))
+    }
+
+    /// Client for the identity provider, its metadata and signing keys are discovered on
+    /// first use and refreshed every hour
+    ///
+    /// # Errors
+    ///
+    /// Fails if the discovery document or the signing keys could not be fetched
+    pub async fn client(&self) -> Result<OidcClient, String> {
+        if let Some((fetched_at, client)) = self.client.lock().ok().and_then(|c| c.clone()) {
+            if fetched_at.elapsed() < METADATA_TTL {
+                return Ok(client);
+            }
+        }
+
+        let metadata = CoreProviderMetadata::discover_async(self.issuer.clone(), async_http_client)
+            .await
+            .map_err(|e| format!("Discovery of {} failed: {e}", self.issuer.as_str()))?;
+        let client = OidcClient::from_provider_metadata(
+            metadata,
+            self.client_id.clone(),
+            self.client_secret.clone(),
+        )
+        .set_redirect_uri(self.redirect_url.clone());
+
+        if let Ok(mut cached) = self.client.lock() {
+            *cached = Some((Instant::now(), client.clone()));
+        }
+        Ok(client)
+    }
+
+    /// Groups of the user listed in the configured claim, a single group may be a string
+    #[must_use]
+    pub fn groups(&self, claims: &OidcIdTokenClaims) -> Vec<String> {
+        match claims.additional_claims().claims.get(&self.groups_claim) {
+            Some(Value::Array(groups)) => groups
+                .iter()
+                .filter_map(Value::as_str)
+                .map(str::to_string)
+                .collect(),
+            Some(Value::String(group)) => vec![group.clone()],
+            _ => Vec::new(),
+        }
+    }
+
+    /// Whether the user has to be an admin, `None` if admins are not mapped from groups
+    #[must_use]
+    pub fn is_admin(&self, claims: &OidcIdTokenClaims) -> Option<bool> {
+        if self.admin_groups.is_empty() {
+            return None;
+        }
+        Some(
+            self.groups(claims)
+                .iter()
+                .any(|g| self.admin_groups.contains(g)),
+        )
+    }
+}
+
+/// Installs the configuration returned by [`OidcConfig::from_env`], called once at startup
+///
+/// # Errors
+///
+/// Returns the configuration back if one has already been installed
+#[allow(clippy::result_large_err)]
+pub fn init(config: OidcConfig) -> Result<(), OidcConfig> {
+    CONFIG.set(config)
+}
+
+/// Single sign-on configuration, `None` if it is disabled
+#[must_use]
+pub fn config() -> Option<&'static OidcConfig> {
+    CONFIG.get()
+}
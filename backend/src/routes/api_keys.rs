@@ -0,0 +1,168 @@
+use std::fmt;
+
+use chrono::{DateTime, NaiveDateTime, Utc};
+use rocket::serde::json::Json;
+use rocket_http::Status;
+use rocket_okapi::okapi::schemars::{self, JsonSchema};
+use rocket_okapi::openapi;
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use validator::Validate;
+
+use crate::{
+    auth::SessionUser,
+    db::DbConn,
+    error::{ApiError, FieldError, JsonBody},
+    models::{
+        api_key::{ApiKey, ApiKeyScope},
+        user::REDACTED,
+    },
+    validation,
+};
+
+/// API key without the key itself, it is only shown once when created
+#[allow(clippy::module_name_repetitions)]
+#[derive(JsonSchema, Serialize, Deserialize, Debug)]
+pub struct ApiKeyResponse {
+    pub id: Option<i32>,
+    pub name: String,
+    /// Start of the key to recognize it, e.g. `cws_1a2b3c4d`
+    pub key_prefix: String,
+    pub scopes: Vec<ApiKeyScope>,
+    /// `null` if the key does not expire
+    #[serde(with = "crate::util::utc_timestamp")]
+    #[schemars(with = "Option<DateTime<Utc>>")]
+    pub expires_at: Option<NaiveDateTime>,
+    /// Accurate to a minute, `null` if the key has never been used
+    #[serde(with = "crate::util::utc_timestamp")]
+    #[schemars(with = "Option<DateTime<Utc>>")]
+    pub last_used_at: Option<NaiveDateTime>,
+    #[serde(with = "crate::util::utc_timestamp")]
+    #[schemars(with = "Option<DateTime<Utc>>")]
+    pub created_at: Option<NaiveDateTime>,
+}
+
+impl From<ApiKey> for ApiKeyResponse {
+    fn from(api_key: ApiKey) -> Self {
+        ApiKeyResponse {
+            id: api_key.id,
+            scopes: api_key.scopes(),
+            name: api_key.name,
+            key_prefix: api_key.key_prefix,
+            expires_at: api_key.expires_at,
+            last_used_at: api_key.last_used_at,
+            created_at: api_key.created_at,
+        }
+    }
+}
+
+#[derive(JsonSchema, Serialize, Deserialize, Validate, Debug)]
+pub struct NewApiKey {
+    /// What the key is used for, e.g. `Room display 2nd floor`
+    #[validate(length(min = 1, max = 100, message = "must be between 1 and 100 characters"))]
+    pub name: String,
+    /// `read` allows `GET` requests, `write` all requests. Permission scopes additionally
+    /// allow to use permissions of the user
+    #[validate(length(min = 1, message = "must not be empty"))]
+    pub scopes: Vec<ApiKeyScope>,
+    /// Omit for a key that does not expire
+    #[validate(custom(function = "validation::future_timestamp"))]
+    pub expires_at: Option<DateTime<Utc>>,
+}
+
+#[derive(JsonSchema, Serialize, Deserialize)]
+pub struct CreatedApiKey {
+    /// Send as `Authorization: Bearer <key>`, it is not shown again
+    pub key: String,
+    #[serde(flatten)]
+    pub api_key: ApiKeyResponse,
+}
+
+impl fmt::Debug for CreatedApiKey {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("CreatedApiKey")
+            .field("key", &REDACTED)
+            .field("api_key", &self.api_key)
+            .finish()
+    }
+}
+
+#[openapi(tag = "API keys")]
+#[get("/me/api-keys")]
+/// API keys of the current user that have not been revoked
+pub fn get_me_api_keys(access: SessionUser, mut db: DbConn) -> Json<Vec<ApiKeyResponse>> {
+    let user = access.user;
+    info!("GET /me/api-keys called by user: {user:?}");
+
+    Json(
+        ApiKey::by_user_id(&mut db, user.id.unwrap_or_default())
+            .into_iter()
+            .map(Into::into)
+            .collect(),
+    )
+}
+
+#[allow(clippy::missing_errors_doc)]
+#[openapi(tag = "API keys")]
+#[post("/me/api-keys", data = "<new_key>")]
+/// Create an API key for scripts and devices, the key is only returned this once
+///
+/// Permission scopes can only be granted for permissions the user has, and stop
+/// working if the user loses the permission
+pub fn post_me_api_keys(
+    new_key: JsonBody<NewApiKey>,
+    access: SessionUser,
+    mut db: DbConn,
+) -> Result<Json<CreatedApiKey>, ApiError> {
+    let user = access.user;
+    info!("POST /me/api-keys {new_key:?} called by user: {user:?}");
+
+    new_key.validate()?;
+
+    if let Some(scope) = new_key.scopes.iter().find(|s| {
+        s.permission()
+            .is_some_and(|p| !user.has_permission(&mut db, p))
+    }) {
+        let field = FieldError {
+            field: "scopes".to_string(),
+            code: "permission_missing".to_string(),
+            message: format!("requires the {} permission", scope.as_str()),
+        };
+        return Err(
+            ApiError::invalid("Invalid fields: scopes").with_details(json!({ "fields": [field] }))
+        );
+    }
+
+    let (api_key, key) = ApiKey::create(
+        &mut db,
+        user.id.unwrap_or_default(),
+        &new_key.name,
+        &new_key.scopes,
+        new_key.expires_at.map(|e| e.naive_utc()),
+    )?;
+    info!("API key created: {api_key:?}");
+
+    Ok(Json(CreatedApiKey {
+        key,
+        api_key: api_key.into(),
+    }))
+}
+
+#[allow(clippy::missing_errors_doc)]
+#[openapi(tag = "API keys")]
+#[delete("/me/api-keys/<id>")]
+/// Revoke an API key of the current user, it stops working immediately
+pub fn delete_me_api_keys(
+    id: i32,
+    access: SessionUser,
+    mut db: DbConn,
+) -> Result<Status, ApiError> {
+    let user = access.user;
+    info!("DELETE /me/api-keys/{id} called by user: {user:?}");
+
+    if ApiKey::revoke(&mut db, id, user.id.unwrap_or_default())? {
+        Ok(Status::NoContent)
+    } else {
+        Err(ApiError::not_found("API key"))
+    }
+}
@@ -1,16 +1,32 @@
+use chrono::NaiveDate;
+use diesel::SqliteConnection;
 use rocket::serde::json::Json;
+use rocket::FromFormField;
 use rocket_http::Status;
 use rocket_okapi::okapi::schemars;
 use rocket_okapi::okapi::schemars::JsonSchema;
 use rocket_okapi::openapi;
 use serde::{Deserialize, Serialize};
+use serde_json::json;
+use validator::Validate;
 
 use crate::{
-    auth::{self, Token},
+    auth::{
+        self, permissions::ManageBookings, AuthUser, RequirePermission, VerificationRequirement,
+    },
+    db::DbConn,
+    error::{ApiError, ErrorCode, JsonBody},
     models::{
-        booking::{Booking, SerializeBooking, UpdateBooking},
+        booking::{
+            duration_times, parse_date, parse_time_range, Booking, BookingStatus, SerializeBooking,
+            UpdateBooking,
+        },
+        booking_series::{BookingSeries, Recurrence},
+        role::Permission,
         room::Room,
+        user::User,
     },
+    validation,
 };
 
 #[allow(clippy::missing_errors_doc, clippy::module_name_repetitions)]
@@ -20,113 +36,345 @@ use crate::{
 ///
 /// Users can only see their own bookings
 ///
-/// Admins can see all bookings
-pub fn get_bookings(token: Token) -> Result<Json<Vec<SerializeBooking>>, Status> {
-    let Some(user) = auth::user_from_token(token.0) else {
-        return Err(Status::Unauthorized);
-    };
-
-    info!("GET /bookings called by user: {user:?}");
+/// Users with the `manage_bookings` permission can see all bookings, API keys only with
+/// the `manage_bookings` scope
+pub fn get_bookings(
+    access: AuthUser,
+    mut db: DbConn,
+) -> Result<Json<Vec<SerializeBooking>>, ApiError> {
+    info!("GET /bookings called by user: {:?}", access.user);
 
-    let bookings = match user.is_admin {
-        Some(true) => Booking::all(),
-        Some(false) => Booking::by_user_id(user.id.unwrap_or_default()),
-        None => return Err(Status::InternalServerError),
-    };
+    if access.can(&mut db, Permission::ManageBookings) {
+        Ok(Json(SerializeBooking::all(&mut db)))
+    } else {
+        Ok(Json(SerializeBooking::by_user_id(
+            &mut db,
+            access.user.id.unwrap_or_default(),
+        )))
+    }
+}
 
-    let mut s_bookings: Vec<SerializeBooking> = Vec::new();
+#[allow(clippy::module_name_repetitions)]
+#[derive(JsonSchema, Serialize, Deserialize, Validate, Debug)]
+pub struct PostBooking {
+    #[validate(length(min = 1, max = 500, message = "must be between 1 and 500 characters"))]
+    pub reason: String,
+    /// Shortcut for `start_time` and `end_time`, ignored if both are set
+    ///
+    /// 0 = Morning (08:00-12:00), 1 = Afternoon (12:00-17:00), 2 = Day (08:00-17:00)
+    #[validate(range(min = 0, max = 2, message = "must be 0, 1 or 2"))]
+    pub duration: Option<i32>,
+    /// HH:MM
+    #[validate(custom(function = "validation::time"))]
+    pub start_time: Option<String>,
+    /// HH:MM
+    #[validate(custom(function = "validation::time"))]
+    pub end_time: Option<String>,
+    /// YYYY-MM-DD, first occurrence if `recurrence` is set, must not be in the past
+    #[validate(custom(function = "validation::upcoming_date"))]
+    pub date: String,
+    pub room_id: i32,
+    /// Expected number of attendees, rejected if it exceeds the capacity of the room
+    #[validate(range(min = 1, message = "must be at least 1"))]
+    pub attendees: Option<i32>,
+    /// Repeat the booking, creates a booking series
+    #[validate(nested)]
+    pub recurrence: Option<Recurrence>,
+}
 
-    for b in bookings {
-        if let Some(s_booking) = SerializeBooking::from_booking(b) {
-            s_bookings.push(s_booking);
+impl PostBooking {
+    /// Normalized start and end time, taken from `duration` if no explicit times are given
+    #[must_use]
+    pub fn time_range(&self) -> Option<(String, String)> {
+        match (&self.start_time, &self.end_time) {
+            (Some(start), Some(end)) => parse_time_range(start, end),
+            _ => duration_times(self.duration?)
+                .map(|(start, end)| (start.to_string(), end.to_string())),
         }
     }
+}
+
+#[allow(clippy::module_name_repetitions)]
+#[derive(JsonSchema, Serialize, Deserialize, Validate, Debug)]
+pub struct PatchBooking {
+    #[validate(length(max = 500, message = "must be at most 500 characters"))]
+    pub reason: Option<String>,
+    /// Shortcut for `start_time` and `end_time`
+    ///
+    /// 0 = Morning (08:00-12:00), 1 = Afternoon (12:00-17:00), 2 = Day (08:00-17:00)
+    #[validate(range(min = -1, max = 2, message = "must be -1, 0, 1 or 2"))]
+    pub duration: Option<i32>,
+    /// HH:MM
+    #[validate(custom(function = "validation::time_or_empty"))]
+    pub start_time: Option<String>,
+    /// HH:MM
+    #[validate(custom(function = "validation::time_or_empty"))]
+    pub end_time: Option<String>,
+    /// Must be a valid transition from the current status, see `POST /bookings/{id}/approve`
+    pub status: Option<BookingStatus>,
+    /// YYYY-MM-DD, must not be in the past
+    #[validate(custom(function = "validation::upcoming_date_or_empty"))]
+    pub date: Option<String>,
+    /// Expected number of attendees, rejected if it exceeds the capacity of the room
+    #[validate(range(min = 1, message = "must be at least 1"))]
+    pub attendees: Option<i32>,
+}
 
-    Ok(Json(s_bookings))
+#[allow(clippy::module_name_repetitions)]
+#[derive(JsonSchema, Serialize, Deserialize, Debug)]
+pub struct SeriesResponse {
+    pub series: BookingSeries,
+    pub bookings: Vec<SerializeBooking>,
+    /// Occurrences that were skipped because the room is already booked
+    pub conflicts: Vec<NaiveDate>,
 }
 
 #[allow(clippy::module_name_repetitions)]
 #[derive(JsonSchema, Serialize, Deserialize, Debug)]
-pub struct PostBooking {
-    pub reason: String,
-    /// 0 = Morning, 1 = Afternoon, 2 = Day
-    pub duration: i32,
-    /// YYYY-MM-DD
-    pub date: String,
-    pub room_id: i32,
+#[serde(untagged)]
+pub enum PostBookingResponse {
+    Booking(Box<SerializeBooking>),
+    Series(SeriesResponse),
+}
+
+/// Which bookings of a series a change applies to
+#[derive(FromFormField, JsonSchema, Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum SeriesScope {
+    /// Only the given booking
+    This,
+    /// The given booking and all later bookings of its series
+    Following,
+    /// Every booking of the series
+    All,
 }
 
 #[allow(clippy::missing_errors_doc, clippy::module_name_repetitions)]
 #[openapi(tag = "Bookings")]
 #[post("/bookings", data = "<post_booking>")]
 /// Only accessible by authenticated users
+///
+/// If `recurrence` is set a booking series is created, occurrences that clash
+/// with existing bookings are skipped and listed in `conflicts`
+///
+/// Returns 409 `booking_conflict` with the id of the conflicting booking if the
+/// slot is already taken, of two concurrent requests for the same slot only one succeeds
+///
+/// Returns 403 `email_not_verified` if the user has not verified their email address,
+/// unless `REQUIRE_VERIFIED_EMAIL` is `none`
 pub fn post_bookings(
-    token: Token,
-    post_booking: Json<PostBooking>,
-) -> Result<Json<SerializeBooking>, Status> {
-    let Some(user) = auth::user_from_token(token.0) else {
-        return Err(Status::Unauthorized);
-    };
+    access: AuthUser,
+    mut db: DbConn,
+    post_booking: JsonBody<PostBooking>,
+) -> Result<Json<PostBookingResponse>, ApiError> {
+    let user = access.user;
     info!("POST /bookings {post_booking:?} called by user: {user:?}");
 
-    if Room::by_id(post_booking.room_id).is_none() {
-        // room not found
-        return Err(Status::NotFound);
+    auth::require_verified(&user, VerificationRequirement::Bookings)?;
+    post_booking.validate()?;
+
+    let Some(room) = Room::by_id(&mut db, post_booking.room_id) else {
+        return Err(ApiError::not_found("Room"));
     };
 
-    let date_bookings = Booking::by_date(&post_booking.date);
-    for b in date_bookings {
-        if b.fk_room_id == post_booking.room_id
-            && (b.duration == post_booking.duration
-                || b.duration == 2
-                || post_booking.duration == 2)
-        {
-            // if duration is same || old booking is day || new booking is day
-            return Err(Status::Conflict);
-        }
+    if let Some(attendees) = post_booking.attendees.filter(|a| !room.fits(*a)) {
+        return Err(capacity_exceeded(&room, attendees));
     }
 
-    if let Some(nb) = Booking::create(
-        &post_booking.reason,
-        post_booking.duration,
-        &post_booking.date,
-        post_booking.room_id,
-        user.id.unwrap_or_default(),
-    ) {
-        match SerializeBooking::from_booking(nb) {
-            Some(b) => Ok(Json(b)),
-            None => Err(Status::InternalServerError),
+    let Some((start_time, end_time)) = post_booking.time_range() else {
+        return Err(invalid_time_range());
+    };
+    let Some(date) = parse_date(&post_booking.date) else {
+        return Err(ApiError::invalid("date must be YYYY-MM-DD"));
+    };
+
+    if let Some(recurrence) = &post_booking.recurrence {
+        return post_booking_series(
+            &mut db,
+            &post_booking,
+            recurrence,
+            date,
+            (&start_time, &end_time),
+            user.id.unwrap_or_default(),
+        )
+        .map(|s| Json(PostBookingResponse::Series(s)));
+    }
+
+    let created = db.immediate_transaction(|conn| {
+        if let Some(conflict) = Booking::conflicting(
+            conn,
+            post_booking.room_id,
+            date,
+            &start_time,
+            &end_time,
+            None,
+        ) {
+            return Err(booking_conflict(conflict.id));
         }
-    } else {
-        Err(Status::InternalServerError)
+
+        Booking::create(
+            conn,
+            &post_booking.reason,
+            date,
+            &start_time,
+            &end_time,
+            post_booking.room_id,
+            user.id.unwrap_or_default(),
+            None,
+            post_booking.attendees,
+        )
+        .ok_or_else(ApiError::internal)
+    })?;
+
+    match SerializeBooking::from_booking(&mut db, created) {
+        Some(b) => Ok(Json(PostBookingResponse::Booking(Box::new(b)))),
+        None => Err(ApiError::internal()),
+    }
+}
+
+fn capacity_exceeded(room: &Room, attendees: i32) -> ApiError {
+    if attendees < 1 {
+        return ApiError::invalid("attendees must be at least 1");
     }
+
+    ApiError::new(
+        ErrorCode::CapacityExceeded,
+        format!("{attendees} attendees exceed the capacity of the room"),
+    )
+    .with_details(json!({ "capacity": room.capacity, "attendees": attendees }))
+}
+
+fn booking_conflict(booking_id: Option<i32>) -> ApiError {
+    ApiError::new(
+        ErrorCode::BookingConflict,
+        "The room is already booked at that time",
+    )
+    .with_details(json!({ "booking_id": booking_id }))
+}
+
+fn invalid_time_range() -> ApiError {
+    ApiError::invalid("Times must be HH:MM with the end after the start, or give a valid duration")
+}
+
+fn post_booking_series(
+    conn: &mut SqliteConnection,
+    post_booking: &PostBooking,
+    recurrence: &Recurrence,
+    start: NaiveDate,
+    (start_time, end_time): (&str, &str),
+    user_id: i32,
+) -> Result<SeriesResponse, ApiError> {
+    let Some(dates) = recurrence.dates(start) else {
+        return Err(ApiError::invalid("Invalid recurrence"));
+    };
+
+    let (series, conflicts) = conn.immediate_transaction(|conn| {
+        let (conflicts, free): (Vec<NaiveDate>, Vec<NaiveDate>) =
+            dates.into_iter().partition(|d| {
+                Booking::is_slot_taken(conn, post_booking.room_id, *d, start_time, end_time)
+            });
+
+        if free.is_empty() {
+            return Err(ApiError::new(
+                ErrorCode::BookingConflict,
+                "The room is already booked on every date of the series",
+            )
+            .with_details(json!({ "dates": conflicts })));
+        }
+
+        let series = BookingSeries::create(conn, recurrence, post_booking.room_id, user_id)
+            .ok_or_else(ApiError::internal)?;
+
+        for date in free {
+            Booking::create(
+                conn,
+                &post_booking.reason,
+                date,
+                start_time,
+                end_time,
+                post_booking.room_id,
+                user_id,
+                series.id,
+                post_booking.attendees,
+            )
+            .ok_or_else(ApiError::internal)?;
+        }
+
+        Ok((series, conflicts))
+    })?;
+
+    let Some(series_id) = series.id else {
+        return Err(ApiError::internal());
+    };
+
+    Ok(SeriesResponse {
+        bookings: SerializeBooking::by_series_id(conn, series_id),
+        series,
+        conflicts,
+    })
 }
 
 #[allow(clippy::missing_errors_doc, clippy::module_name_repetitions)]
 #[openapi(tag = "Bookings")]
-#[delete("/bookings/<id>")]
+#[delete("/bookings/<id>?<scope>")]
 /// Users can only delete their own bookings
 ///
-/// Admins can delete any booking
-pub fn delete_bookings(id: i32, token: Token) -> Result<Status, Status> {
-    let Some(user) = auth::user_from_token(token.0) else {
-        return Err(Status::Unauthorized);
-    };
-    info!("DELETE /bookings/{id:?} called by user: {user:?}");
+/// Users with the `manage_bookings` permission can delete any booking, API keys only with
+/// the `manage_bookings` scope
+///
+/// For bookings of a series `scope` selects whether only this booking (default),
+/// this and all following bookings or the whole series is deleted
+///
+/// The bookings are removed, not cancelled, so they disappear from all lists. Set the
+/// status to `Cancelled` with `PATCH /bookings/<id>` to keep them visible
+pub fn delete_bookings(
+    id: i32,
+    scope: Option<SeriesScope>,
+    access: AuthUser,
+    mut db: DbConn,
+) -> Result<Status, ApiError> {
+    info!(
+        "DELETE /bookings/{id:?} scope {scope:?} called by user: {:?}",
+        access.user
+    );
 
-    let Some(booking) = Booking::by_id(id) else {
+    let Some(booking) = Booking::by_id(&mut db, id) else {
         return Ok(Status::NoContent);
     };
-    let is_admin = user.is_admin.unwrap_or_default();
+    if booking.fk_user_id != access.user.id.unwrap_or_default() {
+        access.require_permission(&mut db, Permission::ManageBookings)?;
+    }
 
-    if booking.fk_user_id == user.id.unwrap_or_default() || is_admin {
-        if Booking::delete(id) {
+    // Bookings and series of one request go together, or not at all
+    db.immediate_transaction(|conn| {
+        let deleted = match (booking.fk_series_id, scope.unwrap_or(SeriesScope::This)) {
+            (Some(series_id), SeriesScope::Following) => {
+                Booking::delete_series(conn, series_id, Some(booking.date))
+                    && delete_series_if_empty(conn, series_id)
+            }
+            (Some(series_id), SeriesScope::All) => {
+                Booking::delete_series(conn, series_id, None)
+                    && BookingSeries::delete(conn, series_id)
+            }
+            (Some(series_id), SeriesScope::This) => {
+                Booking::delete(conn, id) && delete_series_if_empty(conn, series_id)
+            }
+            (None, _) => Booking::delete(conn, id),
+        };
+
+        if deleted {
             Ok(Status::NoContent)
         } else {
-            Err(Status::InternalServerError)
+            Err(ApiError::internal())
         }
+    })
+}
+
+fn delete_series_if_empty(conn: &mut SqliteConnection, series_id: i32) -> bool {
+    if Booking::by_series_id(conn, series_id).is_empty() {
+        BookingSeries::delete(conn, series_id)
     } else {
-        Err(Status::Forbidden)
+        true
     }
 }
 
@@ -136,58 +384,268 @@ pub fn delete_bookings(id: i32, token: Token) -> Result<Status, Status> {
     clippy::module_name_repetitions
 )]
 #[openapi(tag = "Bookings")]
-#[patch("/bookings/<id>", data = "<update_booking>")]
-/// Only accessible by admins
+#[patch("/bookings/<id>?<scope>", data = "<patch_booking>")]
+/// Requires the `manage_bookings` permission
+///
+/// Set reason, date, start_time, end_time to empty string and omit status to leave unchanged
 ///
-/// Set reason, date, status to empty string to leave unchanged
+/// Status changes must follow Pending -> Approved, Rejected, Cancelled and
+/// Approved -> Cancelled, Completed, NoShow, otherwise 422 is returned
 ///
-/// Set duration to -1 to leave unchanged
+/// Set duration to -1 to leave unchanged, explicit times take precedence over duration
+///
+/// For bookings of a series `scope` selects whether only this booking (default),
+/// this and all following bookings or the whole series is changed.
+/// The date can only be changed for a single booking
+///
+/// Like `POST /bookings`, returns 409 `booking_conflict` with the id of the conflicting
+/// booking if the new date or times overlap another booking. For series the clashing
+/// `dates` are listed instead, nothing is changed then. Concurrent changes to the same
+/// slot are serialized, only the first one succeeds
 pub fn patch_bookings(
     id: i32,
-    token: Token,
-    update_booking: Json<UpdateBooking>,
-) -> Result<Json<SerializeBooking>, Status> {
-    let ub = update_booking.0;
+    scope: Option<SeriesScope>,
+    access: RequirePermission<ManageBookings>,
+    mut db: DbConn,
+    patch_booking: JsonBody<PatchBooking>,
+) -> Result<Json<SerializeBooking>, ApiError> {
+    info!(
+        "PATCH /bookings/{id:?} scope {scope:?} called by user: {:?}",
+        access.user
+    );
+
+    patch_booking.validate()?;
+
+    let scope = scope.unwrap_or(SeriesScope::This);
+    let updated = db.immediate_transaction(|conn| apply_patch(conn, id, scope, &patch_booking))?;
+
+    match SerializeBooking::from_booking(&mut db, updated) {
+        Some(sb) => Ok(Json(sb)),
+        None => Err(ApiError::internal()),
+    }
+}
+
+/// Loads the booking, checks the change and applies it, run in a single transaction so
+/// no conflicting booking can be created in between
+fn apply_patch(
+    conn: &mut SqliteConnection,
+    id: i32,
+    scope: SeriesScope,
+    pb: &PatchBooking,
+) -> Result<Booking, ApiError> {
+    let Some(booking) = Booking::by_id(conn, id) else {
+        return Err(ApiError::not_found("Booking"));
+    };
+
+    let non_empty = |x: &Option<String>| x.clone().filter(|x| !x.is_empty());
     let mut update_booking = UpdateBooking {
-        reason: None,
-        duration: None,
-        status: None,
-        date: None,
+        reason: non_empty(&pb.reason),
+        status: pb.status,
+        date: non_empty(&pb.date).as_deref().and_then(parse_date),
+        start_time: None,
+        end_time: None,
+        attendees: None,
     };
 
-    if ub.reason.clone().is_some_and(|x| !x.is_empty()) {
-        update_booking.reason = Some(ub.reason.unwrap());
-    }
-    if ub.duration.is_some() && ub.duration.unwrap() > -1 && ub.duration.unwrap() < 3 {
-        update_booking.duration = Some(ub.duration.unwrap());
-    }
-    if ub.status.clone().is_some_and(|x| !x.is_empty()) {
-        update_booking.status = Some(ub.status.unwrap());
+    if let Some(attendees) = pb.attendees {
+        match Room::by_id(conn, booking.fk_room_id) {
+            Some(room) if room.fits(attendees) => update_booking.attendees = Some(attendees),
+            Some(room) => return Err(capacity_exceeded(&room, attendees)),
+            None => return Err(ApiError::internal()),
+        }
     }
-    if ub.date.clone().is_some_and(|x| !x.is_empty()) {
-        update_booking.date = Some(ub.date.unwrap());
+
+    let start_time = non_empty(&pb.start_time);
+    let end_time = non_empty(&pb.end_time);
+    if start_time.is_some() || end_time.is_some() {
+        let Some((start, end)) = parse_time_range(
+            start_time.as_deref().unwrap_or(&booking.start_time),
+            end_time.as_deref().unwrap_or(&booking.end_time),
+        ) else {
+            return Err(invalid_time_range());
+        };
+        update_booking.start_time = Some(start);
+        update_booking.end_time = Some(end);
+    } else if let Some((start, end)) = pb.duration.and_then(duration_times) {
+        update_booking.start_time = Some(start.to_string());
+        update_booking.end_time = Some(end.to_string());
     }
 
-    let Some(user) = auth::user_from_token(token.0) else {
-        return Err(Status::Unauthorized);
+    let series_bookings = match (booking.fk_series_id, scope) {
+        (Some(series_id), SeriesScope::Following | SeriesScope::All) => Some(
+            Booking::by_series_id(conn, series_id)
+                .into_iter()
+                .filter(|b| scope == SeriesScope::All || b.date >= booking.date)
+                .collect::<Vec<_>>(),
+        ),
+        _ => None,
     };
-    info!("PATCH /bookings/{id:?} called by user: {user:?}");
 
-    if !user.is_admin.unwrap_or_default() {
-        return Err(Status::Forbidden);
+    if let Some(next) = update_booking.status {
+        let legal = match &series_bookings {
+            Some(bookings) => bookings.iter().all(|b| b.status.can_transition_to(next)),
+            None => booking.status.can_transition_to(next),
+        };
+
+        if !legal {
+            return Err(invalid_transition(booking.status, next));
+        }
+    }
+
+    if let (Some(bookings), Some(start), Some(end)) = (
+        &series_bookings,
+        &update_booking.start_time,
+        &update_booking.end_time,
+    ) {
+        let conflicts = series_conflicts(conn, bookings, update_booking.status, start, end);
+        if !conflicts.is_empty() {
+            return Err(ApiError::new(
+                ErrorCode::BookingConflict,
+                "The room is already booked at the new time on some dates of the series",
+            )
+            .with_details(json!({ "dates": conflicts })));
+        }
+    }
+
+    let moved = update_booking.date.is_some() || update_booking.start_time.is_some();
+    let blocks = update_booking
+        .status
+        .unwrap_or(booking.status)
+        .blocks_slot();
+    if series_bookings.is_none() && moved && blocks {
+        if let Some(conflict) = Booking::conflicting(
+            conn,
+            booking.fk_room_id,
+            update_booking.date.unwrap_or(booking.date),
+            update_booking
+                .start_time
+                .as_deref()
+                .unwrap_or(&booking.start_time),
+            update_booking
+                .end_time
+                .as_deref()
+                .unwrap_or(&booking.end_time),
+            booking.id,
+        ) {
+            return Err(booking_conflict(conflict.id));
+        }
     }
 
-    if Booking::by_id(id).is_none() {
-        return Err(Status::NotFound);
+    let updated = match (booking.fk_series_id, scope) {
+        (Some(_), SeriesScope::Following | SeriesScope::All) if update_booking.date.is_some() => {
+            return Err(ApiError::invalid(
+                "The date can only be changed for a single booking",
+            ));
+        }
+        (Some(series_id), SeriesScope::Following) => {
+            if !Booking::update_series(conn, series_id, Some(booking.date), &update_booking) {
+                return Err(ApiError::internal());
+            }
+            Booking::by_id(conn, id)
+        }
+        (Some(series_id), SeriesScope::All) => {
+            if !Booking::update_series(conn, series_id, None, &update_booking) {
+                return Err(ApiError::internal());
+            }
+            Booking::by_id(conn, id)
+        }
+        _ => Booking::update(conn, id, update_booking),
     };
 
-    let updated = Booking::update(id, update_booking);
+    updated.ok_or_else(ApiError::internal)
+}
+
+/// Dates on which moving the bookings of a series to `start`..`end` would clash with
+/// other bookings, bookings that do not keep their slot are not checked
+fn series_conflicts(
+    conn: &mut SqliteConnection,
+    bookings: &[Booking],
+    status: Option<BookingStatus>,
+    start: &str,
+    end: &str,
+) -> Vec<NaiveDate> {
+    bookings
+        .iter()
+        .filter(|b| status.unwrap_or(b.status).blocks_slot())
+        .filter(|b| Booking::conflicting(conn, b.fk_room_id, b.date, start, end, b.id).is_some())
+        .map(|b| b.date)
+        .collect()
+}
+
+fn invalid_transition(from: BookingStatus, to: BookingStatus) -> ApiError {
+    ApiError::new(
+        ErrorCode::InvalidStatusTransition,
+        format!(
+            "A booking can not change from {} to {}",
+            from.as_str(),
+            to.as_str()
+        ),
+    )
+    .with_details(json!({ "from": from, "to": to }))
+}
+
+#[derive(JsonSchema, Serialize, Deserialize, Validate, Debug)]
+pub struct ReviewBooking {
+    /// Optional comment for the member, e.g. the reason for a rejection
+    #[validate(length(max = 1000, message = "must be at most 1000 characters"))]
+    pub comment: Option<String>,
+}
+
+#[allow(clippy::missing_errors_doc, clippy::module_name_repetitions)]
+#[openapi(tag = "Bookings")]
+#[post("/bookings/<id>/approve", data = "<review>")]
+/// Requires the `manage_bookings` permission
+///
+/// Approves a pending booking, 422 if the booking is not pending
+pub fn approve_bookings(
+    id: i32,
+    access: RequirePermission<ManageBookings>,
+    db: DbConn,
+    review: JsonBody<ReviewBooking>,
+) -> Result<Json<SerializeBooking>, ApiError> {
+    review_booking(id, &access.user, db, BookingStatus::Approved, &review)
+}
+
+#[allow(clippy::missing_errors_doc, clippy::module_name_repetitions)]
+#[openapi(tag = "Bookings")]
+#[post("/bookings/<id>/reject", data = "<review>")]
+/// Requires the `manage_bookings` permission
+///
+/// Rejects a pending booking, 422 if the booking is not pending
+pub fn reject_bookings(
+    id: i32,
+    access: RequirePermission<ManageBookings>,
+    db: DbConn,
+    review: JsonBody<ReviewBooking>,
+) -> Result<Json<SerializeBooking>, ApiError> {
+    review_booking(id, &access.user, db, BookingStatus::Rejected, &review)
+}
+
+fn review_booking(
+    id: i32,
+    user: &User,
+    mut db: DbConn,
+    status: BookingStatus,
+    review: &ReviewBooking,
+) -> Result<Json<SerializeBooking>, ApiError> {
+    info!("POST /bookings/{id:?} {status:?} called by user: {user:?}");
+
+    review.validate()?;
+
+    db.immediate_transaction(|conn| {
+        let Some(booking) = Booking::by_id(conn, id) else {
+            return Err(ApiError::not_found("Booking"));
+        };
+
+        if !booking.status.can_transition_to(status) {
+            return Err(invalid_transition(booking.status, status));
+        }
 
-    match updated {
-        Some(nb) => match SerializeBooking::from_booking(nb) {
+        match Booking::update_status(conn, id, status, review.comment.as_deref())
+            .and_then(|b| SerializeBooking::from_booking(conn, b))
+        {
             Some(sb) => Ok(Json(sb)),
-            None => Err(Status::InternalServerError),
-        },
-        None => Err(Status::InternalServerError),
-    }
+            None => Err(ApiError::internal()),
+        }
+    })
 }
@@ -1,121 +1,597 @@
-use std::borrow::Borrow;
+use std::fmt;
 
-use rocket::{response::status, serde::json::Json};
+use chrono::Utc;
+use diesel::SqliteConnection;
+use rocket::serde::json::Json;
 use rocket_http::Status;
 use rocket_okapi::okapi::schemars;
 use rocket_okapi::okapi::schemars::JsonSchema;
 use rocket_okapi::openapi;
 use serde::{Deserialize, Serialize};
+use serde_json::json;
+use validator::Validate;
 
 use crate::{
-    auth::{self, Token},
-    models::user::User,
+    auth::{self, AuthUser, ClientIp, SessionUser, VerificationRequirement},
+    db::DbConn,
+    error::{ApiError, ErrorCode, JsonBody},
+    jwt::{self, Jwks},
+    mail::{self, Mail},
+    models::{
+        login_throttle::{LoginThrottle, ThrottleKey, Throttled},
+        session::{Refresh, RefreshToken, Session},
+        two_factor::{self, Totp},
+        user::{PublicUser, User, REDACTED},
+        user_token::{TokenPurpose, UserToken},
+    },
+    routes::users::email_taken,
+    validation,
 };
 
-#[derive(JsonSchema, Serialize, Deserialize, Debug)]
+#[derive(JsonSchema, Serialize, Deserialize)]
 pub struct Login {
     pub email: String,
     pub password: String,
 }
 
+impl fmt::Debug for Login {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Login")
+            .field("email", &self.email)
+            .field("password", &REDACTED)
+            .finish()
+    }
+}
+
 #[allow(clippy::module_name_repetitions)]
-#[derive(JsonSchema, Serialize, Deserialize, Debug)]
+#[derive(JsonSchema, Serialize, Deserialize)]
 pub struct LoginResponse {
-    pub user: User,
+    pub user: PublicUser,
+    /// Short lived access token for the `Authorization` header
     pub token: String,
+    /// Exchange at `/token/refresh` for a new token pair, can only be used once
+    pub refresh_token: String,
+}
+
+impl LoginResponse {
+    /// Starts a new session for the user and issues its first token pair
+    fn start_session(conn: &mut SqliteConnection, user: User) -> Option<Self> {
+        let (session, refresh_token) = Session::start(conn, user.id?)?;
+        LoginResponse::for_session(user, &session, refresh_token)
+    }
+
+    fn for_session(user: User, session: &Session, refresh_token: String) -> Option<Self> {
+        let token = auth::encode_token(user.id?, session.id?)?;
+        Some(LoginResponse {
+            user: user.into(),
+            token,
+            refresh_token,
+        })
+    }
+}
+
+impl fmt::Debug for LoginResponse {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("LoginResponse")
+            .field("user", &self.user)
+            .field("token", &REDACTED)
+            .field("refresh_token", &REDACTED)
+            .finish()
+    }
+}
+
+/// Returned by `/login` instead of tokens if the user enabled two-factor authentication
+#[derive(JsonSchema, Serialize, Deserialize)]
+pub struct TwoFactorChallenge {
+    /// Always `true`, send the challenge token and a code to `/login/2fa`
+    pub two_factor_required: bool,
+    pub challenge_token: String,
+    /// Seconds until the challenge token expires
+    pub expires_in: i64,
+}
+
+impl fmt::Debug for TwoFactorChallenge {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("TwoFactorChallenge")
+            .field("challenge_token", &REDACTED)
+            .field("expires_in", &self.expires_in)
+            .finish()
+    }
+}
+
+/// Response of `/login`
+#[allow(clippy::module_name_repetitions)]
+#[derive(JsonSchema, Serialize, Deserialize, Debug)]
+#[serde(untagged)]
+pub enum LoginOutcome {
+    Session(LoginResponse),
+    TwoFactor(TwoFactorChallenge),
+}
+
+/// Response of `/register`, without tokens if login requires a verified email address
+#[allow(clippy::module_name_repetitions)]
+#[derive(JsonSchema, Serialize, Deserialize)]
+pub struct RegisterResponse {
+    pub user: PublicUser,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub token: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub refresh_token: Option<String>,
+}
+
+impl From<LoginResponse> for RegisterResponse {
+    fn from(response: LoginResponse) -> Self {
+        RegisterResponse {
+            user: response.user,
+            token: Some(response.token),
+            refresh_token: Some(response.refresh_token),
+        }
+    }
+}
+
+impl fmt::Debug for RegisterResponse {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("RegisterResponse")
+            .field("user", &self.user)
+            .field("token", &self.token.as_ref().map(|_| REDACTED))
+            .field(
+                "refresh_token",
+                &self.refresh_token.as_ref().map(|_| REDACTED),
+            )
+            .finish()
+    }
 }
 
 #[openapi(tag = "Login")]
 #[post("/login", data = "<login>")]
 #[allow(clippy::missing_panics_doc)]
 /// Login with email and password
-pub fn post_login(login: Json<Login>) -> Result<Json<LoginResponse>, Status> {
-    info!("Login attempt: {:?}", login.email);
-
-    match User::by_email(&login.email) {
-        Some(u) => {
-            if auth::verify_password(&login.password, &u.password) {
-                match auth::encode_token(u.id.unwrap()) {
-                    Some(token) => {
-                        let response = LoginResponse {
-                            user: u,
-                            token: token.clone(),
-                        };
-
-                        info!("User logged in: {response:?}");
-
-                        return Ok(Json(response));
-                    }
-                    None => return Err(Status::InternalServerError),
-                }
-            }
+///
+/// Unknown emails and wrong passwords are indistinguishable. After repeated failures
+/// further attempts are refused with 429 `too_many_requests` for a growing delay, and
+/// the account or client IP address is locked with 429 `account_locked` for
+/// `LOGIN_LOCKOUT_MINUTES`, `details.retry_after` is the number of seconds to wait
+///
+/// Returns 403 `email_not_verified` if `REQUIRE_VERIFIED_EMAIL` is `login` and the
+/// email address has not been verified yet
+///
+/// If the user enabled two-factor authentication, a challenge token is returned instead
+/// of the tokens, exchange it together with a code at `/login/2fa`
+pub fn post_login(
+    login: JsonBody<Login>,
+    ip: ClientIp,
+    mut db: DbConn,
+) -> Result<Json<LoginOutcome>, ApiError> {
+    info!("POST /login from {:?}", ip.0);
 
-            Err(Status::Unauthorized)
+    let account = ThrottleKey::account(&login.email);
+    let keys: Vec<_> = std::iter::once(account.clone())
+        .chain(ip.0.map(ThrottleKey::Ip))
+        .collect();
+
+    if let Some(throttled) = LoginThrottle::check(&mut db, &keys) {
+        return Err(throttled_error(throttled));
+    }
+
+    let user = User::by_email(&mut db, &login.email);
+    if !auth::verify_user_password(&login.password, user.as_ref()) {
+        warn!("Failed login from {:?}", ip.0);
+        LoginThrottle::record_failure(&mut db, &keys)?;
+        return Err(ApiError::new(
+            ErrorCode::InvalidCredentials,
+            "Wrong email or password",
+        ));
+    }
+    let Some(user) = user else {
+        return Err(ApiError::internal());
+    };
+
+    LoginThrottle::reset(&mut db, &account)?;
+    complete_login(&mut db, user).map(Json)
+}
+
+/// Starts a session for a user whose identity has been confirmed, or returns a
+/// two-factor challenge if the user enabled two-factor authentication
+///
+/// # Errors
+///
+/// Fails with 403 `email_not_verified` if unverified users can not log in
+pub fn complete_login(conn: &mut SqliteConnection, user: User) -> Result<LoginOutcome, ApiError> {
+    auth::require_verified(&user, VerificationRequirement::Login)?;
+
+    let id = user.id.unwrap_or_default();
+    if Totp::is_enabled(conn, id) {
+        let lifetime = auth::two_factor_challenge_lifetime();
+        let challenge_token =
+            UserToken::issue(conn, id, TokenPurpose::TwoFactorChallenge, lifetime)?;
+        info!("Two-factor challenge for user {id}");
+
+        return Ok(LoginOutcome::TwoFactor(TwoFactorChallenge {
+            two_factor_required: true,
+            challenge_token,
+            expires_in: lifetime.num_seconds(),
+        }));
+    }
+
+    match LoginResponse::start_session(conn, user) {
+        Some(response) => {
+            info!("User logged in: {response:?}");
+            Ok(LoginOutcome::Session(response))
         }
-        _ => Err(Status::Unauthorized),
+        None => Err(ApiError::internal()),
     }
 }
 
-#[derive(JsonSchema, Serialize, Deserialize, Debug)]
+#[derive(JsonSchema, Serialize, Deserialize)]
+pub struct TwoFactorLogin {
+    /// Challenge token returned by `/login`
+    pub challenge_token: String,
+    /// Current code of the authenticator app or an unused recovery code
+    pub code: String,
+}
+
+impl fmt::Debug for TwoFactorLogin {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("TwoFactorLogin")
+            .field("challenge_token", &REDACTED)
+            .field("code", &REDACTED)
+            .finish()
+    }
+}
+
+#[openapi(tag = "Login")]
+#[post("/login/2fa", data = "<login>")]
+/// Second step of the login if two-factor authentication is enabled
+///
+/// Wrong codes count as failed logins, the challenge token stays valid until it expires
+/// or a code was accepted
+pub fn post_login_2fa(
+    login: JsonBody<TwoFactorLogin>,
+    ip: ClientIp,
+    mut db: DbConn,
+) -> Result<Json<LoginResponse>, ApiError> {
+    info!("POST /login/2fa from {:?}", ip.0);
+
+    let invalid_token = || {
+        ApiError::new(
+            ErrorCode::InvalidToken,
+            "Challenge token is invalid, expired or has already been used, log in again",
+        )
+    };
+    let Some(user) = UserToken::user_id(
+        &mut db,
+        &login.challenge_token,
+        TokenPurpose::TwoFactorChallenge,
+    )
+    .and_then(|id| User::by_id(&mut db, id)) else {
+        return Err(invalid_token());
+    };
+
+    let account = ThrottleKey::account(&user.email);
+    let keys: Vec<_> = std::iter::once(account.clone())
+        .chain(ip.0.map(ThrottleKey::Ip))
+        .collect();
+    if let Some(throttled) = LoginThrottle::check(&mut db, &keys) {
+        return Err(throttled_error(throttled));
+    }
+
+    let id = user.id.unwrap_or_default();
+    if !two_factor::verify_code(&mut db, id, &login.code)? {
+        warn!("Wrong two-factor code for user {id} from {:?}", ip.0);
+        LoginThrottle::record_failure(&mut db, &keys)?;
+        return Err(ApiError::new(
+            ErrorCode::InvalidCredentials,
+            "Wrong two-factor code",
+        ));
+    }
+
+    // Of two requests with valid codes only one may start a session
+    if UserToken::consume(
+        &mut db,
+        &login.challenge_token,
+        TokenPurpose::TwoFactorChallenge,
+    )?
+    .is_none()
+    {
+        return Err(invalid_token());
+    }
+    LoginThrottle::reset(&mut db, &account)?;
+
+    match LoginResponse::start_session(&mut db, user) {
+        Some(response) => {
+            info!("User logged in: {response:?}");
+            Ok(Json(response))
+        }
+        None => Err(ApiError::internal()),
+    }
+}
+
+/// 429 with the seconds to wait in `details.retry_after`
+pub fn throttled_error(throttled: Throttled) -> ApiError {
+    let (code, message, wait) = match throttled {
+        Throttled::Delayed(wait) => (
+            ErrorCode::TooManyRequests,
+            "Too many failed logins, wait before trying again",
+            wait,
+        ),
+        Throttled::Locked(wait) => (
+            ErrorCode::AccountLocked,
+            "Locked after too many failed logins, try again later or ask an admin to unlock it",
+            wait,
+        ),
+    };
+    // Round up so clients never retry a moment too early
+    let retry_after = (wait.num_milliseconds() + 999) / 1000;
+    ApiError::new(code, message).with_details(json!({ "retry_after": retry_after }))
+}
+
+#[derive(JsonSchema, Serialize, Deserialize, Validate)]
 pub struct Register {
+    #[validate(length(min = 1, max = 100, message = "must be between 1 and 100 characters"))]
     pub first_name: String,
+    #[validate(length(min = 1, max = 100, message = "must be between 1 and 100 characters"))]
     pub last_name: String,
+    #[validate(email(message = "must be a valid email address"))]
     pub email: String,
+    /// At least 8 characters with a letter and a digit
+    #[validate(custom(function = "validation::password"))]
     pub password: String,
 }
 
+impl fmt::Debug for Register {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Register")
+            .field("first_name", &self.first_name)
+            .field("last_name", &self.last_name)
+            .field("email", &self.email)
+            .field("password", &REDACTED)
+            .finish()
+    }
+}
+
 #[openapi(tag = "Login")]
 #[post("/register", data = "<register>")]
 #[allow(clippy::missing_panics_doc)]
 /// Register with first name, last name, email and password.
 ///
-/// First user is automatically an admin
-pub fn post_register(register: Json<Register>) -> Result<Json<LoginResponse>, Status> {
+/// First user is automatically an admin. A link to verify the email address is mailed
+/// to the user, see `REQUIRE_VERIFIED_EMAIL` for what unverified users can do. No tokens
+/// are returned if unverified users can not log in
+pub fn post_register(
+    register: JsonBody<Register>,
+    mut db: DbConn,
+) -> Result<Json<RegisterResponse>, ApiError> {
     info!("POST /register");
 
-    if User::by_email(&register.email).is_some() {
+    register.validate()?;
+
+    if User::by_email(&mut db, &register.email).is_some() {
         info!("Conflict: {register:?}");
-        return Err(Status::Conflict);
+        return Err(email_taken());
     }
 
-    let is_admin = match User::count() {
+    let is_admin = match User::count(&mut db) {
         Some(c) => c == 0,
-        None => return Err(Status::InternalServerError),
+        None => return Err(ApiError::internal()),
     };
 
     match User::create_hash(
+        &mut db,
         is_admin,
         &register.first_name,
         &register.last_name,
         &register.email,
         &register.password,
+        false,
+    ) {
+        Some(user) => {
+            info!("User created: {user:?}");
+            send_verification_mail(&mut db, &user);
+
+            if auth::require_verified(&user, VerificationRequirement::Login).is_err() {
+                return Ok(Json(RegisterResponse {
+                    user: user.into(),
+                    token: None,
+                    refresh_token: None,
+                }));
+            }
+
+            match LoginResponse::start_session(&mut db, user) {
+                Some(response) => Ok(Json(response.into())),
+                None => Err(ApiError::internal()),
+            }
+        }
+        None => Err(ApiError::internal()),
+    }
+}
+
+/// Mails a new verification link to the user, failures are only logged
+pub fn send_verification_mail(conn: &mut SqliteConnection, user: &User) {
+    let lifetime = auth::email_verification_token_lifetime();
+    match UserToken::issue(
+        conn,
+        user.id.unwrap_or_default(),
+        TokenPurpose::EmailVerification,
+        lifetime,
     ) {
-        Some(user) => match auth::encode_token(user.id.unwrap()) {
-            Some(token) => {
-                info!("User created: {user:?}");
-                let response = LoginResponse {
-                    user,
-                    token: token.clone(),
-                };
-
-                Ok(Json(response))
+        Ok(token) => {
+            if let Err(e) = mail::send(&Mail::email_verification(user, &token)) {
+                error!("Could not send verification mail to {}: {e}", user.email);
             }
-            None => Err(Status::InternalServerError),
-        },
-        None => Err(Status::InternalServerError),
+        }
+        Err(e) => error!("Could not issue verification token: {e}"),
     }
 }
 
+#[derive(JsonSchema, Serialize, Deserialize)]
+pub struct VerifyEmail {
+    /// Token from the verification mail
+    pub token: String,
+}
+
+impl fmt::Debug for VerifyEmail {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("VerifyEmail")
+            .field("token", &REDACTED)
+            .finish()
+    }
+}
+
+#[openapi(tag = "Login")]
+#[post("/register/verify", data = "<verify>")]
+/// Confirm the email address with the token of the verification mail
+pub fn post_register_verify(
+    verify: JsonBody<VerifyEmail>,
+    mut db: DbConn,
+) -> Result<Status, ApiError> {
+    info!("POST /register/verify");
+
+    db.immediate_transaction(|conn| {
+        let Some(user_id) =
+            UserToken::consume(conn, &verify.token, TokenPurpose::EmailVerification)?
+        else {
+            return Err(ApiError::new(
+                ErrorCode::InvalidToken,
+                "Verification token is invalid, expired or has already been used",
+            ));
+        };
+
+        User::verify_email(conn, user_id)?;
+        info!("Email verified for user {user_id}");
+        Ok(Status::NoContent)
+    })
+}
+
+#[derive(JsonSchema, Serialize, Deserialize, Debug)]
+pub struct ResendVerification {
+    pub email: String,
+}
+
+#[openapi(tag = "Login")]
+#[post("/register/verify/resend", data = "<resend>")]
+/// Mail a new verification link, links sent before stop working
+///
+/// Always answers 202, whether an unverified account exists for the address is not
+/// revealed. At most one mail per `VERIFICATION_RESEND_SECONDS` is sent to an address
+pub fn post_register_verify_resend(resend: JsonBody<ResendVerification>, mut db: DbConn) -> Status {
+    info!("POST /register/verify/resend");
+
+    let Some(user) =
+        User::by_email(&mut db, &resend.email).filter(|u| u.email_verified_at.is_none())
+    else {
+        return Status::Accepted;
+    };
+
+    let id = user.id.unwrap_or_default();
+    let last_sent = UserToken::last_issued_at(&mut db, id, TokenPurpose::EmailVerification);
+    if last_sent
+        .is_some_and(|at| at + auth::verification_resend_interval() > Utc::now().naive_utc())
+    {
+        info!("Verification mail to user {id} skipped, the last one was sent moments ago");
+        return Status::Accepted;
+    }
+
+    send_verification_mail(&mut db, &user);
+    Status::Accepted
+}
+
 #[openapi(tag = "Login")]
 #[get("/login")]
 #[allow(clippy::missing_panics_doc)]
 /// Check login status
-pub fn get_login(token: Token) -> Result<Json<User>, Status> {
-    let Some(user) = auth::user_from_token(token.0) else {
-        return Err(Status::Unauthorized);
-    };
+pub fn get_login(access: AuthUser) -> Result<Json<PublicUser>, ApiError> {
+    let user = access.user;
 
     info!("GET /login called by user: {user:?}");
 
-    Ok(Json(user))
+    Ok(Json(user.into()))
+}
+
+#[derive(JsonSchema, Serialize, Deserialize)]
+pub struct RefreshRequest {
+    pub refresh_token: String,
+}
+
+impl fmt::Debug for RefreshRequest {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("RefreshRequest")
+            .field("refresh_token", &REDACTED)
+            .finish()
+    }
+}
+
+#[openapi(tag = "Login")]
+#[post("/token/refresh", data = "<refresh>")]
+/// Exchange a refresh token for a new access and refresh token
+///
+/// Every refresh token can only be used once, replaying an already used token
+/// logs out the session it belongs to
+pub fn post_token_refresh(
+    refresh: JsonBody<RefreshRequest>,
+    mut db: DbConn,
+) -> Result<Json<LoginResponse>, ApiError> {
+    info!("POST /token/refresh");
+
+    match RefreshToken::rotate(&mut db, &refresh.refresh_token) {
+        Some(Refresh::Rotated(session, refresh_token)) => {
+            let Some(user) = User::by_id(&mut db, session.fk_user_id) else {
+                return Err(ApiError::new(
+                    ErrorCode::InvalidToken,
+                    "The user no longer exists",
+                ));
+            };
+
+            match LoginResponse::for_session(user, &session, refresh_token) {
+                Some(response) => Ok(Json(response)),
+                None => Err(ApiError::internal()),
+            }
+        }
+        Some(Refresh::Invalid) => Err(ApiError::new(
+            ErrorCode::InvalidToken,
+            "Refresh token is invalid, expired or revoked",
+        )),
+        Some(Refresh::Reused) => Err(ApiError::new(
+            ErrorCode::TokenReused,
+            "Refresh token has already been used, the session has been revoked",
+        )),
+        None => Err(ApiError::internal()),
+    }
+}
+
+#[openapi(tag = "Login")]
+#[post("/logout")]
+/// Revoke the session of the current token, its access and refresh tokens stop working
+pub fn post_logout(access: SessionUser, mut db: DbConn) -> Result<Status, ApiError> {
+    let user = access.user;
+    info!("POST /logout called by user: {user:?}");
+
+    if Session::revoke(&mut db, access.claims.sid) {
+        Ok(Status::NoContent)
+    } else {
+        Err(ApiError::internal())
+    }
+}
+
+#[openapi(tag = "Login")]
+#[post("/logout/all")]
+/// Revoke all sessions of the current user, logging out on every device
+pub fn post_logout_all(access: SessionUser, mut db: DbConn) -> Result<Status, ApiError> {
+    let user = access.user;
+    info!("POST /logout/all called by user: {user:?}");
+
+    if Session::revoke_all(&mut db, user.id.unwrap_or_default()) {
+        Ok(Status::NoContent)
+    } else {
+        Err(ApiError::internal())
+    }
+}
+
+#[openapi(tag = "Login")]
+#[get("/.well-known/jwks.json")]
+/// Public keys for verifying access tokens, empty if tokens are signed with a shared secret
+pub fn get_jwks() -> Result<Json<Jwks>, ApiError> {
+    match jwt::keys() {
+        Some(keys) => Ok(Json(keys.jwks())),
+        None => Err(ApiError::internal()),
+    }
 }
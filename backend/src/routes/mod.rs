@@ -1,4 +1,10 @@
+pub mod api_keys;
 pub mod bookings;
 pub mod login;
+pub mod oidc;
+pub mod password;
+pub mod profile;
 pub mod rooms;
+pub mod settings;
+pub mod two_factor;
 pub mod users;
@@ -0,0 +1,290 @@
+use std::fmt;
+
+use diesel::SqliteConnection;
+use openidconnect::reqwest::async_http_client;
+use openidconnect::{
+    core::CoreResponseType, AuthenticationFlow, AuthorizationCode, CsrfToken, Nonce,
+    PkceCodeChallenge, PkceCodeVerifier, Scope, TokenResponse,
+};
+use rocket::{
+    http::{Cookie, CookieJar, SameSite},
+    serde::json::Json,
+};
+use rocket_okapi::okapi::schemars::{self, JsonSchema};
+use rocket_okapi::openapi;
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    auth::generate_token,
+    db::DbConn,
+    error::{ApiError, ErrorCode, JsonBody},
+    mail,
+    models::{
+        oidc::{OidcLogin, UserIdentity},
+        user::{User, REDACTED},
+    },
+    oidc::{self, OidcConfig, OidcIdTokenClaims, LOGIN_LIFETIME_MINUTES, STATE_COOKIE},
+    routes::login::{complete_login, send_verification_mail, LoginOutcome},
+};
+
+#[derive(JsonSchema, Serialize, Deserialize, Debug)]
+pub struct OidcAuthorization {
+    /// Identity provider page to send the user to, it redirects back to the frontend with
+    /// `code` and `state` query parameters
+    pub authorization_url: String,
+}
+
+#[derive(JsonSchema, Serialize, Deserialize)]
+pub struct OidcCallback {
+    pub code: String,
+    pub state: String,
+}
+
+impl fmt::Debug for OidcCallback {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("OidcCallback")
+            .field("code", &REDACTED)
+            .field("state", &REDACTED)
+            .finish()
+    }
+}
+
+fn config() -> Result<&'static OidcConfig, ApiError> {
+    oidc::config()
+        .ok_or_else(|| ApiError::new(ErrorCode::NotFound, "Single sign-on is not configured"))
+}
+
+async fn client(config: &OidcConfig) -> Result<oidc::OidcClient, ApiError> {
+    config.client().await.map_err(|e| {
+        error!("{e}");
+        ApiError::new(
+            ErrorCode::IdentityProviderError,
+            "The identity provider is not available",
+        )
+    })
+}
+
+#[allow(clippy::missing_errors_doc)]
+#[openapi(tag = "Single sign-on")]
+#[get("/oidc/authorize")]
+/// Start a login with the identity provider configured with `OIDC_ISSUER_URL`
+///
+/// Sets an HttpOnly cookie that binds the login to this browser, send the request and
+/// the callback with credentials. Returns 404 if single sign-on is not configured
+pub async fn get_oidc_authorize(
+    cookies: &CookieJar<'_>,
+    mut db: DbConn,
+) -> Result<Json<OidcAuthorization>, ApiError> {
+    info!("GET /oidc/authorize");
+
+    let config = config()?;
+    let client = client(config).await?;
+
+    let (pkce_challenge, pkce_verifier) = PkceCodeChallenge::new_random_sha256();
+    let (url, state, nonce) = client
+        .authorize_url(
+            AuthenticationFlow::<CoreResponseType>::AuthorizationCode,
+            CsrfToken::new_random,
+            Nonce::new_random,
+        )
+        .add_scopes(config.scopes.iter().cloned().map(Scope::new))
+        .set_pkce_challenge(pkce_challenge)
+        .url();
+
+    OidcLogin::start(
+        &mut db,
+        state.secret(),
+        nonce.secret(),
+        pkce_verifier.secret(),
+        chrono::Duration::minutes(LOGIN_LIFETIME_MINUTES),
+    )?;
+    cookies.add(
+        Cookie::build((STATE_COOKIE, state.secret().clone()))
+            .path("/oidc")
+            .http_only(true)
+            .secure(mail::app_url().starts_with("https://"))
+            .same_site(SameSite::Lax)
+            .max_age(rocket::time::Duration::minutes(LOGIN_LIFETIME_MINUTES)),
+    );
+
+    Ok(Json(OidcAuthorization {
+        authorization_url: url.to_string(),
+    }))
+}
+
+#[allow(clippy::missing_errors_doc)]
+#[openapi(tag = "Single sign-on")]
+#[post("/oidc/callback", data = "<callback>")]
+/// Finish a login with the `code` and `state` the identity provider redirected back with
+///
+/// The `state` has to match the cookie set by `/oidc/authorize` in the same browser, so
+/// nobody can log a victim into their own account with a link. The account of the
+/// identity provider is linked to the user with the same verified email address if that
+/// user verified it too. Unknown users get an account unless `OIDC_AUTO_CREATE` is
+/// `false`, and if `OIDC_ADMIN_GROUPS` is set, membership in one of them decides whether
+/// the user is an admin. Like `/login`, a two-factor challenge is returned if the user
+/// enabled it
+pub async fn post_oidc_callback(
+    callback: JsonBody<OidcCallback>,
+    cookies: &CookieJar<'_>,
+    mut db: DbConn,
+) -> Result<Json<LoginOutcome>, ApiError> {
+    info!("POST /oidc/callback");
+
+    let config = config()?;
+    let browser_state = cookies.get(STATE_COOKIE).map(|c| c.value().to_string());
+    cookies.remove(Cookie::build(STATE_COOKIE).path("/oidc"));
+    if browser_state.as_deref() != Some(callback.state.as_str()) {
+        return Err(ApiError::new(
+            ErrorCode::InvalidToken,
+            "The login was not started in this browser, start the login again",
+        ));
+    }
+
+    let Some(login) = OidcLogin::take(&mut db, &callback.state)? else {
+        return Err(ApiError::new(
+            ErrorCode::InvalidToken,
+            "Login state is invalid or expired, start the login again",
+        ));
+    };
+    let client = client(config).await?;
+
+    let response = client
+        .exchange_code(AuthorizationCode::new(callback.code.clone()))
+        .set_pkce_verifier(PkceCodeVerifier::new(login.pkce_verifier))
+        .request_async(async_http_client)
+        .await
+        .map_err(|e| {
+            warn!("Code exchange with the identity provider failed: {e}");
+            ApiError::new(
+                ErrorCode::IdentityProviderError,
+                "The identity provider did not accept the authorization code",
+            )
+        })?;
+
+    let verifier = client.id_token_verifier();
+    let claims = response
+        .id_token()
+        .ok_or_else(|| "no ID token".to_string())
+        .and_then(|token| {
+            token
+                .claims(&verifier, &Nonce::new(login.nonce))
+                .map_err(|e| e.to_string())
+        })
+        .map_err(|e| {
+            warn!("Invalid ID token from the identity provider: {e}");
+            ApiError::new(
+                ErrorCode::IdentityProviderError,
+                "The identity provider returned an invalid ID token",
+            )
+        })?;
+
+    let user = user_for_identity(&mut db, config, claims)?;
+    complete_login(&mut db, user).map(Json)
+}
+
+/// User linked to the account of the identity provider, linking or creating one on the
+/// first login
+fn user_for_identity(
+    conn: &mut SqliteConnection,
+    config: &OidcConfig,
+    claims: &OidcIdTokenClaims,
+) -> Result<User, ApiError> {
+    let issuer = config.issuer.as_str();
+    let subject = claims.subject().as_str();
+    let is_admin = config.is_admin(claims);
+
+    let user = match UserIdentity::user_id(conn, issuer, subject) {
+        Some(id) => User::by_id(conn, id).ok_or_else(ApiError::internal)?,
+        None => link_or_create(conn, config, claims)?,
+    };
+
+    let id = user.id.unwrap_or_default();
+    match is_admin {
+        Some(is_admin) if user.is_admin != Some(is_admin) => {
+            info!("Setting is_admin of user {id} to {is_admin} from the identity provider groups");
+            User::set_admin(conn, id, is_admin)?;
+            User::by_id(conn, id).ok_or_else(ApiError::internal)
+        }
+        _ => Ok(user),
+    }
+}
+
+fn link_or_create(
+    conn: &mut SqliteConnection,
+    config: &OidcConfig,
+    claims: &OidcIdTokenClaims,
+) -> Result<User, ApiError> {
+    let issuer = config.issuer.as_str();
+    let subject = claims.subject().as_str();
+    let Some(email) = claims.email().map(|e| e.as_str()) else {
+        return Err(ApiError::new(
+            ErrorCode::Forbidden,
+            "The identity provider did not share an email address",
+        ));
+    };
+    let email_verified = claims.email_verified().unwrap_or(false);
+
+    conn.immediate_transaction(|conn| {
+        if let Some(user) = User::by_email(conn, email) {
+            // Otherwise anyone who can choose their email address at the identity provider
+            // could take over the account, and anyone who registered the address without
+            // owning it could keep a password for the account of its real owner
+            if !email_verified || user.email_verified_at.is_none() {
+                return Err(ApiError::new(
+                    ErrorCode::Conflict,
+                    "An account with this email address exists, log in with its password \
+                     and verify the email address first",
+                ));
+            }
+            let id = user.id.unwrap_or_default();
+            UserIdentity::link(conn, issuer, subject, id)?;
+            info!("Linked identity {subject} of {issuer} to user {id}");
+            return User::by_id(conn, id).ok_or_else(ApiError::internal);
+        }
+
+        if !config.auto_create {
+            return Err(ApiError::new(
+                ErrorCode::Forbidden,
+                "There is no account for this identity, ask an admin to create one",
+            ));
+        }
+
+        let first_name = claims
+            .given_name()
+            .and_then(|n| n.get(None))
+            .map(|n| n.to_string())
+            .or_else(|| {
+                claims
+                    .name()
+                    .and_then(|n| n.get(None))
+                    .map(|n| n.to_string())
+            })
+            .unwrap_or_else(|| email.split('@').next().unwrap_or_default().to_string());
+        let last_name = claims
+            .family_name()
+            .and_then(|n| n.get(None))
+            .map(|n| n.to_string())
+            .unwrap_or_default();
+
+        // The random password is never shown, it can be replaced with /password/forgot
+        let Some(user) = User::create_hash(
+            conn,
+            config.is_admin(claims).unwrap_or(false),
+            &first_name,
+            &last_name,
+            email,
+            &generate_token(),
+            email_verified,
+        ) else {
+            return Err(ApiError::internal());
+        };
+        UserIdentity::link(conn, issuer, subject, user.id.unwrap_or_default())?;
+        info!("User created from identity {subject} of {issuer}: {user:?}");
+
+        if !email_verified {
+            send_verification_mail(conn, &user);
+        }
+        Ok(user)
+    })
+}
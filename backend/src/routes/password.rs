@@ -0,0 +1,173 @@
+use std::fmt;
+
+use chrono::Utc;
+use diesel::SqliteConnection;
+use rocket_http::Status;
+use rocket_okapi::okapi::schemars::{self, JsonSchema};
+use rocket_okapi::openapi;
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use validator::Validate;
+
+use crate::{
+    auth::{self, SessionUser},
+    db::DbConn,
+    error::{ApiError, ErrorCode, FieldError, JsonBody},
+    mail::{self, Mail},
+    models::{
+        user::{User, REDACTED},
+        user_token::{TokenPurpose, UserToken},
+    },
+    validation,
+};
+
+#[derive(JsonSchema, Serialize, Deserialize, Debug)]
+pub struct ForgotPassword {
+    pub email: String,
+}
+
+#[openapi(tag = "Password")]
+#[post("/password/forgot", data = "<forgot>")]
+/// Mail a link to reset the password to the user with this email address
+///
+/// Always answers 202, whether an account exists for the address is not revealed.
+/// At most one mail per `VERIFICATION_RESEND_SECONDS` is sent to an address.
+/// The link is valid once and only until a newer one is requested
+pub fn post_password_forgot(forgot: JsonBody<ForgotPassword>, mut db: DbConn) -> Status {
+    info!("POST /password/forgot");
+
+    if let Some(mail) = password_reset_mail(&mut db, &forgot.email) {
+        // Don't wait for the mail server, known addresses would take noticeably longer
+        rocket::tokio::task::spawn_blocking(move || {
+            if let Err(e) = mail::send(&mail) {
+                error!("Could not send password reset mail: {e}");
+            }
+        });
+    }
+    Status::Accepted
+}
+
+/// Issues a reset token and builds the mail, `None` for unknown addresses and when the
+/// last mail was sent moments ago
+fn password_reset_mail(conn: &mut SqliteConnection, email: &str) -> Option<Mail> {
+    let user = User::by_email(conn, email)?;
+    let id = user.id.unwrap_or_default();
+
+    let last_sent = UserToken::last_issued_at(conn, id, TokenPurpose::PasswordReset);
+    if last_sent
+        .is_some_and(|at| at + auth::verification_resend_interval() > Utc::now().naive_utc())
+    {
+        info!("Password reset mail to user {id} skipped, the last one was sent moments ago");
+        return None;
+    }
+
+    let lifetime = auth::password_reset_token_lifetime();
+    match UserToken::issue(conn, id, TokenPurpose::PasswordReset, lifetime) {
+        Ok(token) => Some(Mail::password_reset(&user, &token)),
+        Err(e) => {
+            error!("Could not issue password reset token: {e}");
+            None
+        }
+    }
+}
+
+#[derive(JsonSchema, Serialize, Deserialize, Validate)]
+pub struct ResetPassword {
+    /// Token from the password reset mail
+    pub token: String,
+    /// At least 8 characters with a letter and a digit
+    #[validate(custom(function = "validation::password"))]
+    pub password: String,
+}
+
+impl fmt::Debug for ResetPassword {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("ResetPassword")
+            .field("token", &REDACTED)
+            .field("password", &REDACTED)
+            .finish()
+    }
+}
+
+#[allow(clippy::missing_errors_doc)]
+#[openapi(tag = "Password")]
+#[post("/password/reset", data = "<reset>")]
+/// Set a new password with the token of a password reset mail
+///
+/// Logs the user out on every device
+pub fn post_password_reset(
+    reset: JsonBody<ResetPassword>,
+    mut db: DbConn,
+) -> Result<Status, ApiError> {
+    info!("POST /password/reset");
+
+    reset.validate()?;
+
+    db.immediate_transaction(|conn| {
+        let Some(user_id) = UserToken::consume(conn, &reset.token, TokenPurpose::PasswordReset)?
+        else {
+            return Err(ApiError::new(
+                ErrorCode::InvalidToken,
+                "Password reset token is invalid, expired or has already been used",
+            ));
+        };
+
+        User::change_password(conn, user_id, &reset.password, None)?;
+        info!("Password reset for user {user_id}");
+        Ok(Status::NoContent)
+    })
+}
+
+#[derive(JsonSchema, Serialize, Deserialize, Validate)]
+pub struct ChangePassword {
+    pub current_password: String,
+    /// At least 8 characters with a letter and a digit
+    #[validate(custom(function = "validation::password"))]
+    pub new_password: String,
+}
+
+impl fmt::Debug for ChangePassword {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("ChangePassword")
+            .field("current_password", &REDACTED)
+            .field("new_password", &REDACTED)
+            .finish()
+    }
+}
+
+#[allow(clippy::missing_errors_doc)]
+#[openapi(tag = "Password")]
+#[post("/me/password", data = "<change>")]
+/// Change the password of the current user, requires the current password
+///
+/// Logs out all other sessions of the user, the current one stays valid
+pub fn post_me_password(
+    change: JsonBody<ChangePassword>,
+    access: SessionUser,
+    mut db: DbConn,
+) -> Result<Status, ApiError> {
+    let user = access.user;
+    info!("POST /me/password called by user: {user:?}");
+
+    change.validate()?;
+
+    if !auth::verify_password(&change.current_password, &user.password) {
+        let field = FieldError {
+            field: "current_password".to_string(),
+            code: "wrong_password".to_string(),
+            message: "is wrong".to_string(),
+        };
+        return Err(ApiError::invalid("Invalid fields: current_password")
+            .with_details(json!({ "fields": [field] })));
+    }
+
+    match User::change_password(
+        &mut db,
+        user.id.unwrap_or_default(),
+        &change.new_password,
+        Some(access.claims.sid),
+    ) {
+        Ok(()) => Ok(Status::NoContent),
+        Err(e) => Err(e.into()),
+    }
+}
@@ -0,0 +1,214 @@
+use std::fmt;
+
+use chrono::Utc;
+use rocket::serde::json::Json;
+use rocket_http::Status;
+use rocket_okapi::okapi::schemars::{self, JsonSchema};
+use rocket_okapi::openapi;
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use validator::Validate;
+
+use crate::{
+    auth::{self, SessionUser},
+    db::DbConn,
+    error::{ApiError, ErrorCode, FieldError, JsonBody},
+    mail::{self, Mail},
+    models::{
+        booking::Booking,
+        preferences::{Language, Preferences, Theme},
+        session::Session,
+        user::{PublicUser, User, REDACTED},
+    },
+    routes::{login::send_verification_mail, users::email_taken},
+};
+
+/// The current user with their preferences
+#[derive(JsonSchema, Serialize, Deserialize, Debug)]
+pub struct Profile {
+    #[serde(flatten)]
+    pub user: PublicUser,
+    pub preferences: Preferences,
+}
+
+/// Fields to change, omitted fields stay unchanged
+///
+/// Unknown fields are rejected, in particular `is_admin` can only be changed by admins
+/// with `PUT /users/<id>`
+#[derive(JsonSchema, Serialize, Deserialize, Validate)]
+#[serde(deny_unknown_fields)]
+pub struct PatchProfile {
+    #[validate(length(min = 1, max = 100, message = "must be between 1 and 100 characters"))]
+    pub first_name: Option<String>,
+    #[validate(length(min = 1, max = 100, message = "must be between 1 and 100 characters"))]
+    pub last_name: Option<String>,
+    /// Has to be verified again with the link mailed to the new address
+    #[validate(email(message = "must be a valid email address"))]
+    pub email: Option<String>,
+    /// Required to change the email address
+    pub current_password: Option<String>,
+    pub preferences: Option<PatchPreferences>,
+}
+
+impl fmt::Debug for PatchProfile {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("PatchProfile")
+            .field("first_name", &self.first_name)
+            .field("last_name", &self.last_name)
+            .field("email", &self.email)
+            .field(
+                "current_password",
+                &self.current_password.as_ref().map(|_| REDACTED),
+            )
+            .field("preferences", &self.preferences)
+            .finish()
+    }
+}
+
+#[derive(JsonSchema, Serialize, Deserialize, Debug)]
+#[serde(deny_unknown_fields)]
+pub struct PatchPreferences {
+    pub language: Option<Language>,
+    pub theme: Option<Theme>,
+}
+
+#[derive(JsonSchema, Serialize, Deserialize)]
+pub struct CloseAccount {
+    pub current_password: String,
+}
+
+impl fmt::Debug for CloseAccount {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("CloseAccount")
+            .field("current_password", &REDACTED)
+            .finish()
+    }
+}
+
+/// 422 unless `password` is the password of the user
+fn check_password(user: &User, password: Option<&str>) -> Result<(), ApiError> {
+    let (code, message) = match password {
+        None => ("required", "is required"),
+        Some(p) if !auth::verify_password(p, &user.password) => ("wrong_password", "is wrong"),
+        Some(_) => return Ok(()),
+    };
+    let field = FieldError {
+        field: "current_password".to_string(),
+        code: code.to_string(),
+        message: message.to_string(),
+    };
+    Err(ApiError::invalid("Invalid fields: current_password")
+        .with_details(json!({ "fields": [field] })))
+}
+
+#[openapi(tag = "Profile")]
+#[get("/me")]
+/// Profile and preferences of the current user
+pub fn get_me(access: SessionUser, mut db: DbConn) -> Json<Profile> {
+    let user = access.user;
+    info!("GET /me called by user: {user:?}");
+
+    let preferences = Preferences::by_user_id(&mut db, user.id.unwrap_or_default());
+    Json(Profile {
+        user: user.into(),
+        preferences,
+    })
+}
+
+#[allow(clippy::missing_errors_doc)]
+#[openapi(tag = "Profile")]
+#[patch("/me", data = "<patch>")]
+/// Change name, email address and preferences of the current user
+///
+/// Changing the email address requires `current_password`. The new address counts as
+/// unverified until the user follows the link mailed to it, and the previous address is
+/// notified of the change
+pub fn patch_me(
+    patch: JsonBody<PatchProfile>,
+    access: SessionUser,
+    mut db: DbConn,
+) -> Result<Json<Profile>, ApiError> {
+    let user = access.user;
+    info!("PATCH /me {patch:?} called by user: {user:?}");
+
+    patch.validate()?;
+
+    let id = user.id.unwrap_or_default();
+    let email = patch.email.as_deref().unwrap_or(&user.email);
+    let email_changed = email != user.email;
+    if email_changed {
+        check_password(&user, patch.current_password.as_deref())?;
+    }
+
+    let (updated, preferences) = db.immediate_transaction(|conn| {
+        if email_changed && User::by_email(conn, email).is_some() {
+            return Err(email_taken());
+        }
+
+        let updated = User::update_profile(
+            conn,
+            &user,
+            patch.first_name.as_deref().unwrap_or(&user.first_name),
+            patch.last_name.as_deref().unwrap_or(&user.last_name),
+            email,
+        )?;
+
+        let mut preferences = Preferences::by_user_id(conn, id);
+        if let Some(patch) = &patch.preferences {
+            preferences.language = patch.language.unwrap_or(preferences.language);
+            preferences.theme = patch.theme.unwrap_or(preferences.theme);
+            preferences.save(conn, id)?;
+        }
+        Ok::<_, ApiError>((updated, preferences))
+    })?;
+
+    if email_changed {
+        info!("Email of user {id} changed from {} to {email}", user.email);
+        if let Err(e) = mail::send(&Mail::email_changed(&user, email)) {
+            error!("Could not send email change notice to {}: {e}", user.email);
+        }
+        send_verification_mail(&mut db, &updated);
+    }
+
+    Ok(Json(Profile {
+        user: updated.into(),
+        preferences,
+    }))
+}
+
+#[allow(clippy::missing_errors_doc)]
+#[openapi(tag = "Profile")]
+#[delete("/me", data = "<close>")]
+/// Close the account of the current user, requires the current password
+///
+/// Upcoming pending and approved bookings are cancelled and all sessions are logged out.
+/// Returns 409 for the last admin, another user has to be made admin first
+pub fn delete_me(
+    close: JsonBody<CloseAccount>,
+    access: SessionUser,
+    mut db: DbConn,
+) -> Result<Status, ApiError> {
+    let user = access.user;
+    info!("DELETE /me called by user: {user:?}");
+
+    check_password(&user, Some(&close.current_password))?;
+
+    let id = user.id.unwrap_or_default();
+    db.immediate_transaction(|conn| {
+        if user.is_admin.unwrap_or_default() && User::admin_count(conn) <= 1 {
+            return Err(ApiError::new(
+                ErrorCode::Conflict,
+                "You are the last admin, make another user an admin before closing your account",
+            ));
+        }
+
+        let today = Utc::now().date_naive();
+        let cancelled =
+            Booking::cancel_upcoming_by_user_id(conn, id, today, "The account was closed")?;
+        if !Session::revoke_all(conn, id) || !User::delete(conn, id) {
+            return Err(ApiError::internal());
+        }
+        info!("User {id} closed their account, {cancelled} bookings cancelled");
+        Ok(Status::NoContent)
+    })
+}
@@ -1,23 +1,190 @@
+use chrono::Utc;
 use rocket::serde::json::Json;
 use rocket_http::Status;
 use rocket_okapi::openapi;
+use serde_json::json;
+use validator::Validate;
 
 use crate::{
-    auth::{self, Token},
-    models::room::RoomResponse,
+    auth::{permissions::ManageRooms, AuthUser, RequirePermission},
+    db::DbConn,
+    error::{ApiError, ErrorCode, JsonBody},
+    models::{
+        availability::DayAvailability,
+        booking::{parse_date, parse_time_range, Booking, CLOSING_TIME, OPENING_TIME},
+        room::{Amenity, Room, RoomRequest, RoomResponse},
+    },
 };
 
 #[allow(clippy::missing_errors_doc, clippy::module_name_repetitions)]
 #[openapi(tag = "Rooms")]
-#[get("/rooms")]
+#[get("/rooms?<min_capacity>&<amenity>")]
 /// Only accessible by authenticated users
-pub fn get_rooms(token: Token) -> Result<Json<Vec<RoomResponse>>, Status> {
-    let Some(user) = auth::user_from_token(token.0) else {
-        return Err(Status::Unauthorized);
-    };
-    info!("GET /rooms called by user: {user:?}");
+///
+/// Optionally only rooms with at least `min_capacity` seats and all given amenities,
+/// `amenity` can be repeated. Rooms without a capacity count as unlimited
+pub fn get_rooms(
+    access: AuthUser,
+    mut db: DbConn,
+    min_capacity: Option<i32>,
+    amenity: Vec<Amenity>,
+) -> Result<Json<Vec<RoomResponse>>, ApiError> {
+    let user = access.user;
+    info!("GET /rooms {min_capacity:?} {amenity:?} called by user: {user:?}");
 
-    let res = RoomResponse::new();
+    let res = RoomResponse::filtered(&mut db, min_capacity, &amenity);
 
     Ok(Json(res))
 }
+
+#[allow(clippy::missing_errors_doc, clippy::module_name_repetitions)]
+#[openapi(tag = "Rooms")]
+#[get("/rooms/availability?<date>&<from>&<to>&<until>")]
+/// Only accessible by authenticated users
+///
+/// Free rooms and free time slots per room on `date` (YYYY-MM-DD) between `from` and `to` (HH:MM),
+/// defaults to the opening hours 08:00-17:00
+///
+/// Set `until` (YYYY-MM-DD) to get one entry per day up to and including that date, at most 31 days
+pub fn get_room_availability(
+    access: AuthUser,
+    mut db: DbConn,
+    date: &str,
+    from: Option<&str>,
+    to: Option<&str>,
+    until: Option<&str>,
+) -> Result<Json<Vec<DayAvailability>>, ApiError> {
+    let user = access.user;
+    info!(
+        "GET /rooms/availability {date} {from:?}-{to:?} until {until:?} called by user: {user:?}"
+    );
+
+    let Some(date) = parse_date(date) else {
+        return Err(ApiError::invalid("date must be YYYY-MM-DD"));
+    };
+    let until = match until {
+        Some(u) => match parse_date(u) {
+            Some(u) => u,
+            None => return Err(ApiError::invalid("until must be YYYY-MM-DD")),
+        },
+        None => date,
+    };
+    let Some((from, to)) =
+        parse_time_range(from.unwrap_or(OPENING_TIME), to.unwrap_or(CLOSING_TIME))
+    else {
+        return Err(ApiError::invalid(
+            "from and to must be HH:MM with to after from",
+        ));
+    };
+
+    match DayAvailability::new(&mut db, date, until, &from, &to) {
+        Some(grid) => Ok(Json(grid)),
+        None => Err(ApiError::invalid(
+            "until must not be before date and at most 31 days after it",
+        )),
+    }
+}
+
+#[allow(clippy::missing_errors_doc, clippy::module_name_repetitions)]
+#[openapi(tag = "Rooms")]
+#[get("/rooms/<id>")]
+/// Only accessible by authenticated users
+pub fn get_room(id: i32, access: AuthUser, mut db: DbConn) -> Result<Json<RoomResponse>, ApiError> {
+    let user = access.user;
+    info!("GET /rooms/{id:?} called by user: {user:?}");
+
+    match RoomResponse::by_id(&mut db, id) {
+        Some(r) => Ok(Json(r)),
+        None => Err(ApiError::not_found("Room")),
+    }
+}
+
+#[allow(clippy::missing_errors_doc, clippy::module_name_repetitions)]
+#[openapi(tag = "Rooms")]
+#[post("/rooms", data = "<post_room>")]
+/// Requires the `manage_rooms` permission
+pub fn post_rooms(
+    post_room: JsonBody<RoomRequest>,
+    access: RequirePermission<ManageRooms>,
+    mut db: DbConn,
+) -> Result<Json<RoomResponse>, ApiError> {
+    info!(
+        "POST /rooms {post_room:?} called by user: {:?}",
+        access.user
+    );
+
+    post_room.validate()?;
+
+    match Room::create(&mut db, &post_room).and_then(|r| RoomResponse::by_id(&mut db, r.id?)) {
+        Some(r) => Ok(Json(r)),
+        None => Err(ApiError::internal()),
+    }
+}
+
+#[allow(clippy::missing_errors_doc, clippy::module_name_repetitions)]
+#[openapi(tag = "Rooms")]
+#[put("/rooms/<id>", data = "<put_room>")]
+/// Requires the `manage_rooms` permission
+pub fn put_rooms(
+    id: i32,
+    put_room: JsonBody<RoomRequest>,
+    access: RequirePermission<ManageRooms>,
+    mut db: DbConn,
+) -> Result<Json<RoomResponse>, ApiError> {
+    info!(
+        "PUT /rooms/{id:?} {put_room:?} called by user: {:?}",
+        access.user
+    );
+
+    if Room::by_id(&mut db, id).is_none() {
+        return Err(ApiError::not_found("Room"));
+    }
+
+    put_room.validate()?;
+
+    match Room::update(&mut db, id, &put_room).and_then(|_| RoomResponse::by_id(&mut db, id)) {
+        Some(r) => Ok(Json(r)),
+        None => Err(ApiError::internal()),
+    }
+}
+
+#[allow(clippy::missing_errors_doc, clippy::module_name_repetitions)]
+#[openapi(tag = "Rooms")]
+#[delete("/rooms/<id>?<cascade>")]
+/// Requires the `manage_rooms` permission
+///
+/// Refuses with 409 `room_has_bookings` if the room has bookings today or later, set `cascade` to
+/// delete those bookings together with the room. Past, rejected and cancelled bookings are
+/// always deleted
+pub fn delete_rooms(
+    id: i32,
+    cascade: Option<bool>,
+    access: RequirePermission<ManageRooms>,
+    mut db: DbConn,
+) -> Result<Status, ApiError> {
+    info!(
+        "DELETE /rooms/{id:?} cascade {cascade:?} called by user: {:?}",
+        access.user
+    );
+
+    if Room::by_id(&mut db, id).is_none() {
+        return Ok(Status::NoContent);
+    }
+
+    let today = Utc::now().date_naive();
+    let upcoming = Booking::upcoming_by_room_id(&mut db, id, today);
+    if !cascade.unwrap_or_default() && !upcoming.is_empty() {
+        let ids: Vec<_> = upcoming.iter().filter_map(|b| b.id).collect();
+        return Err(ApiError::new(
+            ErrorCode::RoomHasBookings,
+            "The room has upcoming bookings, set cascade to delete them",
+        )
+        .with_details(json!({ "booking_ids": ids })));
+    }
+
+    if Room::delete(&mut db, id) {
+        Ok(Status::NoContent)
+    } else {
+        Err(ApiError::internal())
+    }
+}
@@ -0,0 +1,48 @@
+use rocket::serde::json::Json;
+use rocket_okapi::openapi;
+
+use crate::{
+    auth::AdminUser,
+    db::DbConn,
+    error::{ApiError, ErrorCode, JsonBody},
+    models::{setting::Settings, two_factor::Totp},
+};
+
+#[allow(clippy::missing_errors_doc)]
+#[openapi(tag = "Settings")]
+#[get("/settings")]
+/// Only accessible by super admins
+pub fn get_settings(access: AdminUser, mut db: DbConn) -> Result<Json<Settings>, ApiError> {
+    info!("GET /settings called by user: {:?}", access.user);
+
+    Ok(Json(Settings::load(&mut db)))
+}
+
+#[allow(clippy::missing_errors_doc)]
+#[openapi(tag = "Settings")]
+#[put("/settings", data = "<settings>")]
+/// Only accessible by super admins
+///
+/// Enabling `require_admin_2fa` returns 409 unless the admin enabled two-factor
+/// authentication themselves, they would lock themselves out otherwise
+pub fn put_settings(
+    settings: JsonBody<Settings>,
+    access: AdminUser,
+    mut db: DbConn,
+) -> Result<Json<Settings>, ApiError> {
+    info!(
+        "PUT /settings {settings:?} called by user: {:?}",
+        access.user
+    );
+
+    let id = access.user.id.unwrap_or_default();
+    if settings.require_admin_2fa && !Totp::is_enabled(&mut db, id) {
+        return Err(ApiError::new(
+            ErrorCode::Conflict,
+            "Enable two-factor authentication at /me/2fa/totp before requiring it for admins",
+        ));
+    }
+
+    settings.save(&mut db)?;
+    Ok(Json(Settings::load(&mut db)))
+}
@@ -0,0 +1,254 @@
+use std::fmt;
+
+use diesel::{QueryResult, SqliteConnection};
+use rocket::serde::json::Json;
+use rocket_http::Status;
+use rocket_okapi::okapi::schemars::{self, JsonSchema};
+use rocket_okapi::openapi;
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+
+use crate::{
+    auth::{self, SessionUser},
+    db::DbConn,
+    error::{ApiError, ErrorCode, FieldError, JsonBody},
+    models::{
+        login_throttle::{LoginThrottle, ThrottleKey},
+        two_factor::{self, RecoveryCode, Totp},
+        user::{User, REDACTED},
+    },
+    routes::login::throttled_error,
+};
+
+#[derive(JsonSchema, Serialize, Deserialize, Debug)]
+pub struct TwoFactorStatus {
+    /// Whether a code is asked for after the password when logging in
+    pub enabled: bool,
+    /// Unused recovery codes, regenerate them when few are left
+    pub recovery_codes_left: i64,
+    /// Whether the user needs two-factor authentication to use their permissions and roles
+    pub required: bool,
+}
+
+#[derive(JsonSchema, Serialize, Deserialize)]
+pub struct TotpEnrollment {
+    /// Base32 encoded secret, for authenticator apps that can not scan the URI
+    pub secret: String,
+    /// `otpauth://` URI, usually shown as QR code
+    pub otpauth_uri: String,
+}
+
+impl fmt::Debug for TotpEnrollment {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("TotpEnrollment")
+            .field("secret", &REDACTED)
+            .field("otpauth_uri", &REDACTED)
+            .finish()
+    }
+}
+
+#[derive(JsonSchema, Serialize, Deserialize)]
+pub struct TwoFactorCode {
+    /// Current code of the authenticator app, or an unused recovery code where noted
+    pub code: String,
+}
+
+impl fmt::Debug for TwoFactorCode {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("TwoFactorCode")
+            .field("code", &REDACTED)
+            .finish()
+    }
+}
+
+#[derive(JsonSchema, Serialize, Deserialize)]
+pub struct RecoveryCodes {
+    /// One-time codes to log in without the authenticator app, only shown once
+    pub recovery_codes: Vec<String>,
+}
+
+impl fmt::Debug for RecoveryCodes {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("RecoveryCodes")
+            .field("recovery_codes", &self.recovery_codes.len())
+            .finish()
+    }
+}
+
+fn wrong_code() -> ApiError {
+    let field = FieldError {
+        field: "code".to_string(),
+        code: "wrong_code".to_string(),
+        message: "is wrong".to_string(),
+    };
+    ApiError::invalid("Invalid fields: code").with_details(json!({ "fields": [field] }))
+}
+
+/// Wrong codes count as failed logins of the account, like at `POST /login/2fa`, so a
+/// stolen session can't guess codes
+fn verify_throttled(
+    conn: &mut SqliteConnection,
+    user: &User,
+    verify: impl FnOnce(&mut SqliteConnection) -> QueryResult<bool>,
+) -> Result<(), ApiError> {
+    let account = [ThrottleKey::account(&user.email)];
+    if let Some(throttled) = LoginThrottle::check(conn, &account) {
+        return Err(throttled_error(throttled));
+    }
+
+    if !verify(conn)? {
+        warn!("Wrong two-factor code for user {:?}", user.id);
+        LoginThrottle::record_failure(conn, &account)?;
+        return Err(wrong_code());
+    }
+    LoginThrottle::reset(conn, &account[0])?;
+    Ok(())
+}
+
+fn not_enabled() -> ApiError {
+    ApiError::new(
+        ErrorCode::NotFound,
+        "Two-factor authentication is not enabled",
+    )
+}
+
+#[openapi(tag = "Two-factor authentication")]
+#[get("/me/2fa")]
+/// Two-factor authentication status of the current user
+pub fn get_me_2fa(access: SessionUser, mut db: DbConn) -> Json<TwoFactorStatus> {
+    let user = access.user;
+    info!("GET /me/2fa called by user: {user:?}");
+
+    let id = user.id.unwrap_or_default();
+    let enabled = Totp::is_enabled(&mut db, id);
+    Json(TwoFactorStatus {
+        enabled,
+        recovery_codes_left: if enabled {
+            RecoveryCode::remaining(&mut db, id)
+        } else {
+            0
+        },
+        required: auth::two_factor_required(&mut db, &user),
+    })
+}
+
+#[allow(clippy::missing_errors_doc)]
+#[openapi(tag = "Two-factor authentication")]
+#[post("/me/2fa/totp")]
+/// Start enrolling an authenticator app with a new secret
+///
+/// Two-factor authentication is only enabled once a code has been confirmed at
+/// `/me/2fa/totp/confirm`, starting again replaces the unconfirmed secret.
+/// Returns 409 if it is already enabled
+pub fn post_me_2fa_totp(
+    access: SessionUser,
+    mut db: DbConn,
+) -> Result<Json<TotpEnrollment>, ApiError> {
+    let user = access.user;
+    info!("POST /me/2fa/totp called by user: {user:?}");
+
+    let id = user.id.unwrap_or_default();
+    if Totp::is_enabled(&mut db, id) {
+        return Err(ApiError::new(
+            ErrorCode::Conflict,
+            "Two-factor authentication is already enabled, disable it first",
+        ));
+    }
+
+    let totp = Totp::enroll(&mut db, id)?;
+    let Some(otpauth_uri) = totp.uri(&user.email) else {
+        return Err(ApiError::internal());
+    };
+
+    Ok(Json(TotpEnrollment {
+        secret: totp.secret,
+        otpauth_uri,
+    }))
+}
+
+#[allow(clippy::missing_errors_doc)]
+#[openapi(tag = "Two-factor authentication")]
+#[post("/me/2fa/totp/confirm", data = "<confirm>")]
+/// Enable two-factor authentication with the first code of the authenticator app
+///
+/// Returns the recovery codes, they are not shown again
+pub fn post_me_2fa_totp_confirm(
+    confirm: JsonBody<TwoFactorCode>,
+    access: SessionUser,
+    mut db: DbConn,
+) -> Result<Json<RecoveryCodes>, ApiError> {
+    let user = access.user;
+    info!("POST /me/2fa/totp/confirm called by user: {user:?}");
+
+    let id = user.id.unwrap_or_default();
+    let Some(totp) = Totp::by_user_id(&mut db, id) else {
+        return Err(ApiError::new(
+            ErrorCode::NotFound,
+            "No pending enrollment, start one at /me/2fa/totp",
+        ));
+    };
+    if totp.enabled_at.is_some() {
+        return Err(ApiError::new(
+            ErrorCode::Conflict,
+            "Two-factor authentication is already enabled",
+        ));
+    }
+    verify_throttled(&mut db, &user, |conn| totp.verify(conn, &confirm.code))?;
+
+    let recovery_codes = Totp::enable(&mut db, id)?;
+    info!("Two-factor authentication enabled for user {id}");
+    Ok(Json(RecoveryCodes { recovery_codes }))
+}
+
+#[allow(clippy::missing_errors_doc)]
+#[openapi(tag = "Two-factor authentication")]
+#[post("/me/2fa/recovery-codes", data = "<confirm>")]
+/// Replace all recovery codes, requires a current code of the authenticator app
+///
+/// Wrong codes count as failed logins, 429 after too many
+pub fn post_me_2fa_recovery_codes(
+    confirm: JsonBody<TwoFactorCode>,
+    access: SessionUser,
+    mut db: DbConn,
+) -> Result<Json<RecoveryCodes>, ApiError> {
+    let user = access.user;
+    info!("POST /me/2fa/recovery-codes called by user: {user:?}");
+
+    let id = user.id.unwrap_or_default();
+    let Some(totp) = Totp::by_user_id(&mut db, id).filter(|t| t.enabled_at.is_some()) else {
+        return Err(not_enabled());
+    };
+    verify_throttled(&mut db, &user, |conn| totp.verify(conn, &confirm.code))?;
+
+    let recovery_codes = RecoveryCode::regenerate(&mut db, id)?;
+    Ok(Json(RecoveryCodes { recovery_codes }))
+}
+
+#[allow(clippy::missing_errors_doc)]
+#[openapi(tag = "Two-factor authentication")]
+#[post("/me/2fa/disable", data = "<confirm>")]
+/// Disable two-factor authentication, requires a current code or a recovery code
+///
+/// Wrong codes count as failed logins, 429 after too many.
+/// Users with a permission can not use it afterwards while the admin policy of
+/// `GET /settings` requires two-factor authentication
+pub fn post_me_2fa_disable(
+    confirm: JsonBody<TwoFactorCode>,
+    access: SessionUser,
+    mut db: DbConn,
+) -> Result<Status, ApiError> {
+    let user = access.user;
+    info!("POST /me/2fa/disable called by user: {user:?}");
+
+    let id = user.id.unwrap_or_default();
+    if !Totp::is_enabled(&mut db, id) {
+        return Err(not_enabled());
+    }
+    verify_throttled(&mut db, &user, |conn| {
+        two_factor::verify_code(conn, id, &confirm.code)
+    })?;
+
+    Totp::disable(&mut db, id)?;
+    info!("Two-factor authentication disabled for user {id}");
+    Ok(Status::NoContent)
+}
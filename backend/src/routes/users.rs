@@ -1,109 +1,266 @@
+use std::net::IpAddr;
+
+use chrono::Utc;
 use rocket::serde::json::Json;
 use rocket_http::Status;
 use rocket_okapi::openapi;
 
+use rocket_okapi::okapi::schemars::{self, JsonSchema};
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use validator::Validate;
+
 use crate::{
-    auth::{self, Token},
-    models::user::{PostUser, PutUser, User},
+    auth::{permissions::ManageUsers, AdminUser, AuthUser, RequirePermission},
+    db::DbConn,
+    error::{ApiError, ErrorCode, JsonBody},
+    models::{
+        booking::Booking,
+        login_throttle::{LoginThrottle, ThrottleKey},
+        role::{Role, RoleResponse, SetRolesError, SUPER_ADMIN},
+        session::Session,
+        user::{PostUser, PublicUser, PutUser, User},
+    },
 };
 
 #[allow(clippy::missing_errors_doc, clippy::module_name_repetitions)]
 #[openapi(tag = "Users")]
 #[get("/users")]
-/// Only accessible by admins
-pub fn get_users(token: Token) -> Result<Json<Vec<User>>, Status> {
-    let Some(user) = auth::user_from_token(token.0) else {
-        return Err(Status::Unauthorized);
-    };
+/// Requires the `manage_users` permission
+pub fn get_users(
+    access: RequirePermission<ManageUsers>,
+    mut db: DbConn,
+) -> Result<Json<Vec<PublicUser>>, ApiError> {
+    info!("GET /users called by user: {:?}", access.user);
 
-    info!("GET /users called by user: {user:?}");
-
-    if user.is_admin.unwrap_or_default() {
-        Ok(Json(User::all()))
-    } else {
-        Err(Status::Unauthorized)
-    }
+    Ok(Json(
+        User::all(&mut db)
+            .into_iter()
+            .map(PublicUser::from)
+            .collect(),
+    ))
 }
 
 #[allow(clippy::missing_errors_doc, clippy::module_name_repetitions)]
 #[openapi(tag = "Users")]
 #[post("/users", data = "<post_user>")]
-/// Only accessible by admins
-pub fn post_users(post_user: Json<PostUser>, token: Token) -> Result<Json<User>, Status> {
-    let Some(user) = auth::user_from_token(token.0) else {
-        return Err(Status::Unauthorized);
-    };
-    info!("POST /users called by user: {user:?}");
+/// Requires the `manage_users` permission, creating an admin also the `super_admin` role
+///
+/// The email address of users created here counts as verified
+pub fn post_users(
+    post_user: JsonBody<PostUser>,
+    access: RequirePermission<ManageUsers>,
+    auth: AuthUser,
+    mut db: DbConn,
+) -> Result<Json<PublicUser>, ApiError> {
+    info!("POST /users called by user: {:?}", access.user);
 
-    if !user.is_admin.unwrap_or_default() {
-        return Err(Status::Unauthorized);
+    post_user.validate()?;
+
+    if post_user.is_admin {
+        auth.require_role(&mut db, SUPER_ADMIN)?;
     }
 
-    if User::by_email(&post_user.email).is_some() {
-        return Err(Status::Conflict);
+    if User::by_email(&mut db, &post_user.email).is_some() {
+        return Err(email_taken());
     }
 
     let new_user = User::create_hash(
+        &mut db,
         post_user.is_admin,
         &post_user.first_name,
         &post_user.last_name,
         &post_user.email,
         &post_user.password,
+        true,
     );
 
     match new_user {
-        Some(u) => Ok(Json(u)),
-        None => Err(Status::InternalServerError),
+        Some(u) => Ok(Json(u.into())),
+        None => Err(ApiError::internal()),
     }
 }
 
 #[allow(clippy::missing_errors_doc, clippy::module_name_repetitions)]
 #[openapi(tag = "Users")]
 #[put("/users/<id>", data = "<put_user>")]
-/// Only accessible by admins
-pub fn put_users(id: i32, put_user: Json<PutUser>, token: Token) -> Result<Json<User>, Status> {
-    let Some(user) = auth::user_from_token(token.0) else {
-        return Err(Status::Unauthorized);
-    };
-    info!("PATCH /users called by user: {user:?}");
+/// Requires the `manage_users` permission, changing `is_admin` also the `super_admin` role
+///
+/// Returns 409 if the last admin would lose `is_admin`
+pub fn put_users(
+    id: i32,
+    put_user: JsonBody<PutUser>,
+    access: RequirePermission<ManageUsers>,
+    auth: AuthUser,
+    mut db: DbConn,
+) -> Result<Json<PublicUser>, ApiError> {
+    info!("PUT /users/{id} called by user: {:?}", access.user);
 
-    if !user.is_admin.unwrap_or_default() {
-        return Err(Status::Unauthorized);
-    }
+    put_user.validate()?;
+
+    let Some(user) = User::by_id(&mut db, id) else {
+        return Err(ApiError::not_found("User"));
+    };
 
-    if User::by_id(id).is_none() {
-        return Err(Status::NotFound);
+    let was_admin = user.is_admin.unwrap_or_default();
+    if put_user.is_admin != was_admin {
+        auth.require_role(&mut db, SUPER_ADMIN)?;
     }
 
-    let updated = User::update(id, &put_user.0);
+    let updated = db.immediate_transaction(|conn| {
+        if was_admin && !put_user.is_admin && User::admin_count(conn) <= 1 {
+            return Err(last_admin());
+        }
+        User::update(conn, id, &put_user).ok_or_else(ApiError::internal)
+    })?;
 
-    match updated {
-        Some(u) => Ok(Json(u)),
-        None => Err(Status::InternalServerError),
-    }
+    Ok(Json(updated.into()))
 }
 
 #[allow(clippy::missing_errors_doc, clippy::module_name_repetitions)]
 #[openapi(tag = "Users")]
 #[delete("/users/<id>")]
-/// Only accessible by admins
-pub fn delete_users(id: i32, token: Token) -> Result<Status, Status> {
-    let Some(user) = auth::user_from_token(token.0) else {
-        return Err(Status::Unauthorized);
+/// Requires the `manage_users` permission, deleting an admin also the `super_admin` role
+///
+/// Upcoming pending and approved bookings of the user are cancelled and all sessions are
+/// logged out. Returns 409 for the last admin
+pub fn delete_users(
+    id: i32,
+    access: RequirePermission<ManageUsers>,
+    auth: AuthUser,
+    mut db: DbConn,
+) -> Result<Status, ApiError> {
+    info!("DELETE /users/{id} called by user: {:?}", access.user);
+
+    let Some(user) = User::by_id(&mut db, id) else {
+        return Ok(Status::NoContent);
     };
-    info!("PATCH /users called by user: {user:?}");
 
-    if !user.is_admin.unwrap_or_default() {
-        return Err(Status::Unauthorized);
+    let is_admin = user.is_admin.unwrap_or_default();
+    if is_admin {
+        auth.require_role(&mut db, SUPER_ADMIN)?;
     }
 
-    if User::by_id(id).is_none() {
-        return Ok(Status::NoContent);
-    }
+    db.immediate_transaction(|conn| {
+        if is_admin && User::admin_count(conn) <= 1 {
+            return Err(last_admin());
+        }
 
-    if User::delete(id) {
+        let today = Utc::now().date_naive();
+        let cancelled =
+            Booking::cancel_upcoming_by_user_id(conn, id, today, "The account was deleted")?;
+        if !Session::revoke_all(conn, id) || !User::delete(conn, id) {
+            return Err(ApiError::internal());
+        }
+        info!("User {id} deleted, {cancelled} bookings cancelled");
         Ok(Status::NoContent)
-    } else {
-        Err(Status::InternalServerError)
+    })
+}
+
+#[allow(clippy::missing_errors_doc, clippy::module_name_repetitions)]
+#[openapi(tag = "Users")]
+#[post("/users/<id>/unlock?<ip>")]
+/// Requires the `manage_users` permission
+///
+/// Unlocks an account locked after too many failed logins and forgets its failures.
+/// Addresses are locked separately, pass the address the user logs in from as `ip` to
+/// unlock it too, otherwise it stays locked until `LOGIN_LOCKOUT_MINUTES` have passed
+pub fn unlock_users(
+    id: i32,
+    ip: Option<&str>,
+    access: RequirePermission<ManageUsers>,
+    mut db: DbConn,
+) -> Result<Status, ApiError> {
+    info!(
+        "POST /users/{id}/unlock ip {ip:?} called by user: {:?}",
+        access.user
+    );
+
+    let ip = match ip.map(str::parse::<IpAddr>) {
+        Some(Ok(ip)) => Some(ip),
+        Some(Err(_)) => return Err(ApiError::invalid("ip must be an IPv4 or IPv6 address")),
+        None => None,
+    };
+    let Some(user) = User::by_id(&mut db, id) else {
+        return Err(ApiError::not_found("User"));
+    };
+
+    db.immediate_transaction(|conn| {
+        LoginThrottle::reset(conn, &ThrottleKey::account(&user.email))?;
+        if let Some(ip) = ip {
+            LoginThrottle::reset(conn, &ThrottleKey::Ip(ip))?;
+        }
+        Ok::<_, ApiError>(())
+    })?;
+    Ok(Status::NoContent)
+}
+
+#[allow(clippy::missing_errors_doc, clippy::module_name_repetitions)]
+#[openapi(tag = "Users")]
+#[get("/roles")]
+/// Requires the `manage_users` permission
+///
+/// All roles with the permissions they grant
+pub fn get_roles(
+    access: RequirePermission<ManageUsers>,
+    mut db: DbConn,
+) -> Result<Json<Vec<RoleResponse>>, ApiError> {
+    info!("GET /roles called by user: {:?}", access.user);
+
+    Ok(Json(Role::all(&mut db)))
+}
+
+#[derive(JsonSchema, Serialize, Deserialize, Debug)]
+pub struct PutRoles {
+    /// Names of the roles, replaces all current roles of the user
+    pub roles: Vec<String>,
+}
+
+#[allow(clippy::missing_errors_doc, clippy::module_name_repetitions)]
+#[openapi(tag = "Users")]
+#[put("/users/<id>/roles", data = "<put_roles>")]
+/// Only accessible by super admins
+///
+/// Replaces the roles of a user, 422 listing the roles that do not exist.
+/// `is_admin` is set if the user gets the `super_admin` role, 409 if the last
+/// admin would lose it
+pub fn put_user_roles(
+    id: i32,
+    put_roles: JsonBody<PutRoles>,
+    access: AdminUser,
+    mut db: DbConn,
+) -> Result<Json<Vec<String>>, ApiError> {
+    info!(
+        "PUT /users/{id}/roles {put_roles:?} called by user: {:?}",
+        access.user
+    );
+
+    if User::by_id(&mut db, id).is_none() {
+        return Err(ApiError::not_found("User"));
     }
+
+    match db.immediate_transaction(|conn| Role::set_for_user(conn, id, &put_roles.roles)) {
+        Ok(()) => Ok(Json(Role::names_by_user_id(&mut db, id))),
+        Err(SetRolesError::UnknownRole(roles)) => {
+            Err(ApiError::invalid("Unknown role").with_details(json!({ "roles": roles })))
+        }
+        Err(SetRolesError::LastSuperAdmin) => Err(last_admin()),
+        Err(SetRolesError::Database(e)) => Err(e.into()),
+    }
+}
+
+/// 409 if the last admin would lose the `super_admin` role
+fn last_admin() -> ApiError {
+    ApiError::new(
+        ErrorCode::Conflict,
+        "This is the last admin, make another user an admin first",
+    )
+}
+
+/// 409 for an email address that already belongs to a user
+pub fn email_taken() -> ApiError {
+    ApiError::new(
+        ErrorCode::EmailTaken,
+        "A user with this email address already exists",
+    )
 }
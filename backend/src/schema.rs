@@ -1,15 +1,98 @@
 // @generated automatically by Diesel CLI.
 
+diesel::table! {
+    api_key (id) {
+        id -> Nullable<Integer>,
+        fk_user_id -> Integer,
+        name -> Text,
+        key_hash -> Text,
+        key_prefix -> Text,
+        scopes -> Text,
+        expires_at -> Nullable<Timestamp>,
+        last_used_at -> Nullable<Timestamp>,
+        created_at -> Nullable<Timestamp>,
+        revoked_at -> Nullable<Timestamp>,
+    }
+}
+
 diesel::table! {
     booking (id) {
         id -> Nullable<Integer>,
         reason -> Text,
-        duration -> Integer,
         status -> Text,
-        date -> Text,
+        date -> Date,
         fk_room_id -> Integer,
         fk_user_id -> Integer,
         created_at -> Nullable<Timestamp>,
+        fk_series_id -> Nullable<Integer>,
+        start_time -> Text,
+        end_time -> Text,
+        attendees -> Nullable<Integer>,
+        status_comment -> Nullable<Text>,
+    }
+}
+
+diesel::table! {
+    booking_series (id) {
+        id -> Nullable<Integer>,
+        frequency -> Text,
+        until -> Nullable<Date>,
+        occurrences -> Nullable<Integer>,
+        fk_room_id -> Integer,
+        fk_user_id -> Integer,
+        created_at -> Nullable<Timestamp>,
+    }
+}
+
+diesel::table! {
+    login_throttle (key) {
+        key -> Text,
+        failures -> Integer,
+        last_failure_at -> Timestamp,
+        locked_until -> Nullable<Timestamp>,
+    }
+}
+
+diesel::table! {
+    oidc_login (state_hash) {
+        state_hash -> Text,
+        nonce -> Text,
+        pkce_verifier -> Text,
+        expires_at -> Timestamp,
+    }
+}
+
+diesel::table! {
+    recovery_code (id) {
+        id -> Nullable<Integer>,
+        fk_user_id -> Integer,
+        code_hash -> Text,
+        used_at -> Nullable<Timestamp>,
+    }
+}
+
+diesel::table! {
+    refresh_token (id) {
+        id -> Nullable<Integer>,
+        token_hash -> Text,
+        fk_session_id -> Integer,
+        expires_at -> Timestamp,
+        used_at -> Nullable<Timestamp>,
+        created_at -> Nullable<Timestamp>,
+    }
+}
+
+diesel::table! {
+    role (id) {
+        id -> Nullable<Integer>,
+        name -> Text,
+    }
+}
+
+diesel::table! {
+    role_permission (fk_role_id, permission) {
+        fk_role_id -> Integer,
+        permission -> Text,
     }
 }
 
@@ -17,6 +100,42 @@ diesel::table! {
     room (id) {
         id -> Nullable<Integer>,
         name -> Text,
+        capacity -> Nullable<Integer>,
+        floor -> Nullable<Integer>,
+        location -> Nullable<Text>,
+    }
+}
+
+diesel::table! {
+    room_amenity (fk_room_id, amenity) {
+        fk_room_id -> Integer,
+        amenity -> Text,
+    }
+}
+
+diesel::table! {
+    session (id) {
+        id -> Nullable<Integer>,
+        fk_user_id -> Integer,
+        created_at -> Nullable<Timestamp>,
+        revoked_at -> Nullable<Timestamp>,
+    }
+}
+
+diesel::table! {
+    setting (key) {
+        key -> Text,
+        value -> Text,
+    }
+}
+
+diesel::table! {
+    totp (fk_user_id) {
+        fk_user_id -> Integer,
+        secret -> Text,
+        enabled_at -> Nullable<Timestamp>,
+        last_used_step -> Nullable<BigInt>,
+        created_at -> Nullable<Timestamp>,
     }
 }
 
@@ -29,14 +148,82 @@ diesel::table! {
         email -> Text,
         password -> Text,
         created_at -> Nullable<Timestamp>,
+        email_verified_at -> Nullable<Timestamp>,
+    }
+}
+
+diesel::table! {
+    user_identity (issuer, subject) {
+        issuer -> Text,
+        subject -> Text,
+        fk_user_id -> Integer,
+        created_at -> Nullable<Timestamp>,
+    }
+}
+
+diesel::table! {
+    user_preference (fk_user_id) {
+        fk_user_id -> Integer,
+        language -> Text,
+        theme -> Text,
+    }
+}
+
+diesel::table! {
+    user_role (fk_user_id, fk_role_id) {
+        fk_user_id -> Integer,
+        fk_role_id -> Integer,
+    }
+}
+
+diesel::table! {
+    user_token (id) {
+        id -> Nullable<Integer>,
+        token_hash -> Text,
+        purpose -> Text,
+        fk_user_id -> Integer,
+        expires_at -> Timestamp,
+        used_at -> Nullable<Timestamp>,
+        created_at -> Nullable<Timestamp>,
     }
 }
 
+diesel::joinable!(api_key -> user (fk_user_id));
+diesel::joinable!(booking -> booking_series (fk_series_id));
 diesel::joinable!(booking -> room (fk_room_id));
 diesel::joinable!(booking -> user (fk_user_id));
+diesel::joinable!(booking_series -> room (fk_room_id));
+diesel::joinable!(booking_series -> user (fk_user_id));
+diesel::joinable!(recovery_code -> user (fk_user_id));
+diesel::joinable!(refresh_token -> session (fk_session_id));
+diesel::joinable!(role_permission -> role (fk_role_id));
+diesel::joinable!(room_amenity -> room (fk_room_id));
+diesel::joinable!(session -> user (fk_user_id));
+diesel::joinable!(totp -> user (fk_user_id));
+diesel::joinable!(user_identity -> user (fk_user_id));
+diesel::joinable!(user_preference -> user (fk_user_id));
+diesel::joinable!(user_role -> role (fk_role_id));
+diesel::joinable!(user_role -> user (fk_user_id));
+diesel::joinable!(user_token -> user (fk_user_id));
 
 diesel::allow_tables_to_appear_in_same_query!(
+    api_key,
     booking,
+    booking_series,
+    login_throttle,
+    oidc_login,
+    recovery_code,
+    refresh_token,
+    role,
+    role_permission,
     room,
+    room_amenity,
+    session,
+    setting,
+    totp,
     user,
+    user_identity,
+    user_preference,
+    user_role,
+    user_token,
 );
@@ -1,15 +1,10 @@
-use std::{
-    env::{self, args},
-    process::Command,
-};
-
-use diesel::{Connection, SqliteConnection};
+use chrono::NaiveDate;
+use diesel::SqliteConnection;
 use log::LevelFilter;
 use simplelog::{ColorChoice, Config, TermLogger, TerminalMode};
 
 use crate::models::{
     booking::{Booking, SerializeBooking},
-    room::Room,
     user::User,
 };
 
@@ -24,74 +19,133 @@ pub fn setup_logger() {
     .unwrap();
 }
 
-#[allow(clippy::missing_panics_doc)]
-#[must_use]
-pub fn conn() -> SqliteConnection {
-    let url = env::var("DATABASE_URL").expect("DATABASE_URL must be set");
+/// Serde helpers for `created_at` style timestamps, stored as UTC without offset
+/// and serialized as RFC 3339
+pub mod utc_timestamp {
+    use chrono::{DateTime, NaiveDateTime, Utc};
+    use serde::{Deserialize, Deserializer, Serialize, Serializer};
 
-    SqliteConnection::establish(&url).expect("Error connecting to {url}")
-}
-
-pub fn init_data() {
-    let args = args().map(|x| x.to_lowercase()).collect::<Vec<String>>();
-
-    for a in args {
-        if a.contains("clean") {
-            warn!("##################################################");
-            warn!("Running with argument clean");
-            warn!("Cleaning database");
-            info!(
-                "{:?}",
-                Command::new("diesel").args(["migration", "redo"]).output()
-            );
-            warn!("Database cleaned");
-            warn!("##################################################");
-        }
+    #[allow(clippy::missing_errors_doc, clippy::ref_option)]
+    pub fn serialize<S: Serializer>(
+        value: &Option<NaiveDateTime>,
+        serializer: S,
+    ) -> Result<S::Ok, S::Error> {
+        value.map(|v| v.and_utc()).serialize(serializer)
+    }
 
-        if a.contains("test") {
-            warn!("##################################################");
-            warn!("Running with argument test");
-            load_test_data();
-            warn!("Test data loaded");
-            warn!("##################################################");
-        }
+    #[allow(clippy::missing_errors_doc)]
+    pub fn deserialize<'de, D: Deserializer<'de>>(
+        deserializer: D,
+    ) -> Result<Option<NaiveDateTime>, D::Error> {
+        Ok(Option::<DateTime<Utc>>::deserialize(deserializer)?.map(|v| v.naive_utc()))
     }
 }
 
 #[allow(clippy::missing_panics_doc)]
-pub fn load_test_data() {
-    warn!("Deleting all data");
-    info!(
-        "{:?}",
-        Command::new("diesel").args(["migration", "redo"]).output()
-    );
+pub fn load_test_data(conn: &mut SqliteConnection) {
     info!("Loading test users");
     info!(
         "{:?}",
-        User::create_hash(true, "Admin", "Adminname", "admin", "admin")
+        User::create_hash(conn, true, "Admin", "Adminname", "admin", "admin", true)
     );
     info!(
         "{:?}",
-        User::create_hash(false, "User", "Username", "user", "user")
+        User::create_hash(conn, false, "User", "Username", "user", "user", true)
     );
     warn!("Test users loaded");
 
     // warn!("Loading test rooms");
-    // info!("{:?}", Room::create("Room 1"));
-    // info!("{:?}", Room::create("Room 2"));
+    // info!("{:?}", Room::create(conn, "Room 1"));
+    // info!("{:?}", Room::create(conn, "Room 2"));
     // warn!("Test rooms loaded");
 
     warn!("Loading test bookings");
-    let b = Booking::create("Reason 1", 0, "2024-07-11", 1, 1);
+    let day = |d| NaiveDate::from_ymd_opt(2024, 7, d).unwrap_or_default();
+    let b = Booking::create(
+        conn,
+        "Reason 1",
+        day(11),
+        "08:00",
+        "12:00",
+        1,
+        1,
+        None,
+        None,
+    );
     info!("{b:?}");
-    info!("{:?}", Booking::create("Reason 2", 1, "2024-07-11", 1, 1));
-    info!("{:?}", Booking::create("Reason 3", 2, "2024-07-12", 1, 2));
-    info!("{:?}", Booking::create("Reason 4", 2, "2024-07-12", 2, 1));
-    info!("{:?}", Booking::create("Reason 5", 0, "2024-07-13", 1, 2));
-    info!("{:?}", Booking::create("Reason 6", 1, "2024-07-13", 1, 2));
+    info!(
+        "{:?}",
+        Booking::create(
+            conn,
+            "Reason 2",
+            day(11),
+            "12:00",
+            "17:00",
+            1,
+            1,
+            None,
+            None
+        )
+    );
+    info!(
+        "{:?}",
+        Booking::create(
+            conn,
+            "Reason 3",
+            day(12),
+            "08:00",
+            "17:00",
+            1,
+            2,
+            None,
+            None
+        )
+    );
+    info!(
+        "{:?}",
+        Booking::create(
+            conn,
+            "Reason 4",
+            day(12),
+            "08:00",
+            "17:00",
+            2,
+            1,
+            None,
+            None
+        )
+    );
+    info!(
+        "{:?}",
+        Booking::create(
+            conn,
+            "Reason 5",
+            day(13),
+            "08:00",
+            "12:00",
+            1,
+            2,
+            None,
+            None
+        )
+    );
+    info!(
+        "{:?}",
+        Booking::create(
+            conn,
+            "Reason 6",
+            day(13),
+            "12:00",
+            "17:00",
+            1,
+            2,
+            None,
+            None
+        )
+    );
     warn!("Test bookings loaded");
 
     warn!("Test booking");
-    info!("{:#?}", SerializeBooking::from_booking(b.unwrap()));
+    info!("{:#?}", SerializeBooking::from_booking(conn, b.unwrap()));
     warn!("Test booking");
 }
@@ -0,0 +1,111 @@
+use std::borrow::Cow;
+
+use chrono::{DateTime, NaiveDate, NaiveTime, Utc};
+use validator::ValidationError;
+
+use crate::models::booking::DATE_FORMAT;
+
+/// Minimum length of a password
+pub const MIN_PASSWORD_LENGTH: usize = 8;
+/// Maximum length of a password, hashing very long inputs is needlessly expensive
+pub const MAX_PASSWORD_LENGTH: usize = 128;
+
+fn error(code: &'static str, message: &'static str) -> ValidationError {
+    ValidationError::new(code).with_message(Cow::Borrowed(message))
+}
+
+fn parse_date(value: &str) -> Result<NaiveDate, ValidationError> {
+    NaiveDate::parse_from_str(value, DATE_FORMAT)
+        .map_err(|_| error("date", "must be a date in the format YYYY-MM-DD"))
+}
+
+/// YYYY-MM-DD
+///
+/// # Errors
+///
+/// Fails if `value` is not a valid date
+pub fn date(value: &str) -> Result<(), ValidationError> {
+    parse_date(value).map(|_| ())
+}
+
+/// YYYY-MM-DD, today or later
+///
+/// # Errors
+///
+/// Fails if `value` is not a valid date or lies in the past
+pub fn upcoming_date(value: &str) -> Result<(), ValidationError> {
+    if parse_date(value)? < Utc::now().date_naive() {
+        return Err(error("past_date", "must not be in the past"));
+    }
+    Ok(())
+}
+
+/// Like [`upcoming_date`], but an empty string is accepted as "unchanged"
+///
+/// # Errors
+///
+/// Fails if `value` is neither empty nor an upcoming date
+pub fn upcoming_date_or_empty(value: &str) -> Result<(), ValidationError> {
+    if value.is_empty() {
+        return Ok(());
+    }
+    upcoming_date(value)
+}
+
+/// HH:MM
+///
+/// # Errors
+///
+/// Fails if `value` is not a valid time
+pub fn time(value: &str) -> Result<(), ValidationError> {
+    NaiveTime::parse_from_str(value, "%H:%M")
+        .map(|_| ())
+        .map_err(|_| error("time", "must be a time in the format HH:MM"))
+}
+
+/// Like [`time`], but an empty string is accepted as "unchanged"
+///
+/// # Errors
+///
+/// Fails if `value` is neither empty nor a valid time
+pub fn time_or_empty(value: &str) -> Result<(), ValidationError> {
+    if value.is_empty() {
+        return Ok(());
+    }
+    time(value)
+}
+
+/// Point in time after now
+///
+/// # Errors
+///
+/// Fails if `value` is not in the future
+pub fn future_timestamp(value: &DateTime<Utc>) -> Result<(), ValidationError> {
+    if *value <= Utc::now() {
+        return Err(error("past_timestamp", "must be in the future"));
+    }
+    Ok(())
+}
+
+/// Between [`MIN_PASSWORD_LENGTH`] and [`MAX_PASSWORD_LENGTH`] characters with at
+/// least one letter and one digit
+///
+/// # Errors
+///
+/// Fails if `value` does not meet the policy
+pub fn password(value: &str) -> Result<(), ValidationError> {
+    let length = value.chars().count();
+    if !(MIN_PASSWORD_LENGTH..=MAX_PASSWORD_LENGTH).contains(&length) {
+        return Err(error(
+            "password_length",
+            "must be between 8 and 128 characters long",
+        ));
+    }
+    if !value.chars().any(char::is_alphabetic) || !value.chars().any(|c| c.is_ascii_digit()) {
+        return Err(error(
+            "password_strength",
+            "must contain at least one letter and one digit",
+        ));
+    }
+    Ok(())
+}